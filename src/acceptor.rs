@@ -1,21 +1,94 @@
+use std::fs;
 use std::io;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::sync::{
+    atomic::{AtomicUsize, Ordering},
     mpsc::{self, Receiver},
     Arc, Mutex,
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use failure::Fail;
 use log::*;
 
-use crate::byte_stream::ByteStream;
+use crate::byte_stream::{BoxedStream, ByteStream, DeadlineReader};
+use crate::config::{ProxyProtocolOptions, TlsServerOptions, UnixSocketOptions};
 use crate::model;
-use crate::model::{Error, ErrorKind};
+use crate::model::{Error, ErrorKind, SockAddr, UnixAddr};
+use crate::proxy_protocol;
 use crate::tcp_listener_ext::*;
+use crate::thread::spawn_thread;
+use crate::tls::{TlsServer, TlsServerStream};
+use crate::unix_listener_ext::*;
 
-pub struct TcpAcceptor {
-    listener: TcpListener,
+/// Listening socket which accepts connections with timeout
+pub trait Listener: Send + 'static {
+    type Stream: ByteStream + 'static;
+    /// accept a connection and set `rw_timeout` to it
+    fn accept_timeout(
+        &self,
+        accept_timeout: Option<Duration>,
+        rw_timeout: Option<Duration>,
+    ) -> io::Result<(Self::Stream, SockAddr)>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    fn accept_timeout(
+        &self,
+        accept_timeout: Option<Duration>,
+        rw_timeout: Option<Duration>,
+    ) -> io::Result<(Self::Stream, SockAddr)> {
+        TcpListenerExt::accept_timeout(self, accept_timeout).and_then(|(tcp, addr)| {
+            tcp.set_read_timeout(rw_timeout)?;
+            tcp.set_write_timeout(rw_timeout)?;
+            Ok((tcp, addr.into()))
+        })
+    }
+}
+
+/// Unix domain socket listener
+///
+/// The socket file is removed when the listener is dropped.
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    /// path to the socket file. `None` for abstract sockets.
+    path: Option<PathBuf>,
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            debug!("remove socket file: {}", path.display());
+            if let Err(err) = fs::remove_file(path) {
+                warn!("failed to remove socket file: {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+impl Listener for UnixSocketListener {
+    type Stream = UnixStream;
+    fn accept_timeout(
+        &self,
+        accept_timeout: Option<Duration>,
+        rw_timeout: Option<Duration>,
+    ) -> io::Result<(Self::Stream, SockAddr)> {
+        self.listener
+            .accept_timeout(accept_timeout)
+            .and_then(|(strm, addr)| {
+                strm.set_read_timeout(rw_timeout)?;
+                strm.set_write_timeout(rw_timeout)?;
+                Ok((strm, addr.into()))
+            })
+    }
+}
+
+pub struct Acceptor<L> {
+    listener: L,
     rw_timeout: Option<Duration>,
     /// receive termination message
     rx: Arc<Mutex<Receiver<()>>>,
@@ -23,9 +96,12 @@ pub struct TcpAcceptor {
     accept_timeout: Option<Duration>,
 }
 
-impl TcpAcceptor {
+pub type TcpAcceptor = Acceptor<TcpListener>;
+pub type UnixAcceptor = Acceptor<UnixSocketListener>;
+
+impl<L: Listener> Acceptor<L> {
     fn new(
-        listener: TcpListener,
+        listener: L,
         rw_timeout: Option<Duration>,
         rx: Arc<Mutex<Receiver<()>>>,
         accept_timeout: Option<Duration>,
@@ -38,14 +114,9 @@ impl TcpAcceptor {
         }
     }
 
-    fn accept_timeout(&self) -> io::Result<(TcpStream, SocketAddr)> {
+    fn accept_timeout(&self) -> io::Result<(L::Stream, SockAddr)> {
         self.listener
-            .accept_timeout(self.accept_timeout)
-            .and_then(|(tcp, addr)| {
-                tcp.set_read_timeout(self.rw_timeout)?;
-                tcp.set_write_timeout(self.rw_timeout)?;
-                Ok((tcp, addr))
-            })
+            .accept_timeout(self.accept_timeout, self.rw_timeout)
     }
 }
 
@@ -68,8 +139,8 @@ macro_rules! check_done {
     };
 }
 
-impl Iterator for TcpAcceptor {
-    type Item = (TcpStream, SocketAddr);
+impl<L: Listener> Iterator for Acceptor<L> {
+    type Item = (L::Stream, SockAddr);
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             check_done!(&self.rx);
@@ -90,8 +161,8 @@ impl Iterator for TcpAcceptor {
 
 pub trait Binder {
     type Stream: ByteStream + 'static;
-    type Iter: Iterator<Item = (Self::Stream, SocketAddr)> + Send + 'static;
-    fn bind(&self, addr: SocketAddr) -> Result<Self::Iter, Error>;
+    type Iter: Iterator<Item = (Self::Stream, SockAddr)> + Send + 'static;
+    fn bind(&self, addr: SockAddr) -> Result<Self::Iter, Error>;
 }
 
 pub struct TcpBinder {
@@ -99,6 +170,9 @@ pub struct TcpBinder {
     /// receiver for Acceptor termination message
     rx: Arc<Mutex<Receiver<()>>>,
     accept_timeout: Option<Duration>,
+    /// `IPV6_V6ONLY` option for IPv6 listeners.
+    /// If the value is `None`, the system default (`net.ipv6.bindv6only`) is used.
+    v6_only: Option<bool>,
 }
 
 impl TcpBinder {
@@ -106,11 +180,13 @@ impl TcpBinder {
         rw_timeout: Option<Duration>,
         rx: Arc<Mutex<Receiver<()>>>,
         accept_timeout: Option<Duration>,
+        v6_only: Option<bool>,
     ) -> Self {
         Self {
             rw_timeout,
             rx,
             accept_timeout,
+            v6_only,
         }
     }
 }
@@ -118,12 +194,25 @@ impl TcpBinder {
 impl Binder for TcpBinder {
     type Stream = TcpStream;
     type Iter = TcpAcceptor;
-    fn bind(&self, addr: SocketAddr) -> Result<Self::Iter, Error> {
-        let tcp = net2::TcpBuilder::new_v4()?;
+    fn bind(&self, addr: SockAddr) -> Result<Self::Iter, Error> {
+        let addr = match addr {
+            SockAddr::Inet(addr) => addr,
+            addr => return Err(ErrorKind::AddressNotSupported { addr }.into()),
+        };
+        let tcp = match addr {
+            SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => {
+                let tcp = net2::TcpBuilder::new_v6()?;
+                if let Some(v6_only) = self.v6_only {
+                    tcp.only_v6(v6_only)?;
+                }
+                tcp
+            }
+        };
         let tcp = tcp
             .reuse_address(true)?
             .bind(&addr)
-            .map_err(|err| addr_error(err, addr))?;
+            .map_err(|err| addr_error(err, addr.into()))?;
 
         // `backlog` parameter to `TcpBuilder::listen() is directly passed to `listen(2)` system call.
         // If it is too small, clients may not `connect(2)` to the server.
@@ -139,7 +228,456 @@ impl Binder for TcpBinder {
     }
 }
 
-fn addr_error(io_err: io::Error, addr: SocketAddr) -> model::Error {
+pub struct UnixBinder {
+    rw_timeout: Option<Duration>,
+    /// receiver for Acceptor termination message
+    rx: Arc<Mutex<Receiver<()>>>,
+    accept_timeout: Option<Duration>,
+    options: UnixSocketOptions,
+}
+
+impl UnixBinder {
+    pub fn new(
+        rw_timeout: Option<Duration>,
+        rx: Arc<Mutex<Receiver<()>>>,
+        accept_timeout: Option<Duration>,
+        options: UnixSocketOptions,
+    ) -> Self {
+        Self {
+            rw_timeout,
+            rx,
+            accept_timeout,
+            options,
+        }
+    }
+
+    /// Bind the socket file at `path` with `mode` and `owner`/`group` options
+    ///
+    /// With the options, the socket is bound in a private directory next to `path` and linked
+    /// to `path` after they are applied, so that no one connects with permissions of umask.
+    fn bind_path(&self, path: &Path) -> io::Result<UnixListener> {
+        let options = &self.options;
+        if options.mode.is_none() && options.owner.is_none() && options.group.is_none() {
+            return UnixListener::bind(path);
+        }
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name")
+        })?;
+        let dir = path.with_file_name(format!(
+            ".{}.{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let private = dir.join(name);
+        let listener = UnixListener::bind(&private).and_then(|listener| {
+            self.set_permissions(&private)?;
+            // fails as binding to `path` does if the file exists
+            fs::hard_link(&private, path).map_err(|err| match err.kind() {
+                io::ErrorKind::AlreadyExists => io::ErrorKind::AddrInUse.into(),
+                _ => err,
+            })?;
+            Ok(listener)
+        });
+        let _ = fs::remove_file(&private);
+        let _ = fs::remove_dir(&dir);
+        listener
+    }
+
+    /// Apply `mode` and `owner`/`group` options to the socket file
+    fn set_permissions(&self, path: &Path) -> io::Result<()> {
+        use nix::unistd::{chown, Gid, Uid};
+
+        if let Some(mode) = self.options.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        if self.options.owner.is_some() || self.options.group.is_some() {
+            chown(
+                path,
+                self.options.owner.map(Uid::from_raw),
+                self.options.group.map(Gid::from_raw),
+            )
+            .map_err(|err| match err.as_errno() {
+                Some(errno) => io::Error::from_raw_os_error(errno as i32),
+                // e.g. paths containing NUL
+                None => io::Error::new(io::ErrorKind::Other, err),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove the socket file at `path` if no process listens on it
+///
+/// Files other than sockets are left untouched. Binding to them fails afterwards.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {}
+        Ok(_) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    }
+    match UnixStream::connect(path) {
+        // alive
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!("remove stale socket file: {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+impl Binder for UnixBinder {
+    type Stream = UnixStream;
+    type Iter = UnixAcceptor;
+    fn bind(&self, addr: SockAddr) -> Result<Self::Iter, Error> {
+        let listener = match &addr {
+            SockAddr::Unix(UnixAddr::Pathname(path)) => {
+                if self.options.remove_stale {
+                    remove_stale_socket(path)?;
+                }
+                UnixSocketListener {
+                    listener: self
+                        .bind_path(path)
+                        .map_err(|err| addr_error(err, addr.clone()))?,
+                    path: Some(path.clone()),
+                }
+            }
+            SockAddr::Unix(UnixAddr::Abstract(name)) => {
+                if self.options.mode.is_some()
+                    || self.options.owner.is_some()
+                    || self.options.group.is_some()
+                {
+                    warn!(
+                        "permissions are not applicable to abstract socket: {}",
+                        addr
+                    );
+                }
+                UnixSocketListener {
+                    listener: bind_abstract(name, 256)
+                        .map_err(|err| addr_error(err, addr.clone()))?,
+                    path: None,
+                }
+            }
+            _ => return Err(ErrorKind::AddressNotSupported { addr }.into()),
+        };
+
+        Ok(UnixAcceptor::new(
+            listener,
+            self.rw_timeout,
+            self.rx.clone(),
+            self.accept_timeout,
+        ))
+    }
+}
+
+/// Threads reading PROXY protocol headers at most
+const MAX_PENDING_HEADERS: usize = 256;
+
+/// Threads handshaking TLS at most
+const MAX_PENDING_HANDSHAKES: usize = 256;
+
+/// Address of the client told by the PROXY protocol header from `source` on `sock`
+///
+/// Headers of untrusted sources are not read, so that clients cannot spoof their addresses.
+/// The whole header is read within `options.timeout`.
+/// The read timeout of `sock` is restored afterwards.
+fn read_proxy_header(
+    sock: &TcpStream,
+    source: SockAddr,
+    options: &ProxyProtocolOptions,
+) -> io::Result<SockAddr> {
+    match &source {
+        SockAddr::Inet(addr) => {
+            let ip = unmapped(addr.ip());
+            if !options.trusted.iter().any(|net| net.contains(&ip)) {
+                return Ok(source);
+            }
+        }
+        _ => return Ok(source),
+    }
+    let timeout = sock.read_timeout()?;
+    let header = proxy_protocol::read_header(&mut DeadlineReader {
+        sock,
+        deadline: Instant::now() + options.timeout,
+    });
+    sock.set_read_timeout(timeout)?;
+    match header? {
+        Some(client) => {
+            debug!("client told by PROXY protocol: {}: {}", source, client);
+            Ok(client.into())
+        }
+        // connections of the load balancer itself
+        None => Ok(source),
+    }
+}
+
+/// IPv4 address of an IPv4-mapped IPv6 address, e.g. of a client of a dual-stack listener
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            v6.to_ipv4().map_or(ip, IpAddr::V4)
+        }
+        ip => ip,
+    }
+}
+
+/// Binder reads PROXY protocol headers of clients accepted by TCP listeners
+///
+/// Headers are read in their own threads, so that slow sources do not block accepting others.
+/// Clients beyond `max_pending` threads are closed.
+pub struct ProxyProtocolBinder {
+    tcp: TcpBinder,
+    options: ProxyProtocolOptions,
+    max_pending: usize,
+}
+
+/// Slot of a thread reading a header or handshaking, released when dropped
+struct PendingHeader(Arc<AtomicUsize>);
+
+impl PendingHeader {
+    fn acquire(pending: &Arc<AtomicUsize>, max_pending: usize) -> Option<Self> {
+        if pending.fetch_add(1, Ordering::SeqCst) < max_pending {
+            Some(Self(pending.clone()))
+        } else {
+            pending.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+impl Drop for PendingHeader {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Clients with the addresses told by their headers
+pub struct ProxyProtocolAcceptor {
+    rx: Receiver<(TcpStream, SockAddr)>,
+}
+
+impl Iterator for ProxyProtocolAcceptor {
+    type Item = (TcpStream, SockAddr);
+    fn next(&mut self) -> Option<Self::Item> {
+        // disconnected when the TCP acceptor and all headers are done
+        self.rx.recv().ok()
+    }
+}
+
+impl ProxyProtocolBinder {
+    pub fn new(tcp: TcpBinder, options: ProxyProtocolOptions) -> Self {
+        Self {
+            tcp,
+            options,
+            max_pending: MAX_PENDING_HEADERS,
+        }
+    }
+
+    /// Read headers of clients accepted by `acceptor` listening on `addr`
+    fn accept<I>(&self, addr: &SockAddr, acceptor: I) -> Result<ProxyProtocolAcceptor, Error>
+    where
+        I: Iterator<Item = (TcpStream, SockAddr)> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let options = self.options.clone();
+        let max_pending = self.max_pending;
+        let pending = Arc::new(AtomicUsize::new(0));
+        spawn_thread(&format!("proxy protocol acceptor: {}", addr), move || {
+            for (sock, source) in acceptor {
+                let slot = match PendingHeader::acquire(&pending, max_pending) {
+                    Some(slot) => slot,
+                    None => {
+                        warn!("too many pending PROXY protocol headers, close: {}", source);
+                        continue;
+                    }
+                };
+                let (options, tx) = (options.clone(), tx.clone());
+                let name = format!("proxy protocol: {}", source);
+                let spawned = spawn_thread(&name, move || {
+                    let header = read_proxy_header(&sock, source.clone(), &options);
+                    drop(slot);
+                    match header {
+                        Ok(client) => {
+                            tx.send((sock, client)).ok();
+                        }
+                        Err(err) => warn!("proxy protocol error: {}: {}", source, err),
+                    }
+                });
+                if let Err(err) = spawned {
+                    error!("proxy protocol error: {}", err);
+                }
+            }
+        })?;
+        Ok(ProxyProtocolAcceptor { rx })
+    }
+}
+
+impl Binder for ProxyProtocolBinder {
+    type Stream = TcpStream;
+    type Iter = ProxyProtocolAcceptor;
+    fn bind(&self, addr: SockAddr) -> Result<Self::Iter, Error> {
+        let acceptor = self.tcp.bind(addr.clone())?;
+        self.accept(&addr, acceptor)
+    }
+}
+
+/// Binder terminates TLS of clients accepted by TCP listeners
+///
+/// Handshakes run in their own threads, so that slow clients do not block accepting others.
+/// Clients beyond `max_pending` threads are closed.
+pub struct TlsBinder {
+    tcp: TcpBinder,
+    options: TlsServerOptions,
+    max_pending: usize,
+    /// reads PROXY protocol headers before handshakes instead of `tcp` if given
+    proxy_protocol: Option<ProxyProtocolBinder>,
+}
+
+/// Clients completed TLS handshakes
+pub struct TlsAcceptor {
+    rx: Receiver<(TlsServerStream, SockAddr)>,
+}
+
+impl Iterator for TlsAcceptor {
+    type Item = (TlsServerStream, SockAddr);
+    fn next(&mut self) -> Option<Self::Item> {
+        // disconnected when the TCP acceptor and all handshakes are done
+        self.rx.recv().ok()
+    }
+}
+
+impl TlsBinder {
+    pub fn new(tcp: TcpBinder, options: TlsServerOptions) -> Self {
+        Self {
+            tcp,
+            options,
+            max_pending: MAX_PENDING_HANDSHAKES,
+            proxy_protocol: None,
+        }
+    }
+
+    /// Read PROXY protocol headers of load balancers passing TLS through
+    pub fn with_proxy_protocol(self, proxy_protocol: ProxyProtocolBinder) -> Self {
+        Self {
+            proxy_protocol: Some(proxy_protocol),
+            ..self
+        }
+    }
+
+    /// Whether clients of `addr` talk TLS
+    pub fn serves(&self, addr: &SockAddr) -> bool {
+        self.options.addrs.contains(addr)
+    }
+
+    /// Handshake with clients accepted by `acceptor` listening on `addr`
+    fn accept<I>(&self, addr: &SockAddr, acceptor: I) -> Result<TlsAcceptor, Error>
+    where
+        I: Iterator<Item = (TcpStream, SockAddr)> + Send + 'static,
+    {
+        let server = Arc::new(TlsServer::new(&self.options)?);
+        let (tx, rx) = mpsc::channel();
+        let timeout = self.options.handshake_timeout;
+        let max_pending = self.max_pending;
+        let pending = Arc::new(AtomicUsize::new(0));
+        spawn_thread(&format!("tls acceptor: {}", addr), move || {
+            for (sock, source) in acceptor {
+                let slot = match PendingHeader::acquire(&pending, max_pending) {
+                    Some(slot) => slot,
+                    None => {
+                        warn!("too many pending TLS handshakes, close: {}", source);
+                        continue;
+                    }
+                };
+                let (server, tx) = (server.clone(), tx.clone());
+                let name = format!("tls handshake: {}", source);
+                let spawned = spawn_thread(&name, move || {
+                    let accepted = server.accept(sock, timeout);
+                    drop(slot);
+                    match accepted {
+                        Ok(strm) => {
+                            tx.send((strm, source)).ok();
+                        }
+                        Err(err) => warn!("tls handshake error: {}: {}", source, err),
+                    }
+                });
+                if let Err(err) = spawned {
+                    error!("tls handshake error: {}", err);
+                }
+            }
+        })?;
+        Ok(TlsAcceptor { rx })
+    }
+}
+
+impl Binder for TlsBinder {
+    type Stream = TlsServerStream;
+    type Iter = TlsAcceptor;
+    fn bind(&self, addr: SockAddr) -> Result<Self::Iter, Error> {
+        match &self.proxy_protocol {
+            Some(proxy_protocol) => self.accept(&addr, proxy_protocol.bind(addr.clone())?),
+            None => self.accept(&addr, self.tcp.bind(addr.clone())?),
+        }
+    }
+}
+
+/// Binder binds TCP or unix domain sockets according to the address
+pub struct ListenerBinder {
+    tcp: TcpBinder,
+    unix: UnixBinder,
+    tls: Option<TlsBinder>,
+    /// binds TCP listeners instead of `tcp` if given
+    proxy_protocol: Option<ProxyProtocolBinder>,
+}
+
+impl ListenerBinder {
+    pub fn new(tcp: TcpBinder, unix: UnixBinder) -> Self {
+        Self {
+            tcp,
+            unix,
+            tls: None,
+            proxy_protocol: None,
+        }
+    }
+
+    /// Read PROXY protocol headers on TCP listeners other than TLS ones
+    pub fn with_proxy_protocol(self, proxy_protocol: ProxyProtocolBinder) -> Self {
+        Self {
+            proxy_protocol: Some(proxy_protocol),
+            ..self
+        }
+    }
+
+    /// Terminate TLS on the addresses `tls` serves
+    pub fn with_tls(self, tls: TlsBinder) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
+        }
+    }
+}
+
+fn boxed<S: ByteStream + 'static>((strm, addr): (S, SockAddr)) -> (BoxedStream<'static>, SockAddr) {
+    (Box::new(strm), addr)
+}
+
+impl Binder for ListenerBinder {
+    type Stream = BoxedStream<'static>;
+    type Iter = Box<dyn Iterator<Item = (Self::Stream, SockAddr)> + Send>;
+    fn bind(&self, addr: SockAddr) -> Result<Self::Iter, Error> {
+        match addr {
+            SockAddr::Inet(_) => match (&self.tls, &self.proxy_protocol) {
+                (Some(tls), _) if tls.serves(&addr) => Ok(Box::new(tls.bind(addr)?.map(boxed))),
+                (_, Some(proxy_protocol)) => Ok(Box::new(proxy_protocol.bind(addr)?.map(boxed))),
+                _ => Ok(Box::new(self.tcp.bind(addr)?.map(boxed))),
+            },
+            SockAddr::Unix(_) => Ok(Box::new(self.unix.bind(addr)?.map(boxed))),
+        }
+    }
+}
+
+pub(crate) fn addr_error(io_err: io::Error, addr: SockAddr) -> model::Error {
     match io_err.kind() {
         io::ErrorKind::AddrInUse => ErrorKind::AddressAlreadInUse { addr }.into(),
         io::ErrorKind::AddrNotAvailable => ErrorKind::AddressNotAvailable { addr }.into(),
@@ -147,3 +685,378 @@ fn addr_error(io_err: io::Error, addr: SocketAddr) -> model::Error {
     }
     .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn bind_and_connect(addr: SocketAddr, v6_only: Option<bool>) -> Vec<u8> {
+        let (_tx, rx) = mpsc::sync_channel(1);
+        let binder = TcpBinder::new(
+            None,
+            Arc::new(Mutex::new(rx)),
+            Some(Duration::from_secs(3)),
+            v6_only,
+        );
+        let mut acceptor = binder.bind(addr.into()).unwrap();
+        let addr = acceptor.listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"hello").unwrap();
+        let (mut strm, client_addr) = acceptor.next().unwrap();
+        assert_eq!(client_addr, client.local_addr().unwrap().into());
+
+        let mut buf = [0; 5];
+        strm.read_exact(&mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn bind_ipv4() {
+        assert_eq!(
+            bind_and_connect("127.0.0.1:0".parse().unwrap(), None),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn bind_ipv6() {
+        assert_eq!(
+            bind_and_connect("[::1]:0".parse().unwrap(), Some(true)),
+            b"hello"
+        );
+    }
+
+    /// TCP acceptor on a free port of 127.0.0.1, its address and sender of the termination message
+    ///
+    /// The listener is bound directly, so that no one takes the port before the acceptor does.
+    fn tcp_acceptor() -> (TcpAcceptor, SocketAddr, mpsc::SyncSender<()>) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TcpAcceptor::new(
+            listener,
+            None,
+            Arc::new(Mutex::new(rx)),
+            Some(Duration::from_secs(3)),
+        );
+        (acceptor, addr, tx)
+    }
+
+    /// TCP binder for binders wrapping acceptors of `tcp_acceptor`, which it never binds
+    fn unused_tcp_binder() -> TcpBinder {
+        let (_tx, rx) = mpsc::sync_channel(1);
+        TcpBinder::new(None, Arc::new(Mutex::new(rx)), None, None)
+    }
+
+    fn proxy_protocol_binder(max_pending: usize) -> ProxyProtocolBinder {
+        let options = ProxyProtocolOptions {
+            trusted: vec!["127.0.0.0/8".parse().unwrap()],
+            ..ProxyProtocolOptions::default()
+        };
+        ProxyProtocolBinder {
+            max_pending,
+            ..ProxyProtocolBinder::new(unused_tcp_binder(), options)
+        }
+    }
+
+    fn tls_binder(name: &str) -> (TlsBinder, rcgen::Certificate) {
+        let (cert, files) = crate::tls::test::self_signed(name, &["localhost"]);
+        let options = TlsServerOptions {
+            certs: vec![files],
+            ..TlsServerOptions::default()
+        };
+        let binder = TlsBinder {
+            max_pending: 1,
+            ..TlsBinder::new(unused_tcp_binder(), options)
+        };
+        (binder, cert)
+    }
+
+    #[test]
+    fn proxy_protocol() {
+        let header = b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 1081\r\nhello";
+        let bind = |trusted: &str| {
+            let (tcp, addr, tx) = tcp_acceptor();
+            let binder = ProxyProtocolBinder::new(
+                unused_tcp_binder(),
+                ProxyProtocolOptions {
+                    trusted: vec![trusted.parse().unwrap()],
+                    ..ProxyProtocolOptions::default()
+                },
+            );
+            (binder.accept(&addr.into(), tcp).unwrap(), addr, tx)
+        };
+
+        let (mut acceptor, addr, _tx) = bind("127.0.0.0/8");
+        TcpStream::connect(addr).unwrap().write_all(header).unwrap();
+        let (mut strm, client) = acceptor.next().unwrap();
+        assert_eq!(
+            client,
+            "192.0.2.1:56324".parse::<SocketAddr>().unwrap().into()
+        );
+        let mut buf = vec![];
+        strm.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+
+        // headers of untrusted sources are data of the clients
+        let (mut acceptor, addr, _tx) = bind("192.0.2.0/24");
+        let mut sock = TcpStream::connect(addr).unwrap();
+        sock.write_all(header).unwrap();
+        let (mut strm, client) = acceptor.next().unwrap();
+        assert_eq!(client, sock.local_addr().unwrap().into());
+        drop(sock);
+        let mut buf = vec![];
+        strm.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, header);
+    }
+
+    #[test]
+    fn proxy_protocol_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = ProxyProtocolOptions {
+            trusted: vec!["127.0.0.0/8".parse().unwrap()],
+            timeout: Duration::from_millis(300),
+        };
+        let client = std::thread::spawn(move || {
+            let mut sock = TcpStream::connect(addr).unwrap();
+            // every byte arrives within the timeout, but not the whole header
+            for byte in b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 1081\r\n" {
+                if sock.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let (sock, source) = listener.accept().unwrap();
+        let started = Instant::now();
+        assert!(read_proxy_header(&sock, source.into(), &options).is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(sock);
+        client.join().unwrap();
+    }
+
+    /// Check that `acceptor` on `addr` closes clients while its only pending thread is taken
+    ///
+    /// `serve` completes a client, which holds the thread until then.
+    /// Returns the addresses of the first client and of a client connecting after it.
+    fn assert_pending_limit<I, S, F>(mut acceptor: I, addr: SocketAddr, serve: F) -> Vec<SockAddr>
+    where
+        I: Iterator<Item = (S, SockAddr)>,
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let serve = Arc::new(serve);
+        let first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .unwrap();
+        assert_eq!(second.read(&mut [0; 1]).unwrap(), 0);
+
+        let client = {
+            let serve = serve.clone();
+            std::thread::spawn(move || serve(first))
+        };
+        let mut clients = vec![acceptor.next().unwrap().1];
+        client.join().unwrap();
+
+        // the thread is released
+        let later = TcpStream::connect(addr).unwrap();
+        let client = std::thread::spawn(move || serve(later));
+        clients.push(acceptor.next().unwrap().1);
+        client.join().unwrap();
+        clients
+    }
+
+    #[test]
+    fn proxy_protocol_pending_limit() {
+        let (tcp, addr, _tx) = tcp_acceptor();
+        let acceptor = proxy_protocol_binder(1).accept(&addr.into(), tcp).unwrap();
+        let clients = assert_pending_limit(acceptor, addr, |mut sock| {
+            sock.write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 1081\r\n")
+                .unwrap();
+        });
+        let client: SockAddr = "192.0.2.1:56324".parse::<SocketAddr>().unwrap().into();
+        assert_eq!(clients, vec![client.clone(), client]);
+    }
+
+    #[test]
+    fn tls_pending_limit() {
+        let (tcp, addr, _tx) = tcp_acceptor();
+        let (binder, cert) = tls_binder("tls-pending");
+        let acceptor = binder.accept(&addr.into(), tcp).unwrap();
+        assert_pending_limit(acceptor, addr, move |sock| {
+            crate::tls::test::connect_client(&[&cert], "localhost", sock).unwrap();
+        });
+    }
+
+    #[test]
+    fn tls_proxy_protocol_pending_limit() {
+        let (tcp, addr, _tx) = tcp_acceptor();
+        let (binder, cert) = tls_binder("tls-proxy-protocol");
+        // the TLS thread is free while the PROXY protocol one is held by load balancers
+        let binder = TlsBinder {
+            max_pending: MAX_PENDING_HANDSHAKES,
+            ..binder
+        };
+        let proxy_protocol = proxy_protocol_binder(1).accept(&addr.into(), tcp).unwrap();
+        let acceptor = binder.accept(&addr.into(), proxy_protocol).unwrap();
+        let clients = assert_pending_limit(acceptor, addr, move |mut sock| {
+            sock.write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 1081\r\n")
+                .unwrap();
+            crate::tls::test::connect_client(&[&cert], "localhost", sock).unwrap();
+        });
+        let client: SockAddr = "192.0.2.1:56324".parse::<SocketAddr>().unwrap().into();
+        assert_eq!(clients, vec![client.clone(), client]);
+    }
+
+    /// Returns binder and sender of the termination message
+    fn unix_binder(options: UnixSocketOptions) -> (UnixBinder, mpsc::SyncSender<()>) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let binder = UnixBinder::new(
+            None,
+            Arc::new(Mutex::new(rx)),
+            Some(Duration::from_secs(3)),
+            options,
+        );
+        (binder, tx)
+    }
+
+    fn temp_socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tcp2socks-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn bind_unix_pathname() {
+        let path = temp_socket_path("pathname");
+        let (binder, _tx) = unix_binder(UnixSocketOptions {
+            mode: Some(0o600),
+            ..UnixSocketOptions::default()
+        });
+        let mut acceptor = binder
+            .bind(UnixAddr::Pathname(path.clone()).into())
+            .unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"hello").unwrap();
+        let (mut strm, client_addr) = acceptor.next().unwrap();
+        assert_eq!(client_addr, UnixAddr::Unnamed.into());
+        let mut buf = [0; 5];
+        strm.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        drop(acceptor);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bind_unix_stale() {
+        let path = temp_socket_path("stale");
+        // leave the socket file as a dead process does
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (keep, _tx) = unix_binder(UnixSocketOptions {
+            remove_stale: false,
+            ..UnixSocketOptions::default()
+        });
+        match keep.bind(UnixAddr::Pathname(path.clone()).into()) {
+            Err(err) => assert_eq!(
+                err.kind(),
+                &ErrorKind::AddressAlreadInUse {
+                    addr: UnixAddr::Pathname(path.clone()).into()
+                }
+            ),
+            Ok(_) => panic!("stale socket must not be removed"),
+        }
+
+        let (binder, _tx) = unix_binder(UnixSocketOptions::default());
+        let acceptor = binder
+            .bind(UnixAddr::Pathname(path.clone()).into())
+            .unwrap();
+        // do not remove alive socket
+        binder
+            .bind(UnixAddr::Pathname(path.clone()).into())
+            .err()
+            .unwrap();
+        drop(acceptor);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bind_unix_private() {
+        let path = temp_socket_path("private");
+        drop(UnixListener::bind(&path).unwrap());
+        let (binder, _tx) = unix_binder(UnixSocketOptions {
+            mode: Some(0o660),
+            remove_stale: false,
+            ..UnixSocketOptions::default()
+        });
+        // the socket is linked to the path without replacing files
+        match binder.bind(UnixAddr::Pathname(path.clone()).into()) {
+            Err(err) => assert_eq!(
+                err.kind(),
+                &ErrorKind::AddressAlreadInUse {
+                    addr: UnixAddr::Pathname(path.clone()).into()
+                }
+            ),
+            Ok(_) => panic!("existing socket file must not be replaced"),
+        }
+        fs::remove_file(&path).unwrap();
+
+        let acceptor = binder
+            .bind(UnixAddr::Pathname(path.clone()).into())
+            .unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o660);
+        UnixStream::connect(&path).unwrap();
+        // the private directory is removed
+        let private = path.with_file_name(format!(
+            ".{}.{}",
+            path.file_name().unwrap().to_string_lossy(),
+            std::process::id()
+        ));
+        assert!(!private.exists());
+
+        drop(acceptor);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bind_unix_abstract() {
+        let name = format!("tcp2socks-abstract-{}", std::process::id());
+        let (binder, _tx) = unix_binder(UnixSocketOptions::default());
+        let mut acceptor = binder
+            .bind(UnixAddr::Abstract(name.as_bytes().to_vec()).into())
+            .unwrap();
+
+        // connect to the abstract address
+        let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) };
+        let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (dst, src) in addr.sun_path[1..].iter_mut().zip(name.as_bytes()) {
+            *dst = *src as libc::c_char;
+        }
+        let len = std::mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+        let r = unsafe {
+            libc::connect(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                len as libc::socklen_t,
+            )
+        };
+        assert_eq!(r, 0);
+        let mut client = unsafe { <UnixStream as std::os::unix::io::FromRawFd>::from_raw_fd(fd) };
+        client.write_all(b"hello").unwrap();
+
+        let (mut strm, _) = acceptor.next().unwrap();
+        let mut buf = [0; 5];
+        strm.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
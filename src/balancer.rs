@@ -0,0 +1,230 @@
+//! Load balancing of sessions over alternative proxies
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use rand::seq::SliceRandom;
+use ring::digest;
+
+use crate::config::ProxyConfig;
+use crate::model::{Address, SockAddr};
+
+/// Strategy to choose a proxy for a new session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// the first available proxy in priority order
+    Priority,
+    RoundRobin,
+    /// the proxy with the fewest live sessions
+    LeastSessions,
+    /// random in proportion to weights of proxies
+    WeightedRandom,
+    /// rendezvous hashing of the client IP address, which keeps clients on the same proxy
+    ClientHash,
+}
+
+impl FromStr for BalanceStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "priority" => Ok(BalanceStrategy::Priority),
+            "round-robin" => Ok(BalanceStrategy::RoundRobin),
+            "least-sessions" => Ok(BalanceStrategy::LeastSessions),
+            "weighted-random" => Ok(BalanceStrategy::WeightedRandom),
+            "client-hash" => Ok(BalanceStrategy::ClientHash),
+            _ => Err(format!(
+                "unknown balance strategy: {} (priority, round-robin, least-sessions, weighted-random or client-hash)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BalanceStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BalanceStrategy::Priority => write!(f, "priority"),
+            BalanceStrategy::RoundRobin => write!(f, "round-robin"),
+            BalanceStrategy::LeastSessions => write!(f, "least-sessions"),
+            BalanceStrategy::WeightedRandom => write!(f, "weighted-random"),
+            BalanceStrategy::ClientHash => write!(f, "client-hash"),
+        }
+    }
+}
+
+/// Chooses proxies by the strategy. Clones share the round-robin counter.
+#[derive(Debug, Clone)]
+pub struct Balancer {
+    strategy: BalanceStrategy,
+    next: Arc<AtomicUsize>,
+}
+
+impl Balancer {
+    pub fn new(strategy: BalanceStrategy) -> Self {
+        Self {
+            strategy,
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Choose one of `candidates` in priority order for a session of `client`
+    ///
+    /// `load` counts live sessions by proxy.
+    pub fn choose(
+        &self,
+        candidates: &[&ProxyConfig],
+        client: &SockAddr,
+        load: &HashMap<Address, usize>,
+    ) -> Option<Address> {
+        let first = candidates.first()?;
+        let proxy = match self.strategy {
+            BalanceStrategy::Priority => first,
+            BalanceStrategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                &candidates[next % candidates.len()]
+            }
+            BalanceStrategy::LeastSessions => candidates
+                .iter()
+                .min_by_key(|proxy| load.get(&proxy.addr).copied().unwrap_or(0))
+                .unwrap_or(first),
+            BalanceStrategy::WeightedRandom => candidates
+                .choose_weighted(&mut rand::thread_rng(), |proxy| proxy.weight)
+                .unwrap_or(first),
+            BalanceStrategy::ClientHash => match client {
+                SockAddr::Inet(addr) => candidates
+                    .iter()
+                    .max_by_key(|proxy| rendezvous_weight(addr.ip(), &proxy.addr))
+                    .unwrap_or(first),
+                // no address to tell clients apart
+                SockAddr::Unix(_) => first,
+            },
+        };
+        Some(proxy.addr.clone())
+    }
+}
+
+/// Weight of `proxy` for clients of `ip` in rendezvous hashing
+///
+/// SHA-256 keeps clients on the same proxies across builds, unlike `DefaultHasher`.
+fn rendezvous_weight(ip: IpAddr, proxy: &Address) -> u64 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    match ip {
+        IpAddr::V4(ip) => ctx.update(&ip.octets()),
+        IpAddr::V6(ip) => ctx.update(&ip.octets()),
+    }
+    ctx.update(proxy.to_string().as_bytes());
+    let mut weight = [0; 8];
+    weight.copy_from_slice(&ctx.finish().as_ref()[..8]);
+    u64::from_be_bytes(weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::ProxyProtocol;
+    use std::net::SocketAddr;
+
+    fn proxies(n: u16) -> Vec<ProxyConfig> {
+        (0..n)
+            .map(|i| {
+                let addr = SocketAddr::from(([127, 0, 0, 1], 1080 + i));
+                ProxyConfig::new(ProxyProtocol::Socks5h, addr)
+            })
+            .collect()
+    }
+
+    fn client(ip: [u8; 4]) -> SockAddr {
+        SocketAddr::from((ip, 50000)).into()
+    }
+
+    #[test]
+    fn round_robin() {
+        let proxies = proxies(3);
+        let candidates: Vec<_> = proxies.iter().collect();
+        let balancer = Balancer::new(BalanceStrategy::RoundRobin);
+        let shared = balancer.clone();
+        let chosen: Vec<_> = (0..4)
+            .map(|i| {
+                let balancer = if i % 2 == 0 { &balancer } else { &shared };
+                balancer.choose(&candidates, &client([10, 0, 0, 1]), &HashMap::new())
+            })
+            .collect();
+        let addrs: Vec<_> = [0, 1, 2, 0]
+            .iter()
+            .map(|&i| Some(proxies[i].addr.clone()))
+            .collect();
+        assert_eq!(chosen, addrs);
+    }
+
+    #[test]
+    fn least_sessions() {
+        let proxies = proxies(3);
+        let candidates: Vec<_> = proxies.iter().collect();
+        let balancer = Balancer::new(BalanceStrategy::LeastSessions);
+        let load = vec![(proxies[0].addr.clone(), 2), (proxies[1].addr.clone(), 1)]
+            .into_iter()
+            .collect();
+        let client = client([10, 0, 0, 1]);
+        assert_eq!(
+            balancer.choose(&candidates, &client, &load),
+            Some(proxies[2].addr.clone())
+        );
+        assert_eq!(
+            balancer.choose(&candidates[..2], &client, &load),
+            Some(proxies[1].addr.clone())
+        );
+    }
+
+    #[test]
+    fn weighted_random() {
+        let mut proxies = proxies(2);
+        proxies[0].weight = 0;
+        let candidates: Vec<_> = proxies.iter().collect();
+        let balancer = Balancer::new(BalanceStrategy::WeightedRandom);
+        for _ in 0..10 {
+            assert_eq!(
+                balancer.choose(&candidates, &client([10, 0, 0, 1]), &HashMap::new()),
+                Some(proxies[1].addr.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn client_hash() {
+        let proxies = proxies(4);
+        let candidates: Vec<_> = proxies.iter().collect();
+        let balancer = Balancer::new(BalanceStrategy::ClientHash);
+        let load = HashMap::new();
+        for i in 0..16 {
+            let client = client([10, 0, 0, i]);
+            let chosen = balancer.choose(&candidates, &client, &load).unwrap();
+            assert_eq!(
+                balancer.choose(&candidates, &client, &load),
+                Some(chosen.clone())
+            );
+            // clients of the other proxies stay when a proxy is removed
+            let rest: Vec<_> = candidates
+                .iter()
+                .copied()
+                .filter(|proxy| proxy.addr != proxies[0].addr)
+                .collect();
+            if chosen != proxies[0].addr {
+                assert_eq!(balancer.choose(&rest, &client, &load), Some(chosen));
+            }
+        }
+        // the same across builds and releases
+        let chosen: Vec<_> = (0..8)
+            .map(|i| {
+                let chosen = balancer.choose(&candidates, &client([10, 0, 0, i]), &load);
+                proxies
+                    .iter()
+                    .position(|proxy| Some(&proxy.addr) == chosen.as_ref())
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(chosen, vec![3, 3, 2, 1, 2, 2, 0, 0]);
+    }
+}
@@ -2,6 +2,8 @@ use std::fmt;
 use std::io;
 use std::net::TcpStream;
 use std::ops::Deref;
+use std::os::unix::net::UnixStream;
+use std::time::Instant;
 
 use crate::model::Error;
 
@@ -9,6 +11,11 @@ use crate::model::Error;
 pub trait ByteStream: fmt::Debug + io::Read + io::Write + Send {
     #[allow(clippy::type_complexity)]
     fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error>;
+
+    /// Identity of the peer verified by the stream, e.g. by its TLS client certificate
+    fn peer_identity(&self) -> Option<String> {
+        None
+    }
 }
 
 /// byte stream on tcp connection
@@ -21,16 +28,48 @@ impl ByteStream for TcpStream {
     }
 }
 
+/// byte stream on unix domain socket connection
+impl ByteStream for UnixStream {
+    #[allow(clippy::type_complexity)]
+    fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+        let rd = self.try_clone()?;
+        let wr = self.try_clone()?;
+        Ok((Box::new(rd), Box::new(wr)))
+    }
+}
+
 /// Boxed stream
-impl<S: ByteStream> ByteStream for Box<S> {
+impl<S: ByteStream + ?Sized> ByteStream for Box<S> {
     #[allow(clippy::type_complexity)]
     fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
         self.deref().split()
     }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.deref().peer_identity()
+    }
 }
 
 pub type BoxedStream<'a> = Box<dyn ByteStream + 'a>;
 
+/// Reader of `sock` which times out at `deadline` however slowly the data arrives
+pub(crate) struct DeadlineReader<'a> {
+    pub sock: &'a TcpStream,
+    pub deadline: Instant,
+}
+
+impl io::Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            Some(timeout) if timeout.as_nanos() > 0 => {
+                self.sock.set_read_timeout(Some(timeout))?;
+                (&*self.sock).read(buf)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut, "deadline exceeded")),
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
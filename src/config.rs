@@ -1,30 +1,122 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use crate::model::{Address, SocketAddr};
+use ipnet::IpNet;
+
+use crate::balancer::BalanceStrategy;
+use crate::destination::DestinationOrder;
+use crate::model::{Address, Credentials, L4Protocol, ProxyProtocol, SockAddr, SocketAddr};
+use crate::resolver::IpPreference;
+use crate::retry::RetryPolicy;
+use crate::rules::Rules;
 
 /// Server configuration
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
-    pub server_addr: SocketAddr,
-    pub proxy_addr: SocketAddr,
-    pub dst_addr: Address,
+    /// transport protocol of the listeners and the destination. (default: Tcp)
+    pub protocol: L4Protocol,
+    /// addresses to listen on. all of them feed the same server.
+    pub server_addrs: Vec<SockAddr>,
+    /// hops to go through in order. each of them is reached through the previous one.
+    /// a hop lists alternative proxies by priority, which are tried when earlier ones fail.
+    /// no hops to connect to destinations directly.
+    pub proxies: Vec<Vec<ProxyConfig>>,
+    /// proxies failed to connect are skipped for this duration. (default: 30s)
+    pub proxy_cooldown: Duration,
+    /// how sessions are spread over alternatives of the first hop. (default: Priority)
+    pub balance: BalanceStrategy,
+    /// check proxies of the first hop in background. (default: disabled)
+    pub health_check: Option<HealthCheckOptions>,
+    /// alternative destinations. a session moves on to the next one when the proxy cannot reach one.
+    pub dst_addrs: Vec<Address>,
+    /// order of destinations for each session. (default: Failover)
+    pub dst_order: DestinationOrder,
     /// timeout of relaying data chunk from client to external network. (default: 2000ms)
     pub client_rw_timeout: Option<Duration>,
     /// timeout of relaying data chunk from external network to client. (default: 5000ms)
     pub server_rw_timeout: Option<Duration>,
+    /// timeout of connecting to the proxy. (default: 10s)
+    pub connect_timeout: Option<Duration>,
+    /// timeout of the whole handshake with each proxy. (default: 10s)
+    pub handshake_timeout: Option<Duration>,
+    /// retries of connections to the destination before giving up sessions. (default: no retry)
+    pub retry: RetryPolicy,
+    /// routes destinations directly, through the proxies or nowhere. (default: through the proxies)
+    pub rules: Rules,
+    /// sockets to proxies and to destinations of direct routes
+    pub outbound: OutboundOptions,
     /// timeout of accpet connection from client. (default 3s)
     pub accept_timeout: Option<Duration>,
+    /// accept only IPv6 connections on IPv6 listeners (`IPV6_V6ONLY`).
+    /// `Some(false)` makes them dual-stack. (default: system setting)
+    pub v6_only: Option<bool>,
+    /// options for unix domain socket listeners
+    pub unix_socket: UnixSocketOptions,
+    /// listeners terminating TLS
+    pub tls: TlsServerOptions,
+    /// PROXY protocol headers of clients of TCP and TLS listeners. (default: not read)
+    pub proxy_protocol: Option<ProxyProtocolOptions>,
+    /// TLS to `tls://` destinations over the proxied streams. (default: plaintext destinations)
+    pub dst_tls: Option<TlsClientOptions>,
+    /// TLS to `socks5h+tls://` and `socks5+tls://` proxies
+    pub proxy_tls: TlsClientOptions,
+    /// duration to cache names of proxies and names resolved locally for `socks5` proxies. (default: 60s)
+    pub dns_cache_ttl: Duration,
+    /// address family tried first among names resolved locally. (default: System)
+    pub ip_preference: IpPreference,
+    /// UDP associations of clients without traffic are closed after this. (default: 60s)
+    pub udp_idle_timeout: Duration,
+    /// datagrams of new clients are dropped while this many UDP associations are open. (default: 1024)
+    pub udp_max_sessions: usize,
 }
 
 impl ServerConfig {
     pub fn new(server_addr: SocketAddr, proxy_addr: SocketAddr, dst_addr: Address) -> Self {
+        ServerConfig::with_server_addrs(vec![server_addr.into()], proxy_addr.into(), dst_addr)
+    }
+
+    pub fn with_server_addrs(
+        server_addrs: Vec<SockAddr>,
+        proxy_addr: Address,
+        dst_addr: Address,
+    ) -> Self {
         Self {
-            server_addr,
-            proxy_addr,
-            dst_addr,
+            protocol: L4Protocol::Tcp,
+            server_addrs,
+            proxies: vec![vec![ProxyConfig::new(ProxyProtocol::Socks5h, proxy_addr)]],
+            proxy_cooldown: Duration::from_secs(30),
+            balance: BalanceStrategy::Priority,
+            health_check: None,
+            dst_addrs: vec![dst_addr],
+            dst_order: DestinationOrder::Failover,
             client_rw_timeout: Some(Duration::from_millis(2000)),
             server_rw_timeout: Some(Duration::from_millis(5000)),
+            connect_timeout: Some(Duration::from_secs(10)),
+            handshake_timeout: Some(Duration::from_secs(10)),
+            retry: RetryPolicy::default(),
+            rules: Rules::default(),
+            outbound: OutboundOptions::default(),
             accept_timeout: Some(Duration::from_secs(3)),
+            v6_only: None,
+            unix_socket: UnixSocketOptions::default(),
+            tls: TlsServerOptions::default(),
+            proxy_protocol: None,
+            dst_tls: None,
+            proxy_tls: TlsClientOptions::default(),
+            dns_cache_ttl: Duration::from_secs(60),
+            ip_preference: IpPreference::System,
+            udp_idle_timeout: Duration::from_secs(60),
+            udp_max_sessions: 1024,
+        }
+    }
+
+    /// Credentials for every alternative of the last hop
+    pub fn set_last_hop_auth(&mut self, auth: Credentials) {
+        if let Some(hop) = self.proxies.last_mut() {
+            for proxy in hop {
+                proxy.auth = Some(auth.clone());
+            }
         }
     }
 }
@@ -38,3 +130,177 @@ impl Default for ServerConfig {
         )
     }
 }
+
+/// Proxy server to go through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// protocol to talk with the proxy
+    pub protocol: ProxyProtocol,
+    /// resolved at every connection if it is a domain name
+    pub addr: Address,
+    /// credentials to the proxy. only username is used by SOCKS4 as USERID.
+    pub auth: Option<Credentials>,
+    /// relative weight for weighted random balancing. (default: 1)
+    pub weight: u32,
+    /// talk with the proxy in TLS
+    pub tls: bool,
+    /// TLS options of this proxy instead of `proxy_tls` of the server
+    pub tls_options: Option<TlsClientOptions>,
+}
+
+impl ProxyConfig {
+    pub fn new<A: Into<Address>>(protocol: ProxyProtocol, addr: A) -> Self {
+        Self {
+            protocol,
+            addr: addr.into(),
+            auth: None,
+            weight: 1,
+            tls: false,
+            tls_options: None,
+        }
+    }
+}
+
+/// Options for health checks of proxies
+#[derive(Debug, Clone)]
+pub struct HealthCheckOptions {
+    /// interval between checks of each proxy. (default: 10s)
+    pub interval: Duration,
+    /// timeout of connecting and handshaking. (default: 3s)
+    pub timeout: Duration,
+    /// destination to request to proxies. only TCP connections are checked without it.
+    pub probe: Option<Address>,
+    /// consecutive successes to mark a down proxy up. (default: 2)
+    pub rise: u32,
+    /// consecutive failures to mark an up proxy down. (default: 3)
+    pub fall: u32,
+}
+
+impl Default for HealthCheckOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(3),
+            probe: None,
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+/// Options for outbound sockets to steer them by policy routing
+///
+/// They apply to connections to proxies, to destinations of direct routes,
+/// and to health checks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutboundOptions {
+    /// local address to bind. only addresses of its family are connected. (default: any)
+    pub bind_addr: Option<IpAddr>,
+    /// interface to bind by `SO_BINDTODEVICE`. needs `CAP_NET_RAW`. (default: any)
+    pub interface: Option<String>,
+    /// `SO_MARK` for routing rules. needs `CAP_NET_ADMIN`. (default: none)
+    pub fwmark: Option<u32>,
+    /// IPv4 TOS or IPv6 traffic class. DSCP is its upper 6 bits. (default: system setting)
+    pub tos: Option<u8>,
+}
+
+/// Options for unix domain socket listeners
+///
+/// Permissions and ownership are applied to filesystem sockets only.
+/// Abstract sockets have no permissions.
+#[derive(Debug, Clone)]
+pub struct UnixSocketOptions {
+    /// permission bits of the socket file, e.g. `0o660`. (default: follows umask)
+    pub mode: Option<u32>,
+    /// user id of the owner of the socket file. (default: unchanged)
+    pub owner: Option<u32>,
+    /// group id of the owner of the socket file. (default: unchanged)
+    pub group: Option<u32>,
+    /// remove the socket file left by a dead process before binding. (default: true)
+    pub remove_stale: bool,
+}
+
+impl Default for UnixSocketOptions {
+    fn default() -> Self {
+        Self {
+            mode: None,
+            owner: None,
+            group: None,
+            remove_stale: true,
+        }
+    }
+}
+
+/// PROXY protocol v1 and v2 headers sent by load balancers before the data of clients
+#[derive(Debug, Clone)]
+pub struct ProxyProtocolOptions {
+    /// sources sending headers, e.g. load balancers. headers of other sources are not read.
+    pub trusted: Vec<IpNet>,
+    /// timeout of reading the whole header. (default: 3s)
+    pub timeout: Duration,
+}
+
+impl Default for ProxyProtocolOptions {
+    fn default() -> Self {
+        Self {
+            trusted: vec![],
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Options for listeners terminating TLS
+#[derive(Debug, Clone)]
+pub struct TlsServerOptions {
+    /// TCP listen addresses of TLS. they are in `server_addrs` too.
+    pub addrs: Vec<SockAddr>,
+    /// certificates selected by SNI. one without server name is the default.
+    pub certs: Vec<TlsCertificate>,
+    /// require client certificates signed by the CAs in the PEM bundle. (default: no client auth)
+    pub client_ca: Option<PathBuf>,
+    /// CRLs in PEM or DER revoking client certificates
+    pub client_crls: Vec<PathBuf>,
+    /// deadline of reading the whole handshake of each client,
+    /// and timeout of each write of the handshake. (default: 10s)
+    pub handshake_timeout: Duration,
+}
+
+/// TLS to destinations through the proxies, or to proxies
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TlsClientOptions {
+    /// CAs in the PEM bundle verifying servers. (default: system roots)
+    pub ca: Option<PathBuf>,
+    /// server name to send by SNI and to verify. (default: host of the server)
+    pub server_name: Option<String>,
+    /// ALPN protocols to offer, e.g. `h2`
+    pub alpn: Vec<String>,
+    /// client certificate presented to servers requesting one. `server_name` is unused.
+    pub cert: Option<TlsCertificate>,
+    /// SHA-256 digests of certificates, one of which the server certificate must be
+    /// in addition to the CA verification. (default: any certificate)
+    pub fingerprints: Vec<Vec<u8>>,
+}
+
+impl Default for TlsServerOptions {
+    fn default() -> Self {
+        Self {
+            addrs: vec![],
+            certs: vec![],
+            client_ca: None,
+            client_crls: vec![],
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Certificate chain and private key in PEM files
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TlsCertificate {
+    /// name to select the certificate by SNI, e.g. `camera.example.com` or `*.example.com`.
+    /// `None` for the default certificate.
+    pub server_name: Option<String>,
+    /// certificate chain from the end entity
+    pub cert: PathBuf,
+    /// PKCS#8 or RSA private key
+    pub key: PathBuf,
+}
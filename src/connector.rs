@@ -1,53 +1,2327 @@
-use std::net::{SocketAddr, TcpStream};
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use failure::Fail;
+use log::*;
+
+use crate::balancer::{BalanceStrategy, Balancer};
 use crate::byte_stream::ByteStream;
-use crate::model::error::Error;
+use crate::config::{OutboundOptions, ProxyConfig, ServerConfig, TlsClientOptions};
+use crate::health::HealthState;
+use crate::http;
+use crate::model::error::{Error, ErrorKind};
 use crate::model::model::*;
-use crate::pkt_stream::{PktStream, UdpPktStream};
+use crate::outbound;
+use crate::pkt_stream::{
+    PktStream, ProxyPktStream, Socks5PktStream, UdpPktStream, MAX_UDP_PAYLOAD,
+};
+use crate::resolver::Resolver;
+use crate::rules::{Route, Rules};
+use crate::socks4;
+use crate::socks5;
+use crate::thread::spawn_thread;
+use crate::tls::{TlsClient, TlsClientStream};
 
-use socks::Socks5Stream;
+/// Delay before racing the next address of a proxy (RFC 8305 Connection Attempt Delay)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
 pub trait Connector: Send {
     type B: ByteStream;
     type P: PktStream;
-    fn connect_byte_stream(&self, addr: Address) -> Result<(Self::B, SocketAddr), Error>;
+    /// Returns the stream, the connected address of the proxy
+    /// and the address bound by the proxy if it tells.
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> Result<(Self::B, SocketAddr, Option<Address>), Error>;
     fn connect_pkt_stream(&self, addr: Address) -> Result<(Self::P, SocketAddr), Error>;
+
+    /// Connector for a new session of `client` and the first proxy the session goes through
+    ///
+    /// `load` counts live sessions by their first proxies.
+    /// Connectors without alternative proxies, and direct or rejected routes, set none.
+    fn assign(&self, _client: &SockAddr, _load: &HashMap<Address, usize>) -> (Self, UsedProxy)
+    where
+        Self: Sized + Clone,
+    {
+        (self.clone(), UsedProxy::default())
+    }
+
+    /// Connector for a session of the client authenticated as `identity`
+    fn identify(&self, _identity: &str) -> Self
+    where
+        Self: Sized + Clone,
+    {
+        self.clone()
+    }
+}
+
+/// First proxy of a session. Shared by clones.
+///
+/// The assigned proxy until connected, then the first proxy of the last connection.
+#[derive(Debug, Clone, Default)]
+pub struct UsedProxy(Arc<Mutex<Option<Address>>>);
+
+impl UsedProxy {
+    fn new(proxy: Option<Address>) -> Self {
+        Self(Arc::new(Mutex::new(proxy)))
+    }
+
+    pub fn get(&self) -> Option<Address> {
+        match self.0.lock() {
+            Ok(proxy) => proxy.clone(),
+            Err(_) => None,
+        }
+    }
+
+    fn set(&self, proxy: &Address) {
+        if let Ok(mut used) = self.0.lock() {
+            *used = Some(proxy.clone());
+        }
+    }
+
+    /// The session goes through no proxy
+    fn clear(&self) {
+        if let Ok(mut used) = self.0.lock() {
+            *used = None;
+        }
+    }
+}
+
+/// Timeouts of connections to proxies
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// establishing a TCP connection to the proxy
+    pub connect: Option<Duration>,
+    /// the whole handshake with a proxy
+    pub handshake: Option<Duration>,
+    /// each read and write after the handshake
+    pub rw: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            connect: config.connect_timeout,
+            handshake: config.handshake_timeout,
+            rw: config.server_rw_timeout,
+        }
+    }
+
+    /// Connect to `addr` of `peer` within the connect timeout
+    ///
+    /// `peer` is a proxy, or the destination if `direct` is set.
+    fn connect(
+        &self,
+        outbound: &OutboundOptions,
+        peer: &Address,
+        addr: SocketAddr,
+        direct: bool,
+    ) -> Result<TcpStream, Error> {
+        outbound::connect(outbound, addr, self.connect).map_err(|err| match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                let kind = if direct {
+                    ErrorKind::DestinationTimeout { addr: peer.clone() }
+                } else {
+                    ErrorKind::ConnectTimeout {
+                        proxy: peer.clone(),
+                    }
+                };
+                err.context(kind).into()
+            }
+            _ => err.into(),
+        })
+    }
+
+    /// Run `handshake` with `proxy` over `strm` within the handshake timeout
+    ///
+    /// The rw timeout is set on `strm` afterwards.
+    fn handshake<T, F>(
+        &self,
+        proxy: &Address,
+        strm: &mut ProxyStream,
+        handshake: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Deadline) -> Result<T, Error>,
+    {
+        strm.set_read_timeout(self.rw)?;
+        strm.set_write_timeout(self.rw)?;
+        let deadline = self.handshake.map(|timeout| Instant::now() + timeout);
+        let result = handshake(&mut Deadline { strm, deadline }).map_err(|err| {
+            if err.is_timeout() {
+                err.context(ErrorKind::HandshakeTimeout {
+                    proxy: proxy.clone(),
+                })
+                .into()
+            } else {
+                err
+            }
+        });
+        strm.set_read_timeout(self.rw)?;
+        strm.set_write_timeout(self.rw)?;
+        result
+    }
+}
+
+/// Stream fails with `TimedOut` after the deadline
+pub(crate) struct Deadline<'a> {
+    strm: &'a mut ProxyStream,
+    deadline: Option<Instant>,
+}
+
+impl Deadline<'_> {
+    /// Time left before the deadline
+    fn remaining(&self) -> io::Result<Option<Duration>> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(None),
+        };
+        let now = Instant::now();
+        if now < deadline {
+            Ok(Some(deadline - now))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "handshake timed out",
+            ))
+        }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(timeout) = self.remaining()? {
+            self.strm.set_read_timeout(Some(timeout))?;
+        }
+        self.strm.read(buf)
+    }
+}
+
+impl Write for Deadline<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(timeout) = self.remaining()? {
+            self.strm.set_write_timeout(Some(timeout))?;
+        }
+        self.strm.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.strm.flush()
+    }
+}
+
+/// Connection to a proxy, in TLS with `socks5h+tls://` proxies
+///
+/// Timeouts are set on the TCP connection under TLS.
+#[derive(Debug)]
+pub struct ProxyStream {
+    sock: TcpStream,
+    /// TLS session with the proxy, which may be a hop through the tunnels of other proxies
+    tls: Option<TlsClientStream>,
+}
+
+impl ProxyStream {
+    /// Start TLS with `proxy` over the stream
+    fn start_tls(self, client: &TlsClient, proxy: &Address) -> Result<Self, Error> {
+        let sock = self.sock.try_clone()?;
+        let tls = client.connect(proxy, self)?;
+        Ok(Self {
+            sock,
+            tls: Some(tls),
+        })
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+}
+
+impl From<TcpStream> for ProxyStream {
+    fn from(sock: TcpStream) -> Self {
+        Self { sock, tls: None }
+    }
+}
+
+impl Read for ProxyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.tls {
+            Some(tls) => tls.read(buf),
+            None => self.sock.read(buf),
+        }
+    }
+}
+
+impl Write for ProxyStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.tls {
+            Some(tls) => tls.write(buf),
+            None => self.sock.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.tls {
+            Some(tls) => tls.flush(),
+            None => self.sock.flush(),
+        }
+    }
+}
+
+impl ByteStream for ProxyStream {
+    #[allow(clippy::type_complexity)]
+    fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+        match &self.tls {
+            Some(tls) => tls.split(),
+            None => self.sock.split(),
+        }
+    }
+}
+
+/// Connects to proxies resolving their names at every connection
+#[derive(Debug, Clone)]
+pub struct Dialer {
+    timeouts: Timeouts,
+    /// resolves proxies, and destinations of socks5:// proxies
+    resolver: Resolver,
+    /// applied to every socket
+    outbound: OutboundOptions,
+    /// TLS with `socks5h+tls://` proxies without their own options
+    tls: TlsClientOptions,
+    /// clients by their options, created at their first TLS connections. shared by clones.
+    tls_clients: Arc<Mutex<HashMap<TlsClientOptions, Arc<TlsClient>>>>,
+}
+
+impl Dialer {
+    pub fn new(timeouts: Timeouts, resolver: Resolver) -> Self {
+        Self {
+            timeouts,
+            resolver,
+            outbound: OutboundOptions::default(),
+            tls: TlsClientOptions::default(),
+            tls_clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Bind, mark and set TOS of sockets by `outbound`
+    pub fn with_outbound(self, outbound: OutboundOptions) -> Self {
+        Self { outbound, ..self }
+    }
+
+    /// Verify proxies talking in TLS by `tls` unless they have their own options
+    pub fn with_tls(self, tls: TlsClientOptions) -> Self {
+        Self {
+            tls,
+            tls_clients: Arc::new(Mutex::new(HashMap::new())),
+            ..self
+        }
+    }
+
+    /// TLS options with `proxy`, or `None` if it talks in plaintext
+    pub(crate) fn proxy_tls<'a>(&'a self, proxy: &'a ProxyConfig) -> Option<&'a TlsClientOptions> {
+        if proxy.tls {
+            Some(proxy.tls_options.as_ref().unwrap_or(&self.tls))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
+    /// Connect to one of the addresses of `proxy`. Returns the connected address.
+    ///
+    /// Addresses are tried in order, or raced Happy Eyeballs style
+    /// when both IPv4 and IPv6 addresses are resolved.
+    /// Only addresses of the family of the bind address are tried if it is given.
+    pub(crate) fn connect(&self, proxy: &Address) -> Result<(TcpStream, SocketAddr), Error> {
+        self.dial(proxy, false)
+    }
+
+    /// Connect to the destination `addr` without proxies like `connect`
+    pub(crate) fn connect_direct(&self, addr: &Address) -> Result<(TcpStream, SocketAddr), Error> {
+        self.dial(addr, true)
+    }
+
+    /// Connect to one of the addresses of `peer`, which is the destination if `direct` is set
+    fn dial(&self, peer: &Address, direct: bool) -> Result<(TcpStream, SocketAddr), Error> {
+        let (addrs, others): (Vec<_>, _) = self
+            .resolver
+            .resolve(peer)?
+            .into_iter()
+            .partition(|addr| outbound::reachable(&self.outbound, addr));
+        // fails with the mismatch of families
+        let addrs = if addrs.is_empty() { others } else { addrs };
+        let mixed = addrs
+            .iter()
+            .any(|addr| addr.is_ipv4() != addrs[0].is_ipv4());
+        if mixed {
+            return self.race(peer, interleave(addrs), direct);
+        }
+        let mut last_err = None;
+        for addr in addrs {
+            match self.timeouts.connect(&self.outbound, peer, addr, direct) {
+                Ok(strm) => return Ok((strm, addr)),
+                Err(err) => {
+                    debug!("connect error: {}: {}: {}", peer, addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("resolved at least one address"))
+    }
+
+    /// Start connecting to the next address at every failure or attempt delay until one succeeds
+    fn race(
+        &self,
+        peer: &Address,
+        addrs: Vec<SocketAddr>,
+        direct: bool,
+    ) -> Result<(TcpStream, SocketAddr), Error> {
+        let (tx, rx) = mpsc::channel();
+        let mut addrs = addrs.into_iter();
+        let mut pending = 0;
+        let mut last_err = None;
+        loop {
+            if let Some(addr) = addrs.next() {
+                let (timeouts, peer, tx) = (self.timeouts, peer.clone(), tx.clone());
+                let outbound = self.outbound.clone();
+                spawn_thread(&format!("connect: {}", addr), move || {
+                    // the receiver is gone if another address has won
+                    tx.send((addr, timeouts.connect(&outbound, &peer, addr, direct)))
+                        .ok();
+                })?;
+                pending += 1;
+            } else if pending == 0 {
+                return Err(last_err.expect("resolved at least one address"));
+            }
+            let received = if addrs.as_slice().is_empty() {
+                rx.recv().ok()
+            } else {
+                rx.recv_timeout(CONNECTION_ATTEMPT_DELAY).ok()
+            };
+            match received {
+                Some((addr, Ok(strm))) => return Ok((strm, addr)),
+                Some((addr, Err(err))) => {
+                    debug!("connect error: {}: {}: {}", peer, addr, err);
+                    pending -= 1;
+                    last_err = Some(err);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Connect to `proxy`, and start TLS with it if `tls` is given. Returns the connected address.
+    pub(crate) fn connect_proxy(
+        &self,
+        proxy: &Address,
+        tls: Option<&TlsClientOptions>,
+    ) -> Result<(ProxyStream, SocketAddr), Error> {
+        let (strm, addr) = self
+            .connect(proxy)
+            .map_err(|err| proxy_io_error(proxy, err))?;
+        match tls {
+            Some(tls) => Ok((self.start_tls(proxy, tls, strm.into())?, addr)),
+            None => Ok((strm.into(), addr)),
+        }
+    }
+
+    /// Start TLS with `proxy` over `strm` by `tls`
+    ///
+    /// Each read and write of the TLS handshake times out after the handshake timeout.
+    pub(crate) fn start_tls(
+        &self,
+        proxy: &Address,
+        tls: &TlsClientOptions,
+        strm: ProxyStream,
+    ) -> Result<ProxyStream, Error> {
+        let timeout = self.timeouts.handshake.or(self.timeouts.rw);
+        strm.set_read_timeout(timeout)?;
+        strm.set_write_timeout(timeout)?;
+        let strm = strm
+            .start_tls(&*self.tls_client(tls)?, proxy)
+            .map_err(|err| proxy_io_error(proxy, err))?;
+        debug!("tls started: {}", proxy);
+        Ok(strm)
+    }
+
+    fn tls_client(&self, tls: &TlsClientOptions) -> Result<Arc<TlsClient>, Error> {
+        let mut cached = match self.tls_clients.lock() {
+            Ok(cached) => cached,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(client) = cached.get(tls) {
+            return Ok(client.clone());
+        }
+        let client = Arc::new(TlsClient::new(tls)?);
+        cached.insert(tls.clone(), client.clone());
+        Ok(client)
+    }
+
+    /// Run `handshake` with `proxy` over `strm` within the handshake timeout
+    pub(crate) fn handshake<T, F>(
+        &self,
+        proxy: &Address,
+        strm: &mut ProxyStream,
+        handshake: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Deadline) -> Result<T, Error>,
+    {
+        self.timeouts
+            .handshake(proxy, strm, handshake)
+            .map_err(|err| proxy_io_error(proxy, err))
+    }
+}
+
+/// Tell that IO with `proxy` failed by `err`, unlike IO errors of the destination
+fn proxy_io_error(proxy: &Address, err: Error) -> Error {
+    match err.kind() {
+        ErrorKind::Io => err
+            .context(ErrorKind::ProxyConnectionFailed {
+                proxy: proxy.clone(),
+            })
+            .into(),
+        _ => err,
+    }
+}
+
+/// Alternate address families starting from the family of the first address
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v4 = match addrs.first() {
+        Some(addr) => addr.is_ipv4(),
+        None => return addrs,
+    };
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv4() == first_v4);
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    let mut addrs = vec![];
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return addrs,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct SocksConnector {
-    proxy_addr: SocketAddr,
-    rw_timeout: Option<Duration>,
+    proxy: Address,
+    /// username/password authentication to the proxy
+    auth: Option<Credentials>,
+    dialer: Dialer,
+    /// resolve domain names locally instead of sending them to the proxy
+    local_dns: bool,
+    /// talk SOCKS5 inside TLS with the proxy by the options
+    tls: Option<Box<TlsClientOptions>>,
 }
 
 impl SocksConnector {
-    pub fn new(proxy_addr: SocketAddr, rw_timeout: Option<Duration>) -> Self {
+    pub fn new(proxy: Address, auth: Option<Credentials>, dialer: Dialer) -> Self {
         Self {
-            proxy_addr,
-            rw_timeout,
+            proxy,
+            auth,
+            dialer,
+            local_dns: false,
+            tls: None,
+        }
+    }
+
+    /// Resolve destinations before requesting them to the proxy (socks5://)
+    pub fn with_local_dns(self) -> Self {
+        Self {
+            local_dns: true,
+            ..self
+        }
+    }
+
+    /// Start TLS with the proxy by `tls` before the SOCKS5 handshake (socks5h+tls://)
+    pub fn with_tls(self, tls: TlsClientOptions) -> Self {
+        Self {
+            tls: Some(Box::new(tls)),
+            ..self
+        }
+    }
+
+    /// Destinations to request to the proxy in order
+    fn destinations(&self, addr: Address) -> Result<Vec<Address>, Error> {
+        if self.local_dns {
+            Ok(self
+                .dialer
+                .resolver
+                .resolve(&addr)?
+                .into_iter()
+                .map(Address::from)
+                .collect())
+        } else {
+            Ok(vec![addr])
+        }
+    }
+
+    fn connect(&self, addr: Address) -> Result<(ProxyStream, SocketAddr, Option<Address>), Error> {
+        let (mut strm, proxy_addr) = self
+            .dialer
+            .connect_proxy(&self.proxy, self.tls.as_deref())?;
+        let bound = self.dialer.handshake(&self.proxy, &mut strm, |strm| {
+            socks5_handshake(strm, &self.proxy, self.auth.as_ref(), &addr)
+        })?;
+        Ok((strm, proxy_addr, Some(bound)))
+    }
+
+    /// Address to send datagrams to
+    ///
+    /// Unspecified relay address means the address of the proxy.
+    /// Domain names are resolved within the handshake deadline of `strm`.
+    fn relay_addr(
+        &self,
+        strm: &Deadline,
+        relay: Address,
+        proxy_addr: SocketAddr,
+    ) -> Result<SocketAddr, Error> {
+        let relay = self
+            .dialer
+            .resolver
+            .resolve_timeout(&relay, strm.remaining()?)?
+            .remove(0);
+        if relay.ip().is_unspecified() {
+            Ok(SocketAddr::new(proxy_addr.ip(), relay.port()))
+        } else {
+            Ok(relay)
         }
     }
 }
 
 impl Connector for SocksConnector {
-    type B = TcpStream;
-    type P = UdpPktStream;
+    type B = ProxyStream;
+    type P = Socks5PktStream;
+
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> Result<(Self::B, SocketAddr, Option<Address>), Error> {
+        // try resolved addresses in order
+        let mut last_err = None;
+        for addr in self.destinations(addr)? {
+            match self.connect(addr.clone()) {
+                Ok(conn) => return Ok(conn),
+                Err(err) => {
+                    if let ErrorKind::AuthenticationFailed { .. } = err.kind() {
+                        return Err(err);
+                    }
+                    debug!("connect error: {}: {}", addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("resolved at least one address"))
+    }
+
+    fn connect_pkt_stream(&self, addr: Address) -> Result<(Self::P, SocketAddr), Error> {
+        let addr = self.destinations(addr)?.remove(0);
+        let (mut control, proxy_addr) = self
+            .dialer
+            .connect_proxy(&self.proxy, self.tls.as_deref())?;
+        let relay_addr = self.dialer.handshake(&self.proxy, &mut control, |strm| {
+            socks5_negotiate(strm, &self.proxy, self.auth.as_ref())?;
+            // the source address of datagrams is not known before sending them
+            let src_addr = Address::IpAddr(Ipv4Addr::UNSPECIFIED.into(), 0);
+            let relay = socks5_request(strm, &self.proxy, socks5::CMD_UDP_ASSOCIATE, &src_addr)?;
+            self.relay_addr(strm, relay, proxy_addr)
+        })?;
+
+        let socket = outbound::bind_udp(&self.dialer.outbound, relay_addr)?;
+        socket.connect(relay_addr)?;
+        socket.set_read_timeout(self.dialer.timeouts.rw)?;
+        socket.set_write_timeout(self.dialer.timeouts.rw)?;
+
+        // datagrams are not in TLS, which only protects the handshake
+        let strm = Socks5PktStream::new(socket, addr, control.sock)?;
+        Ok((strm, proxy_addr))
+    }
+}
+
+/// Connector tunnels through an HTTP proxy by CONNECT method
+#[derive(Debug, Clone)]
+pub struct HttpConnector {
+    proxy: Address,
+    /// Basic authentication to the proxy
+    auth: Option<Credentials>,
+    dialer: Dialer,
+}
+
+impl HttpConnector {
+    pub fn new(proxy: Address, auth: Option<Credentials>, dialer: Dialer) -> Self {
+        Self {
+            proxy,
+            auth,
+            dialer,
+        }
+    }
+}
+
+impl Connector for HttpConnector {
+    type B = ProxyStream;
+    type P = Socks5PktStream;
+
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> Result<(Self::B, SocketAddr, Option<Address>), Error> {
+        let (mut strm, proxy_addr) = self.dialer.connect_proxy(&self.proxy, None)?;
+        self.dialer.handshake(&self.proxy, &mut strm, |strm| {
+            http_handshake(strm, &self.proxy, self.auth.as_ref(), &addr)
+        })?;
+        Ok((strm, proxy_addr, None))
+    }
+
+    fn connect_pkt_stream(&self, _addr: Address) -> Result<(Self::P, SocketAddr), Error> {
+        Err(ErrorKind::NotSupported {
+            proxy: self.proxy.clone(),
+            protocol: L4Protocol::Udp,
+        }
+        .into())
+    }
+}
 
-    fn connect_byte_stream(&self, addr: Address) -> Result<(Self::B, SocketAddr), Error> {
-        let strm = Socks5Stream::connect(self.proxy_addr, addr)?;
-        let strm = strm.into_inner();
-        strm.set_read_timeout(self.rw_timeout)?;
-        strm.set_write_timeout(self.rw_timeout)?;
+/// Connector talks SOCKS4, or SOCKS4a when `remote_dns` is set
+#[derive(Debug, Clone)]
+pub struct Socks4Connector {
+    proxy: Address,
+    /// USERID field of requests
+    userid: String,
+    /// send domain names to the proxy (SOCKS4a)
+    remote_dns: bool,
+    dialer: Dialer,
+}
+
+impl Socks4Connector {
+    pub fn new(proxy: Address, userid: String, remote_dns: bool, dialer: Dialer) -> Self {
+        Self {
+            proxy,
+            userid,
+            remote_dns,
+            dialer,
+        }
+    }
+}
 
-        Ok((strm, self.proxy_addr))
+impl Connector for Socks4Connector {
+    type B = ProxyStream;
+    type P = Socks5PktStream;
+
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> Result<(Self::B, SocketAddr, Option<Address>), Error> {
+        socks4_destination(&self.proxy, self.remote_dns, &addr)?;
+        let (mut strm, proxy_addr) = self.dialer.connect_proxy(&self.proxy, None)?;
+        self.dialer.handshake(&self.proxy, &mut strm, |strm| {
+            socks4_handshake(strm, &self.proxy, &self.userid, &addr)
+        })?;
+        Ok((strm, proxy_addr, None))
     }
 
     fn connect_pkt_stream(&self, _addr: Address) -> Result<(Self::P, SocketAddr), Error> {
-        unimplemented!("connect_pkt_stream")
-        /*
-        let sock_addr = self.resolve(addr)?;
-        UdpSocket::connect(sock_addr).map_err(Into::into)
-        */
+        Err(ErrorKind::NotSupported {
+            proxy: self.proxy.clone(),
+            protocol: L4Protocol::Udp,
+        }
+        .into())
+    }
+}
+
+/// Request a SOCKS5 tunnel to `addr` over `strm`. Returns the bound address.
+fn socks5_handshake<S: Read + Write>(
+    strm: &mut S,
+    proxy: &Address,
+    auth: Option<&Credentials>,
+    addr: &Address,
+) -> Result<Address, Error> {
+    socks5_negotiate(strm, proxy, auth)?;
+    socks5_request(strm, proxy, socks5::CMD_CONNECT, addr)
+}
+
+/// Send a SOCKS5 request of `cmd` for `addr`. Returns the bound address.
+fn socks5_request<S: Read + Write>(
+    strm: &mut S,
+    proxy: &Address,
+    cmd: u8,
+    addr: &Address,
+) -> Result<Address, Error> {
+    socks5::request(strm, cmd, addr).map_err(|err| match socks5::reply_of(&err) {
+        Some(reply) => err.context(reply_error(reply, proxy, cmd, addr)).into(),
+        None => Error::from(err),
+    })
+}
+
+/// Error of a failure `reply` of `proxy` to the request
+fn reply_error(reply: socks5::Reply, proxy: &Address, cmd: u8, addr: &Address) -> ErrorKind {
+    use socks5::Reply;
+    let (proxy, addr) = (proxy.clone(), addr.clone());
+    match reply {
+        Reply::GeneralFailure => ErrorKind::ProxyGeneralFailure { proxy },
+        Reply::NotAllowed => ErrorKind::ConnectionNotAllowed { proxy, addr },
+        Reply::NetworkUnreachable => ErrorKind::NetworkUnreachable { proxy, addr },
+        Reply::HostUnreachable => ErrorKind::HostUnreachable { proxy, addr },
+        Reply::ConnectionRefused => ErrorKind::ConnectionRefused { proxy, addr },
+        Reply::TtlExpired => ErrorKind::TtlExpired { proxy, addr },
+        Reply::CommandNotSupported => ErrorKind::CommandNotSupported {
+            proxy,
+            command: cmd,
+        },
+        Reply::AddressTypeNotSupported => ErrorKind::AddressTypeNotSupported { proxy, addr },
+        Reply::Unassigned(code) => ErrorKind::UnassignedReply { proxy, code },
+    }
+}
+
+/// Negotiate the SOCKS5 authentication method over `strm`
+fn socks5_negotiate<S: Read + Write>(
+    strm: &mut S,
+    proxy: &Address,
+    auth: Option<&Credentials>,
+) -> Result<(), Error> {
+    socks5::negotiate(strm, auth).map_err(|err| {
+        if err.kind() == io::ErrorKind::PermissionDenied {
+            err.context(ErrorKind::AuthenticationFailed {
+                proxy: proxy.clone(),
+            })
+            .into()
+        } else {
+            Error::from(err)
+        }
+    })
+}
+
+/// Request an HTTP CONNECT tunnel to `addr` over `strm`
+fn http_handshake<S: Read + Write>(
+    strm: &mut S,
+    proxy: &Address,
+    auth: Option<&Credentials>,
+    addr: &Address,
+) -> Result<(), Error> {
+    let status = http::connect(strm, addr, auth)?;
+    let proxy = proxy.clone();
+    if status.is_success() {
+        Ok(())
+    } else if status.code == 407 {
+        Err(ErrorKind::AuthenticationFailed { proxy }.into())
+    } else {
+        Err(ErrorKind::HttpConnectFailed {
+            proxy,
+            status: status.code,
+            reason: status.reason,
+        }
+        .into())
+    }
+}
+
+/// SOCKS4 connects to IPv4 addresses only. SOCKS4a also sends domain names.
+fn socks4_destination(proxy: &Address, remote_dns: bool, addr: &Address) -> Result<(), Error> {
+    let supported = match addr {
+        Address::IpAddr(ip, _) => ip.is_ipv4(),
+        Address::Domain(..) => remote_dns,
+    };
+    if supported {
+        Ok(())
+    } else {
+        Err(ErrorKind::DestinationNotSupported {
+            proxy: proxy.clone(),
+            addr: addr.clone(),
+        }
+        .into())
+    }
+}
+
+/// Request a SOCKS4 tunnel to `addr` over `strm`
+fn socks4_handshake<S: Read + Write>(
+    strm: &mut S,
+    proxy: &Address,
+    userid: &str,
+    addr: &Address,
+) -> Result<(), Error> {
+    socks4::connect(strm, addr, userid).map_err(|err| {
+        if socks4::is_rejected(&err) {
+            err.context(ErrorKind::ConnectionRefused {
+                proxy: proxy.clone(),
+                addr: addr.clone(),
+            })
+            .into()
+        } else if err.kind() == io::ErrorKind::PermissionDenied {
+            err.context(ErrorKind::AuthenticationFailed {
+                proxy: proxy.clone(),
+            })
+            .into()
+        } else {
+            Error::from(err)
+        }
+    })
+}
+
+/// Resolve `addr` of the next proxy to its first IPv4 address for SOCKS4 proxies
+fn socks4_target(resolver: &Resolver, proxy: &Address, addr: Address) -> Result<Address, Error> {
+    match resolver
+        .resolve(&addr)?
+        .into_iter()
+        .find(SocketAddr::is_ipv4)
+    {
+        Some(ipv4) => Ok(ipv4.into()),
+        None => Err(ErrorKind::DestinationNotSupported {
+            proxy: proxy.clone(),
+            addr,
+        }
+        .into()),
+    }
+}
+
+/// Resolve `addr` of the next proxy locally for proxies without remote name resolution
+pub(crate) fn local_target(
+    resolver: &Resolver,
+    proxy: &ProxyConfig,
+    addr: Address,
+) -> Result<Address, Error> {
+    match proxy.protocol {
+        ProxyProtocol::Socks4 => socks4_target(resolver, &proxy.addr, addr),
+        _ => Ok(local_destinations(resolver, proxy, addr)?.remove(0)),
+    }
+}
+
+/// Resolve the destination `addr` locally for socks5:// proxies. Returns addresses to try in order.
+///
+/// socks4:// proxies reject domain names of destinations instead.
+fn local_destinations(
+    resolver: &Resolver,
+    proxy: &ProxyConfig,
+    addr: Address,
+) -> Result<Vec<Address>, Error> {
+    match proxy.protocol {
+        ProxyProtocol::Socks5 => Ok(resolver
+            .resolve(&addr)?
+            .into_iter()
+            .map(Address::from)
+            .collect()),
+        _ => Ok(vec![addr]),
+    }
+}
+
+/// Request a tunnel to `addr` from `proxy` over `strm`. Returns the bound address if told.
+///
+/// `addr` is sent as is. socks5:// and socks4:// proxies expect IP addresses.
+pub(crate) fn handshake<S: Read + Write>(
+    strm: &mut S,
+    proxy: &ProxyConfig,
+    addr: &Address,
+) -> Result<Option<Address>, Error> {
+    let auth = proxy.auth.as_ref();
+    match proxy.protocol {
+        ProxyProtocol::Socks5 | ProxyProtocol::Socks5h => {
+            socks5_handshake(strm, &proxy.addr, auth, addr).map(Some)
+        }
+        ProxyProtocol::Http => http_handshake(strm, &proxy.addr, auth, addr).map(|_| None),
+        ProxyProtocol::Socks4 | ProxyProtocol::Socks4a => {
+            let remote_dns = proxy.protocol == ProxyProtocol::Socks4a;
+            socks4_destination(&proxy.addr, remote_dns, addr)?;
+            let userid = auth.map_or("", |auth| auth.username.as_str());
+            socks4_handshake(strm, &proxy.addr, userid, addr).map(|_| None)
+        }
+    }
+}
+
+/// Connector goes through proxies in order
+///
+/// The first proxy is connected by TCP and asked for a tunnel to the next one.
+/// Handshakes with the following proxies run through the tunnels,
+/// inside TLS started through the tunnels with TLS proxies.
+#[derive(Debug, Clone)]
+pub struct ChainConnector {
+    hops: Vec<ProxyConfig>,
+    /// the connect timeout applies to the first hop and the handshake timeout to each hop.
+    /// its resolver also resolves next hops of socks5:// and socks4:// hops,
+    /// and destinations of socks5:// hops before connecting.
+    dialer: Dialer,
+}
+
+impl ChainConnector {
+    /// `hops` must not be empty
+    pub fn new(hops: Vec<ProxyConfig>, dialer: Dialer) -> Self {
+        assert!(!hops.is_empty(), "no proxies to chain");
+        Self { hops, dialer }
+    }
+
+    /// Request a tunnel to the next hop, or to `addr` from the last hop
+    fn handshake(
+        &self,
+        strm: &mut ProxyStream,
+        index: usize,
+        addr: &Address,
+    ) -> Result<Option<Address>, Error> {
+        let hop = &self.hops[index];
+        let next = match self.hops.get(index + 1) {
+            Some(next) => local_target(&self.dialer.resolver, hop, next.addr.clone())?,
+            None => addr.clone(),
+        };
+        self.dialer
+            .handshake(&hop.addr, strm, |strm| handshake(strm, hop, &next))
+    }
+
+    /// Go through the hops to `addr`
+    fn connect(&self, addr: &Address) -> Result<(ProxyStream, SocketAddr, Option<Address>), Error> {
+        let (mut strm, proxy_addr) = self
+            .dialer
+            .connect_proxy(&self.hops[0].addr, self.dialer.proxy_tls(&self.hops[0]))
+            .map_err(|err| self.hop_error(0, err))?;
+        let mut bound = None;
+        for (index, hop) in self.hops.iter().enumerate() {
+            match self.dialer.proxy_tls(hop) {
+                Some(tls) if index > 0 => {
+                    strm = self
+                        .dialer
+                        .start_tls(&hop.addr, tls, strm)
+                        .map_err(|err| self.hop_error(index, err))?;
+                }
+                _ => {}
+            }
+            bound = self
+                .handshake(&mut strm, index, addr)
+                .map_err(|err| self.hop_error(index, err))?;
+            debug!("tunnel established: hop {}: {}", index + 1, hop.addr);
+        }
+        Ok((strm, proxy_addr, bound))
+    }
+
+    /// Tell which hop failed. `index` starts from 0.
+    fn hop_error(&self, index: usize, err: Error) -> Error {
+        let kind = ErrorKind::ProxyHop {
+            hop: index + 1,
+            last: index + 1 == self.hops.len(),
+            proxy: self.hops[index].addr.clone(),
+            cause: Box::new(err.kind().clone()),
+        };
+        err.context(kind).into()
+    }
+}
+
+impl Connector for ChainConnector {
+    type B = ProxyStream;
+    type P = Socks5PktStream;
+
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> Result<(Self::B, SocketAddr, Option<Address>), Error> {
+        // resolved before connecting, so that failures are not of the proxies
+        let last = self.hops.last().expect("at least one hop");
+        let mut last_err = None;
+        // try resolved addresses in order while the last hop replies about them
+        for addr in local_destinations(&self.dialer.resolver, last, addr)? {
+            match self.connect(&addr) {
+                Ok(conn) => return Ok(conn),
+                Err(err) => match err.kind() {
+                    ErrorKind::ProxyHop {
+                        last: true, cause, ..
+                    } if !is_proxy_failure(cause) => {
+                        debug!("connect error: {}: {}", addr, err);
+                        last_err = Some(err);
+                    }
+                    _ => return Err(err),
+                },
+            }
+        }
+        Err(last_err.expect("resolved at least one address"))
+    }
+
+    fn connect_pkt_stream(&self, _addr: Address) -> Result<(Self::P, SocketAddr), Error> {
+        Err(ErrorKind::NotSupported {
+            proxy: self.hops[0].addr.clone(),
+            protocol: L4Protocol::Udp,
+        }
+        .into())
+    }
+}
+
+/// Connector falls back to alternative proxies of each hop
+///
+/// Proxies failed to connect or handshake are skipped for `cooldown`
+/// unless all the alternatives of the hop are cooling down.
+/// Replies about the destination are returned without trying the alternatives.
+#[derive(Debug, Clone)]
+pub struct FailoverConnector {
+    /// alternatives of each hop by priority
+    hops: Vec<Vec<ProxyConfig>>,
+    cooldown: Duration,
+    dialer: Dialer,
+    /// when proxies failed. shared by sessions.
+    failures: Arc<Mutex<HashMap<Address, Instant>>>,
+    /// chooses the first hop of sessions
+    balancer: Balancer,
+    /// first hop assigned to the session
+    assigned: Option<Address>,
+    /// first hop the session went through
+    used: UsedProxy,
+    /// proxies marked down are skipped like cooling down ones
+    health: HealthState,
+}
+
+impl FailoverConnector {
+    /// Every hop must have at least one proxy
+    pub fn new(hops: Vec<Vec<ProxyConfig>>, cooldown: Duration, dialer: Dialer) -> Self {
+        assert!(
+            !hops.is_empty() && hops.iter().all(|hop| !hop.is_empty()),
+            "no proxies to fail over"
+        );
+        Self {
+            hops,
+            cooldown,
+            dialer,
+            failures: Arc::new(Mutex::new(HashMap::new())),
+            balancer: Balancer::new(BalanceStrategy::Priority),
+            assigned: None,
+            used: UsedProxy::default(),
+            health: HealthState::new(),
+        }
+    }
+
+    /// Skip proxies marked down in `health`
+    pub fn with_health(self, health: HealthState) -> Self {
+        Self { health, ..self }
+    }
+
+    /// Spread sessions over alternatives of the first hop by `strategy`
+    pub fn with_strategy(self, strategy: BalanceStrategy) -> Self {
+        Self {
+            balancer: Balancer::new(strategy),
+            ..self
+        }
+    }
+
+    /// Not cooling down nor marked down by health checks
+    fn available(&self, proxy: &Address) -> bool {
+        !self.cooling_down(proxy) && !self.health.is_down(proxy)
+    }
+
+    pub(crate) fn cooling_down(&self, proxy: &Address) -> bool {
+        let failed = match self.failures.lock() {
+            Ok(failures) => failures.get(proxy).copied(),
+            Err(_) => None,
+        };
+        matches!(failed, Some(failed) if failed.elapsed() < self.cooldown)
+    }
+
+    /// Proxies to go through, skipping `excluded` ones
+    ///
+    /// The assigned proxy or the first available alternative is taken for each hop.
+    fn select(&self, excluded: &HashSet<Address>) -> Option<Vec<ProxyConfig>> {
+        self.hops
+            .iter()
+            .map(|hop| {
+                let mut candidates = hop.iter().filter(|proxy| !excluded.contains(&proxy.addr));
+                let first = candidates.clone().next()?;
+                let proxy = candidates
+                    .clone()
+                    .find(|proxy| {
+                        self.assigned.as_ref() == Some(&proxy.addr) && self.available(&proxy.addr)
+                    })
+                    .or_else(|| candidates.find(|proxy| self.available(&proxy.addr)))
+                    .unwrap_or(first);
+                Some(proxy.clone())
+            })
+            .collect()
+    }
+
+    /// Proxy blamed for `err` on `path`. `None` if the destination failed.
+    fn failed_proxy(path: &[ProxyConfig], err: &Error) -> Option<Address> {
+        match err.kind() {
+            // replies of the last hop are about the destination
+            ErrorKind::ProxyHop {
+                hop, proxy, cause, ..
+            } => {
+                if *hop < path.len() || is_proxy_failure(cause) {
+                    Some(proxy.clone())
+                } else {
+                    None
+                }
+            }
+            kind if is_proxy_failure(kind) => Some(path[0].addr.clone()),
+            _ => None,
+        }
+    }
+
+    /// Proxies of `path` are working
+    fn recovered(&self, path: &[ProxyConfig]) {
+        if let Ok(mut failures) = self.failures.lock() {
+            for proxy in path {
+                failures.remove(&proxy.addr);
+            }
+        }
+    }
+
+    /// Run `connect` on paths of proxies until one succeeds
+    fn connect<T, F>(&self, connect: F) -> Result<T, Error>
+    where
+        F: Fn(&ProxyConnector) -> Result<T, Error>,
+    {
+        let mut excluded = HashSet::new();
+        let mut last_err = None;
+        while let Some(path) = self.select(&excluded) {
+            let connector = ProxyConnector::path(path.clone(), &self.dialer);
+            match connect(&connector) {
+                Ok(result) => {
+                    self.recovered(&path);
+                    self.used.set(&path[0].addr);
+                    return Ok(result);
+                }
+                Err(err) => {
+                    let proxy = match Self::failed_proxy(&path, &err) {
+                        Some(proxy) => proxy,
+                        // the other proxies would not reach the destination either
+                        None => {
+                            self.recovered(&path);
+                            return Err(err);
+                        }
+                    };
+                    warn!("proxy failed: {}: {}", proxy, err);
+                    if let Ok(mut failures) = self.failures.lock() {
+                        failures.insert(proxy.clone(), Instant::now());
+                    }
+                    excluded.insert(proxy);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("tried at least one proxy"))
+    }
+}
+
+impl Connector for FailoverConnector {
+    type B = ProxyStream;
+    type P = ProxyPktStream;
+
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> Result<(Self::B, SocketAddr, Option<Address>), Error> {
+        self.connect(|connector| connector.connect_byte_stream(addr.clone()))
+    }
+
+    fn connect_pkt_stream(&self, addr: Address) -> Result<(Self::P, SocketAddr), Error> {
+        self.connect(|connector| connector.connect_pkt_stream(addr.clone()))
+    }
+
+    fn assign(&self, client: &SockAddr, load: &HashMap<Address, usize>) -> (Self, UsedProxy) {
+        let available: Vec<&ProxyConfig> = self.hops[0]
+            .iter()
+            .filter(|proxy| self.available(&proxy.addr))
+            .collect();
+        let candidates = if available.is_empty() {
+            self.hops[0].iter().collect()
+        } else {
+            available
+        };
+        let assigned = self.balancer.choose(&candidates, client, load);
+        // counted against the assigned proxy while connecting
+        let connector = Self {
+            used: UsedProxy::new(assigned.clone()),
+            assigned,
+            ..self.clone()
+        };
+        let used = connector.used.clone();
+        (connector, used)
+    }
+}
+
+/// Whether `kind` is a failure of the proxy itself to connect, start TLS, authenticate or handshake
+///
+/// Other IO errors, e.g. of resolving destinations, are not of the proxy.
+/// Nor are replies, including general failures, which may be of reaching the destination.
+fn is_proxy_failure(kind: &ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::ProxyConnectionFailed { .. }
+            | ErrorKind::ConnectTimeout { .. }
+            | ErrorKind::HandshakeTimeout { .. }
+            | ErrorKind::TlsHandshake { .. }
+            | ErrorKind::AuthenticationFailed { .. }
+    )
+}
+
+/// Connector connects to destinations without proxies
+///
+/// Destinations are resolved and connected like proxies.
+#[derive(Debug, Clone)]
+pub struct DirectConnector {
+    dialer: Dialer,
+}
+
+impl DirectConnector {
+    pub fn new(dialer: Dialer) -> Self {
+        Self { dialer }
+    }
+}
+
+impl Connector for DirectConnector {
+    type B = ProxyStream;
+    type P = UdpPktStream;
+
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> Result<(Self::B, SocketAddr, Option<Address>), Error> {
+        let (strm, dst_addr) = self
+            .dialer
+            .connect_direct(&addr)
+            .map_err(|err| unreachable_error(err, &addr))?;
+        strm.set_read_timeout(self.dialer.timeouts.rw)?;
+        strm.set_write_timeout(self.dialer.timeouts.rw)?;
+        Ok((strm.into(), dst_addr, None))
+    }
+
+    fn connect_pkt_stream(&self, addr: Address) -> Result<(Self::P, SocketAddr), Error> {
+        let addrs = self.dialer.resolver.resolve(&addr)?;
+        let dst_addr = addrs
+            .iter()
+            .find(|addr| outbound::reachable(&self.dialer.outbound, addr))
+            .unwrap_or(&addrs[0]);
+        let socket = outbound::bind_udp(&self.dialer.outbound, *dst_addr)?;
+        socket.connect(dst_addr)?;
+        socket.set_write_timeout(self.dialer.timeouts.rw)?;
+        Ok((UdpPktStream::new(MAX_UDP_PAYLOAD, socket), *dst_addr))
+    }
+}
+
+/// Tell that `addr` could not be reached by `err` of connecting to it directly,
+/// so that sessions try the next destination
+fn unreachable_error(err: Error, addr: &Address) -> Error {
+    let reason = match (err.kind(), err.io_error_kind(), err.raw_os_error()) {
+        (ErrorKind::DestinationTimeout { .. }, ..)
+        | (_, Some(io::ErrorKind::TimedOut), _)
+        | (_, _, Some(libc::EHOSTUNREACH)) => "host unreachable",
+        (_, Some(io::ErrorKind::ConnectionRefused), _) => "connection refused",
+        (_, _, Some(libc::ENETUNREACH)) => "network unreachable",
+        _ => return err,
+    };
+    err.context(ErrorKind::DestinationUnreachable {
+        addr: addr.clone(),
+        reason: reason.into(),
+    })
+    .into()
+}
+
+/// Connector routes destinations by rules
+#[derive(Debug, Clone)]
+pub struct RuleConnector {
+    rules: Rules,
+    direct: DirectConnector,
+    proxy: Box<ProxyConnector>,
+    /// identity of the client of the session
+    client: Option<String>,
+    /// first proxy of the session, cleared for routes without proxies
+    used: UsedProxy,
+}
+
+impl RuleConnector {
+    pub fn new(rules: Rules, direct: DirectConnector, proxy: ProxyConnector) -> Self {
+        Self {
+            rules,
+            direct,
+            proxy: Box::new(proxy),
+            client: None,
+            used: UsedProxy::default(),
+        }
+    }
+
+    fn route(&self, addr: &Address) -> Result<Route, Error> {
+        let route = self.rules.route_for(self.client.as_deref(), addr);
+        debug!("route: {}: {}", addr, route);
+        if route != Route::Proxy {
+            self.used.clear();
+        }
+        match route {
+            Route::Reject => Err(ErrorKind::Rejected { addr: addr.clone() }.into()),
+            route => Ok(route),
+        }
+    }
+}
+
+impl Connector for RuleConnector {
+    type B = ProxyStream;
+    type P = ProxyPktStream;
+
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> Result<(Self::B, SocketAddr, Option<Address>), Error> {
+        match self.route(&addr)? {
+            Route::Direct => self.direct.connect_byte_stream(addr),
+            _ => self.proxy.connect_byte_stream(addr),
+        }
+    }
+
+    fn connect_pkt_stream(&self, addr: Address) -> Result<(Self::P, SocketAddr), Error> {
+        match self.route(&addr)? {
+            Route::Direct => {
+                let (strm, dst_addr) = self.direct.connect_pkt_stream(addr)?;
+                Ok((strm.into(), dst_addr))
+            }
+            _ => self.proxy.connect_pkt_stream(addr),
+        }
+    }
+
+    fn assign(&self, client: &SockAddr, load: &HashMap<Address, usize>) -> (Self, UsedProxy) {
+        let (proxy, used) = self.proxy.assign(client, load);
+        let connector = Self {
+            proxy: Box::new(proxy),
+            used: used.clone(),
+            ..self.clone()
+        };
+        (connector, used)
+    }
+
+    fn identify(&self, identity: &str) -> Self {
+        Self {
+            client: Some(identity.to_owned()),
+            ..self.clone()
+        }
+    }
+}
+
+/// Connector selected by the proxies of the configuration
+#[derive(Debug, Clone)]
+pub enum ProxyConnector {
+    Direct(DirectConnector),
+    Socks5(SocksConnector),
+    Http(HttpConnector),
+    Socks4(Socks4Connector),
+    Chain(ChainConnector),
+    Failover(FailoverConnector),
+    Rule(RuleConnector),
+}
+
+impl ProxyConnector {
+    pub fn new(config: &ServerConfig) -> Self {
+        let resolver = Resolver::new(config.dns_cache_ttl, config.ip_preference);
+        let dialer = Dialer::new(Timeouts::new(config), resolver)
+            .with_outbound(config.outbound.clone())
+            .with_tls(config.proxy_tls.clone());
+        let connector = if config.proxies.iter().all(|hop| hop.len() == 1) {
+            let path = config.proxies.iter().map(|hop| hop[0].clone()).collect();
+            ProxyConnector::path(path, &dialer)
+        } else {
+            let connector = FailoverConnector::new(
+                config.proxies.clone(),
+                config.proxy_cooldown,
+                dialer.clone(),
+            );
+            ProxyConnector::Failover(connector.with_strategy(config.balance))
+        };
+        if config.rules.is_empty() {
+            connector
+        } else {
+            let direct = DirectConnector::new(dialer);
+            ProxyConnector::Rule(RuleConnector::new(config.rules.clone(), direct, connector))
+        }
+    }
+
+    /// Skip proxies marked down in `health` if there are alternatives
+    pub fn with_health(self, health: HealthState) -> Self {
+        match self {
+            ProxyConnector::Failover(connector) => {
+                ProxyConnector::Failover(connector.with_health(health))
+            }
+            ProxyConnector::Rule(connector) => ProxyConnector::Rule(RuleConnector {
+                proxy: Box::new(connector.proxy.with_health(health)),
+                ..connector
+            }),
+            connector => connector,
+        }
+    }
+
+    /// Connector going through `path` of proxies. Empty `path` connects directly.
+    fn path(path: Vec<ProxyConfig>, dialer: &Dialer) -> Self {
+        let proxy = match path.as_slice() {
+            [] => return ProxyConnector::Direct(DirectConnector::new(dialer.clone())),
+            [proxy] => proxy.clone(),
+            _ => return ProxyConnector::Chain(ChainConnector::new(path, dialer.clone())),
+        };
+        let tls = dialer.proxy_tls(&proxy).cloned();
+        let (addr, auth, dialer) = (proxy.addr, proxy.auth, dialer.clone());
+        match proxy.protocol {
+            ProxyProtocol::Socks5 | ProxyProtocol::Socks5h => {
+                let mut connector = SocksConnector::new(addr, auth, dialer);
+                if proxy.protocol == ProxyProtocol::Socks5 {
+                    connector = connector.with_local_dns();
+                }
+                if let Some(tls) = tls {
+                    connector = connector.with_tls(tls);
+                }
+                ProxyConnector::Socks5(connector)
+            }
+            ProxyProtocol::Http => ProxyConnector::Http(HttpConnector::new(addr, auth, dialer)),
+            ProxyProtocol::Socks4 | ProxyProtocol::Socks4a => {
+                let userid = auth.map(|auth| auth.username).unwrap_or_default();
+                let remote_dns = proxy.protocol == ProxyProtocol::Socks4a;
+                ProxyConnector::Socks4(Socks4Connector::new(addr, userid, remote_dns, dialer))
+            }
+        }
+    }
+}
+
+impl Connector for ProxyConnector {
+    type B = ProxyStream;
+    type P = ProxyPktStream;
+
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> Result<(Self::B, SocketAddr, Option<Address>), Error> {
+        match self {
+            ProxyConnector::Direct(connector) => connector.connect_byte_stream(addr),
+            ProxyConnector::Socks5(connector) => connector.connect_byte_stream(addr),
+            ProxyConnector::Http(connector) => connector.connect_byte_stream(addr),
+            ProxyConnector::Socks4(connector) => connector.connect_byte_stream(addr),
+            ProxyConnector::Chain(connector) => connector.connect_byte_stream(addr),
+            ProxyConnector::Failover(connector) => connector.connect_byte_stream(addr),
+            ProxyConnector::Rule(connector) => connector.connect_byte_stream(addr),
+        }
+    }
+
+    fn connect_pkt_stream(&self, addr: Address) -> Result<(Self::P, SocketAddr), Error> {
+        fn into<S: Into<ProxyPktStream>>(
+            result: Result<(S, SocketAddr), Error>,
+        ) -> Result<(ProxyPktStream, SocketAddr), Error> {
+            result.map(|(strm, addr)| (strm.into(), addr))
+        }
+        match self {
+            ProxyConnector::Direct(connector) => into(connector.connect_pkt_stream(addr)),
+            ProxyConnector::Socks5(connector) => into(connector.connect_pkt_stream(addr)),
+            ProxyConnector::Http(connector) => into(connector.connect_pkt_stream(addr)),
+            ProxyConnector::Socks4(connector) => into(connector.connect_pkt_stream(addr)),
+            ProxyConnector::Chain(connector) => into(connector.connect_pkt_stream(addr)),
+            ProxyConnector::Failover(connector) => connector.connect_pkt_stream(addr),
+            ProxyConnector::Rule(connector) => connector.connect_pkt_stream(addr),
+        }
+    }
+
+    fn assign(&self, client: &SockAddr, load: &HashMap<Address, usize>) -> (Self, UsedProxy) {
+        match self {
+            ProxyConnector::Failover(connector) => {
+                let (connector, used) = connector.assign(client, load);
+                (ProxyConnector::Failover(connector), used)
+            }
+            ProxyConnector::Rule(connector) => {
+                let (connector, used) = connector.assign(client, load);
+                (ProxyConnector::Rule(connector), used)
+            }
+            _ => (self.clone(), UsedProxy::default()),
+        }
+    }
+
+    fn identify(&self, identity: &str) -> Self {
+        match self {
+            ProxyConnector::Rule(connector) => ProxyConnector::Rule(connector.identify(identity)),
+            _ => self.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::IpPreference;
+    use crate::retry::RetryOn;
+    use crate::test::servers::{spawn_echo_server, MockProxy};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Write `hello` to `strm` and read it back
+    fn echo<S: Read + Write>(strm: &mut S) -> [u8; 5] {
+        strm.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        strm.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    /// every timeout is `secs`
+    fn timeouts(secs: u64) -> Timeouts {
+        let timeout = Some(Duration::from_secs(secs));
+        Timeouts {
+            connect: timeout,
+            handshake: timeout,
+            rw: timeout,
+        }
+    }
+
+    fn dialer(timeouts: Timeouts) -> Dialer {
+        let resolver = Resolver::new(Duration::from_secs(0), IpPreference::System);
+        Dialer::new(timeouts, resolver)
+    }
+
+    #[test]
+    fn authentication_failed() {
+        let proxy = MockProxy::new().reject_auth().spawn();
+        let connector = SocksConnector::new(
+            proxy.addr.into(),
+            Some(Credentials::new("user", "pass")),
+            dialer(Timeouts::default()),
+        );
+        let err = connector
+            .connect_byte_stream("127.0.0.1:80".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::AuthenticationFailed {
+                proxy: proxy.addr.into()
+            }
+        );
+        assert_eq!(proxy.credentials(), ("user".into(), "pass".into()));
+    }
+
+    #[test]
+    fn socks5_over_tls() {
+        use crate::tls::test::{certificate_authority, signed};
+        let (ca, ca_file) = certificate_authority("proxy-ca");
+        let options = TlsClientOptions {
+            ca: Some(ca_file),
+            server_name: Some("proxy.example.com".into()),
+            ..TlsClientOptions::default()
+        };
+        // the proxy resolves the name
+        let dst = Address::Domain("localhost".into(), spawn_echo_server().port());
+        let connect = |options: &TlsClientOptions, proxy_addr: SocketAddr| {
+            let auth = Credentials::new("user", "pass");
+            SocksConnector::new(proxy_addr.into(), Some(auth), dialer(timeouts(3)))
+                .with_tls(options.clone())
+                .connect_byte_stream(dst.clone())
+        };
+
+        let proxy = MockProxy::new()
+            .with_tls(signed(&ca, &["proxy.example.com"]))
+            .spawn();
+        let (mut strm, _, _) = connect(&options, proxy.addr).unwrap();
+        assert_eq!(&echo(&mut strm), b"hello");
+        assert_eq!(proxy.credentials(), ("user".into(), "pass".into()));
+        assert_eq!(proxy.request(), (socks5::CMD_CONNECT, dst.clone()));
+
+        // the proxy certificate is not pinned
+        let options = TlsClientOptions {
+            fingerprints: vec![vec![0; 32]],
+            ..options
+        };
+        let err = connect(&options, proxy.addr).unwrap_err();
+        assert!(err.to_string().contains("not pinned"), "{}", err);
+    }
+
+    #[test]
+    fn udp_associate() {
+        let proxy = MockProxy::new().with_datagrams(2).spawn();
+        let connector = SocksConnector::new(proxy.addr.into(), None, dialer(timeouts(1)));
+        let (strm, addr) = connector
+            .connect_pkt_stream("192.0.2.1:53".parse().unwrap())
+            .unwrap();
+        assert_eq!(addr, proxy.addr);
+        assert_eq!(
+            proxy.request(),
+            (socks5::CMD_UDP_ASSOCIATE, "0.0.0.0:0".parse().unwrap())
+        );
+        strm.send_pkt(b"hello").unwrap();
+        let mut buf = vec![0; 1024];
+        let (size, src_addr) = strm.recv_pkt(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        assert_eq!(src_addr, "192.0.2.1:53".parse().unwrap());
+
+        // the proxy closes the control connection after echoing the second,
+        // which may be dropped as the closure is seen first
+        strm.send_pkt(b"bye").unwrap();
+        let err = loop {
+            match strm.recv_pkt(&mut buf) {
+                Err(err) if err.is_timeout() => continue,
+                Err(err) => break err,
+                Ok((size, _)) if &buf[..size] == b"bye" => continue,
+                Ok((size, _)) => panic!("unexpected packet: {:?}", &buf[..size]),
+            }
+        };
+        assert_eq!(err.kind(), &ErrorKind::disconnected("udp association"));
+    }
+
+    /// HTTP proxy which responds `status` to CONNECT
+    fn spawn_http_proxy(status: &'static str) -> (SocketAddr, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let th = thread::spawn(move || {
+            let (mut strm, _) = listener.accept().unwrap();
+            let mut req = vec![];
+            let mut byte = [0; 1];
+            while !req.ends_with(b"\r\n\r\n") {
+                strm.read_exact(&mut byte).unwrap();
+                req.push(byte[0]);
+            }
+            assert!(req.starts_with(b"CONNECT 192.0.2.1:554 HTTP/1.1\r\n"));
+            write!(strm, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+        });
+        (addr, th)
+    }
+
+    #[test]
+    fn http_connect() {
+        // expected error for the proxy address
+        type Expected = fn(SocketAddr) -> Option<ErrorKind>;
+        let cases: Vec<(&str, Expected)> = vec![
+            ("200 Connection established", |_| None),
+            ("407 Proxy Authentication Required", |proxy| {
+                Some(ErrorKind::AuthenticationFailed {
+                    proxy: proxy.into(),
+                })
+            }),
+            ("403 Forbidden", |proxy| {
+                Some(ErrorKind::HttpConnectFailed {
+                    proxy: proxy.into(),
+                    status: 403,
+                    reason: "Forbidden".into(),
+                })
+            }),
+        ];
+        for (status, expected) in cases {
+            let (proxy_addr, th) = spawn_http_proxy(status);
+            let connector = HttpConnector::new(proxy_addr.into(), None, dialer(timeouts(3)));
+            let result = connector.connect_byte_stream("192.0.2.1:554".parse().unwrap());
+            assert_eq!(
+                result.err().map(|err| err.kind().clone()),
+                expected(proxy_addr)
+            );
+            th.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn socks4_domain_not_supported() {
+        // no connection is made to the proxy
+        let proxy_addr: Address = "127.0.0.1:1".parse().unwrap();
+        let addr = Address::Domain("camera.local".into(), 554);
+        let connector = Socks4Connector::new(
+            proxy_addr.clone(),
+            "".into(),
+            false,
+            dialer(Timeouts::default()),
+        );
+        let err = connector.connect_byte_stream(addr.clone()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::DestinationNotSupported {
+                proxy: proxy_addr,
+                addr
+            }
+        );
+    }
+
+    #[test]
+    fn socks4_next_hop() {
+        let proxy = ProxyConfig::new(
+            ProxyProtocol::Socks4,
+            "127.0.0.1:1".parse::<Address>().unwrap(),
+        );
+        let next = Address::Domain("proxy2".into(), 1080);
+        let resolver = |ips: Vec<&'static str>| {
+            Resolver::new(Duration::from_secs(0), IpPreference::System)
+                .with_lookup(move |_| Ok(ips.iter().map(|ip| ip.parse().unwrap()).collect()))
+        };
+
+        // the first IPv4 address is taken
+        let ips = vec!["2001:db8::1", "192.0.2.1", "192.0.2.2"];
+        assert_eq!(
+            local_target(&resolver(ips), &proxy, next.clone()).unwrap(),
+            "192.0.2.1:1080".parse::<Address>().unwrap()
+        );
+        let err = local_target(&resolver(vec!["2001:db8::1"]), &proxy, next.clone()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::DestinationNotSupported {
+                proxy: proxy.addr.clone(),
+                addr: next
+            }
+        );
+        // destinations are not resolved
+        let dst = Address::Domain("camera.local".into(), 554);
+        assert_eq!(
+            local_destinations(&resolver(vec!["192.0.2.1"]), &proxy, dst.clone()).unwrap(),
+            vec![dst]
+        );
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        // accepts connections and never replies
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let timeouts = Timeouts {
+            handshake: Some(Duration::from_millis(300)),
+            rw: Some(Duration::from_secs(10)),
+            ..Timeouts::default()
+        };
+        let connector = SocksConnector::new(proxy_addr.into(), None, dialer(timeouts));
+        let start = Instant::now();
+        let err = connector
+            .connect_byte_stream("192.0.2.1:554".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::HandshakeTimeout {
+                proxy: proxy_addr.into()
+            }
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[test]
+    fn socks5_replies() {
+        let addr: Address = "192.0.2.1:554".parse().unwrap();
+        let connect = |code| {
+            let proxy_addr = MockProxy::new().with_reply(code).spawn().addr;
+            let connector = SocksConnector::new(proxy_addr.into(), None, dialer(timeouts(3)));
+            (proxy_addr, connector.connect_byte_stream(addr.clone()))
+        };
+
+        let (_, result) = connect(0);
+        let (_, _, bound) = result.unwrap();
+        assert_eq!(bound, Some("10.0.0.1:8080".parse().unwrap()));
+
+        let (proxy_addr, result) = connect(5);
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::ConnectionRefused {
+                proxy: proxy_addr.into(),
+                addr: addr.clone()
+            }
+        );
+        // retried as refused connections
+        assert_eq!(err.io_error_kind(), Some(io::ErrorKind::ConnectionRefused));
+
+        let (proxy_addr, result) = connect(2);
+        assert_eq!(
+            result.unwrap_err().kind(),
+            &ErrorKind::ConnectionNotAllowed {
+                proxy: proxy_addr.into(),
+                addr: addr.clone()
+            }
+        );
+        let (proxy_addr, result) = connect(7);
+        assert_eq!(
+            result.unwrap_err().kind(),
+            &ErrorKind::CommandNotSupported {
+                proxy: proxy_addr.into(),
+                command: socks5::CMD_CONNECT
+            }
+        );
+        let (proxy_addr, result) = connect(0x42);
+        assert_eq!(
+            result.unwrap_err().kind(),
+            &ErrorKind::UnassignedReply {
+                proxy: proxy_addr.into(),
+                code: 0x42
+            }
+        );
+    }
+
+    fn chain(hops: Vec<SocketAddr>, auth: Option<Credentials>) -> ChainConnector {
+        let mut hops: Vec<_> = hops
+            .into_iter()
+            .map(|addr| ProxyConfig::new(ProxyProtocol::Socks5h, addr))
+            .collect();
+        hops.last_mut().unwrap().auth = auth;
+        ChainConnector::new(hops, dialer(timeouts(3)))
+    }
+
+    #[test]
+    fn chain_through_proxies() {
+        let echo_addr = spawn_echo_server();
+        let first = MockProxy::new().spawn();
+        let second = MockProxy::new().spawn();
+        let connector = chain(vec![first.addr, second.addr], None);
+        let (mut strm, proxy_addr, _) = connector.connect_byte_stream(echo_addr.into()).unwrap();
+        assert_eq!(proxy_addr, first.addr);
+        assert_eq!(&echo(&mut strm), b"hello");
+        assert_eq!(first.request(), (socks5::CMD_CONNECT, second.addr.into()));
+        assert_eq!(second.request(), (socks5::CMD_CONNECT, echo_addr.into()));
+    }
+
+    #[test]
+    fn chain_local_dns() {
+        let echo_addr = spawn_echo_server();
+        let first = MockProxy::new().spawn();
+        let second = MockProxy::new().spawn();
+        // nothing listens on the first address
+        let refused = SocketAddr::new("127.0.0.2".parse().unwrap(), echo_addr.port());
+        let resolver = Resolver::new(Duration::from_secs(0), IpPreference::System)
+            .with_lookup(move |_| Ok(vec![refused.ip(), echo_addr.ip()]));
+        let hops = vec![
+            ProxyConfig::new(ProxyProtocol::Socks5h, first.addr),
+            ProxyConfig::new(ProxyProtocol::Socks5, second.addr),
+        ];
+        let connector = ChainConnector::new(hops, Dialer::new(timeouts(3), resolver));
+        let dst = Address::Domain("camera.local".into(), echo_addr.port());
+        let (mut strm, _, _) = connector.connect_byte_stream(dst).unwrap();
+        assert_eq!(&echo(&mut strm), b"hello");
+        assert_eq!(second.request(), (socks5::CMD_CONNECT, refused.into()));
+        assert_eq!(second.request(), (socks5::CMD_CONNECT, echo_addr.into()));
+    }
+
+    #[test]
+    fn chain_hop_error() {
+        let first = MockProxy::new().spawn().addr;
+        let rejecting = MockProxy::new().reject_auth().spawn();
+        let second = rejecting.addr;
+        let connector = chain(vec![first, second], Some(Credentials::new("user", "pass")));
+        let err = connector
+            .connect_byte_stream("127.0.0.1:80".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::ProxyHop {
+                hop: 2,
+                last: true,
+                proxy: second.into(),
+                cause: Box::new(ErrorKind::AuthenticationFailed {
+                    proxy: second.into()
+                }),
+            }
+        );
+        assert_eq!(rejecting.credentials(), ("user".into(), "pass".into()));
+    }
+
+    #[test]
+    fn chain_tls_per_proxy() {
+        use crate::tls::test::{certificate_authority, signed};
+        let (first_ca, first_ca_file) = certificate_authority("first-ca");
+        let (second_ca, second_ca_file) = certificate_authority("second-ca");
+        let echo_addr = spawn_echo_server();
+        let first = MockProxy::new()
+            .with_tls(signed(&first_ca, &["first.example.com"]))
+            .spawn();
+        let second = MockProxy::new()
+            .with_tls(signed(&second_ca, &["second.example.com"]))
+            .spawn();
+        let hop = |addr: SocketAddr| ProxyConfig {
+            tls: true,
+            ..ProxyConfig::new(ProxyProtocol::Socks5h, addr)
+        };
+        // the first proxy is verified by the options of the dialer
+        let dialer = dialer(timeouts(3)).with_tls(TlsClientOptions {
+            ca: Some(first_ca_file),
+            server_name: Some("first.example.com".into()),
+            ..TlsClientOptions::default()
+        });
+        let connector =
+            ChainConnector::new(vec![hop(first.addr), hop(second.addr)], dialer.clone());
+        let err = connector.connect_byte_stream(echo_addr.into()).unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::ProxyHop { hop: 2, .. }),
+            "{}",
+            err
+        );
+
+        // the second one by its own
+        let second_hop = ProxyConfig {
+            tls_options: Some(TlsClientOptions {
+                ca: Some(second_ca_file),
+                server_name: Some("second.example.com".into()),
+                ..TlsClientOptions::default()
+            }),
+            ..hop(second.addr)
+        };
+        let connector = ChainConnector::new(vec![hop(first.addr), second_hop], dialer);
+        let (mut strm, _, _) = connector.connect_byte_stream(echo_addr.into()).unwrap();
+        assert_eq!(&echo(&mut strm), b"hello");
+        assert_eq!(second.request(), (socks5::CMD_CONNECT, echo_addr.into()));
+    }
+
+    #[test]
+    fn failover() {
+        let echo_addr = spawn_echo_server();
+        // nothing listens on the port of the dropped listener
+        let down = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let up = MockProxy::new().spawn().addr;
+        let hop = vec![
+            ProxyConfig::new(ProxyProtocol::Socks5h, down),
+            ProxyConfig::new(ProxyProtocol::Socks5h, up),
+        ];
+        let connector =
+            FailoverConnector::new(vec![hop], Duration::from_secs(60), dialer(timeouts(3)));
+
+        let (_strm, proxy_addr, _) = connector.connect_byte_stream(echo_addr.into()).unwrap();
+        assert_eq!(proxy_addr, up);
+        assert!(connector.cooling_down(&down.into()));
+        // the failed proxy is skipped while cooling down
+        let path = connector.select(&HashSet::new()).unwrap();
+        assert_eq!(path[0].addr, up.into());
+        // and tried again when all the alternatives failed
+        let path = connector
+            .select(&vec![up.into()].into_iter().collect())
+            .unwrap();
+        assert_eq!(path[0].addr, down.into());
+    }
+
+    #[test]
+    fn failover_on_proxy_failures_only() {
+        let unreachable = MockProxy::new().with_reply(4).spawn();
+        let other = MockProxy::new().spawn();
+        let hop = vec![
+            ProxyConfig::new(ProxyProtocol::Socks5h, unreachable.addr),
+            ProxyConfig::new(ProxyProtocol::Socks5h, other.addr),
+        ];
+        let connector =
+            FailoverConnector::new(vec![hop], Duration::from_secs(60), dialer(timeouts(3)));
+        let dst: Address = "192.0.2.1:554".parse().unwrap();
+
+        for _ in 0..2 {
+            let err = connector.connect_byte_stream(dst.clone()).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::HostUnreachable {
+                    proxy: unreachable.addr.into(),
+                    addr: dst.clone()
+                }
+            );
+            // the proxy stays available for other sessions
+            assert!(!connector.cooling_down(&unreachable.addr.into()));
+            assert_eq!(unreachable.request(), (socks5::CMD_CONNECT, dst.clone()));
+        }
+        let path = connector.select(&HashSet::new()).unwrap();
+        assert_eq!(path[0].addr, unreachable.addr.into());
+    }
+
+    #[test]
+    fn failover_general_failure() {
+        let echo_addr = spawn_echo_server();
+        let dst: Address = "192.0.2.1:554".parse().unwrap();
+
+        // the last hop failed reaching the destination
+        let failing = MockProxy::new().with_reply(1).spawn();
+        let other = MockProxy::new().spawn();
+        let hop = vec![
+            ProxyConfig::new(ProxyProtocol::Socks5h, failing.addr),
+            ProxyConfig::new(ProxyProtocol::Socks5h, other.addr),
+        ];
+        let connector =
+            FailoverConnector::new(vec![hop], Duration::from_secs(60), dialer(timeouts(3)));
+        let err = connector.connect_byte_stream(dst.clone()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::ProxyGeneralFailure {
+                proxy: failing.addr.into()
+            }
+        );
+        assert_eq!(failing.request(), (socks5::CMD_CONNECT, dst));
+        assert!(!connector.cooling_down(&failing.addr.into()));
+
+        // an intermediate hop failed reaching the next hop
+        let first = MockProxy::new().with_reply(1).spawn();
+        let spare = MockProxy::new().spawn();
+        let last = MockProxy::new().spawn();
+        let hops = vec![
+            vec![
+                ProxyConfig::new(ProxyProtocol::Socks5h, first.addr),
+                ProxyConfig::new(ProxyProtocol::Socks5h, spare.addr),
+            ],
+            vec![ProxyConfig::new(ProxyProtocol::Socks5h, last.addr)],
+        ];
+        let connector = FailoverConnector::new(hops, Duration::from_secs(60), dialer(timeouts(3)));
+        let (mut strm, proxy_addr, _) = connector.connect_byte_stream(echo_addr.into()).unwrap();
+        assert_eq!(proxy_addr, spare.addr);
+        assert_eq!(&echo(&mut strm), b"hello");
+        assert!(connector.cooling_down(&first.addr.into()));
+        assert!(!connector.cooling_down(&last.addr.into()));
+    }
+
+    /// SOCKS4 proxy which replies `code` to a request
+    fn spawn_socks4_proxy(code: u8) -> (SocketAddr, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let th = thread::spawn(move || {
+            let (mut strm, _) = listener.accept().unwrap();
+            // VN CD DSTPORT DSTIP USERID NULL
+            let mut req = [0; 8];
+            strm.read_exact(&mut req).unwrap();
+            let mut byte = [1; 1];
+            while byte[0] != 0 {
+                strm.read_exact(&mut byte).unwrap();
+            }
+            strm.write_all(&[0, code, 0, 0, 0, 0, 0, 0]).unwrap();
+        });
+        (addr, th)
+    }
+
+    #[test]
+    fn failover_destination_failures() {
+        let other = MockProxy::new().spawn();
+        let dst: Address = "192.0.2.1:554".parse().unwrap();
+        let failover = |proxy: ProxyConfig, dialer: Dialer| {
+            let hop = vec![proxy, ProxyConfig::new(ProxyProtocol::Socks5h, other.addr)];
+            FailoverConnector::new(vec![hop], Duration::from_secs(60), dialer)
+        };
+
+        // the socks4 proxy rejects the destination
+        let (rejecting, th) = spawn_socks4_proxy(91);
+        let connector = failover(
+            ProxyConfig::new(ProxyProtocol::Socks4, rejecting),
+            dialer(timeouts(3)),
+        );
+        let err = connector.connect_byte_stream(dst.clone()).unwrap_err();
+        th.join().unwrap();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::ConnectionRefused {
+                proxy: rejecting.into(),
+                addr: dst
+            }
+        );
+        assert!(!connector.cooling_down(&rejecting.into()));
+        // nor retried, as the proxy may reject it by rules
+        assert_eq!(RetryOn::of(&err), None);
+
+        // the name of the destination is not resolved for the socks5 proxy
+        let proxy = MockProxy::new().spawn().addr;
+        let resolver = Resolver::new(Duration::from_secs(0), IpPreference::System)
+            .with_lookup(|_| Err(io::Error::new(io::ErrorKind::Other, "no such name")));
+        let connector = failover(
+            ProxyConfig::new(ProxyProtocol::Socks5, proxy),
+            Dialer::new(timeouts(3), resolver),
+        );
+        let err = connector
+            .connect_byte_stream(Address::Domain("camera.local".into(), 554))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::Io);
+        assert!(!connector.cooling_down(&proxy.into()));
+    }
+
+    #[test]
+    fn failover_last_hop_unreachable() {
+        let first = MockProxy::new().spawn();
+        let spare = MockProxy::new().spawn();
+        let last = MockProxy::new().with_reply(4).spawn();
+        let hops = vec![
+            vec![
+                ProxyConfig::new(ProxyProtocol::Socks5h, first.addr),
+                ProxyConfig::new(ProxyProtocol::Socks5h, spare.addr),
+            ],
+            vec![ProxyConfig::new(ProxyProtocol::Socks5h, last.addr)],
+        ];
+        let connector = FailoverConnector::new(hops, Duration::from_secs(60), dialer(timeouts(3)));
+        let dst: Address = "192.0.2.1:554".parse().unwrap();
+
+        let err = connector.connect_byte_stream(dst.clone()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::ProxyHop {
+                hop: 2,
+                last: true,
+                proxy: last.addr.into(),
+                cause: Box::new(ErrorKind::HostUnreachable {
+                    proxy: last.addr.into(),
+                    addr: dst.clone()
+                }),
+            }
+        );
+        assert_eq!(first.request(), (socks5::CMD_CONNECT, last.addr.into()));
+        assert_eq!(last.request(), (socks5::CMD_CONNECT, dst));
+        // the reply is about the destination, not the proxies
+        assert!(!connector.cooling_down(&first.addr.into()));
+        assert!(!connector.cooling_down(&last.addr.into()));
+        let path = connector.select(&HashSet::new()).unwrap();
+        assert_eq!(path[0].addr, first.addr.into());
+    }
+
+    #[test]
+    fn used_proxy() {
+        use crate::rules::{Pattern, Rule};
+
+        let echo_addr = spawn_echo_server();
+        // nothing listens on the port of the dropped listener
+        let down = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let up = MockProxy::new().spawn().addr;
+        let config = ServerConfig {
+            proxies: vec![vec![
+                ProxyConfig::new(ProxyProtocol::Socks5h, down),
+                ProxyConfig::new(ProxyProtocol::Socks5h, up),
+            ]],
+            rules: Rules {
+                rules: vec![Rule::new(
+                    Pattern::parse("192.0.2.0/24").unwrap(),
+                    Route::Reject,
+                )],
+                default: Route::Proxy,
+            },
+            ..ServerConfig::default()
+        };
+        let connector = ProxyConnector::new(&config);
+        let client: SockAddr = "127.0.0.1:50000".parse::<SocketAddr>().unwrap().into();
+
+        // the proxy failed over to, not the assigned one
+        let (session, used) = connector.assign(&client, &HashMap::new());
+        assert_eq!(used.get(), Some(down.into()));
+        session.connect_byte_stream(echo_addr.into()).unwrap();
+        assert_eq!(used.get(), Some(up.into()));
+
+        // rejected sessions go through no proxy
+        let (session, used) = connector.assign(&client, &HashMap::new());
+        session
+            .connect_byte_stream("192.0.2.1:554".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(used.get(), None);
+    }
+
+    #[test]
+    fn interleave_families() {
+        let addrs: Vec<SocketAddr> = [
+            "[::1]:1",
+            "[::1]:2",
+            "[::1]:3",
+            "127.0.0.1:4",
+            "127.0.0.1:5",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        let ports: Vec<_> = interleave(addrs).iter().map(SocketAddr::port).collect();
+        assert_eq!(ports, vec![1, 4, 2, 5, 3]);
+    }
+
+    #[test]
+    fn proxy_name() {
+        let echo_addr = spawn_echo_server();
+        // localhost may also resolve to ::1, where nothing listens
+        let proxy_addr = MockProxy::new().spawn().addr;
+        let proxy = Address::Domain("localhost".into(), proxy_addr.port());
+        let connector = SocksConnector::new(proxy, None, dialer(timeouts(3)));
+        let (mut strm, addr, _) = connector.connect_byte_stream(echo_addr.into()).unwrap();
+        assert_eq!(addr, proxy_addr);
+        assert_eq!(&echo(&mut strm), b"hello");
+    }
+
+    #[test]
+    fn direct_datagrams() {
+        let echo = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 64];
+            let (size, client) = echo.recv_from(&mut buf).unwrap();
+            echo.send_to(&buf[..size], client).unwrap();
+        });
+
+        let connector = DirectConnector::new(dialer(timeouts(3)));
+        let (strm, dst_addr) = connector.connect_pkt_stream(addr.into()).unwrap();
+        assert_eq!(dst_addr, addr);
+        strm.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        strm.send_pkt(b"hello").unwrap();
+        let mut buf = [0; MAX_UDP_PAYLOAD];
+        let (size, from) = strm.recv_pkt(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        assert_eq!(from, addr.into());
+
+        let err = strm.send_pkt(&vec![0; MAX_UDP_PAYLOAD + 1]).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::PacketSizeLimitExceeded {
+                size: MAX_UDP_PAYLOAD + 1,
+                limit: MAX_UDP_PAYLOAD
+            }
+        );
+    }
+
+    #[test]
+    fn direct_unreachable() {
+        // nothing listens on the port of the dropped listener
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let connector = DirectConnector::new(dialer(timeouts(3)));
+        let err = connector.connect_byte_stream(addr.into()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::DestinationUnreachable {
+                addr: addr.into(),
+                reason: "connection refused".into()
+            }
+        );
+        assert_eq!(err.io_error_kind(), Some(io::ErrorKind::ConnectionRefused));
+    }
+
+    #[test]
+    fn direct_timeout() {
+        use socket2::{Domain, Socket, Type};
+
+        // connections beyond the backlog are not accepted by the kernel
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        listener.bind(&addr.into()).unwrap();
+        listener.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+        let _queued = TcpStream::connect(addr).unwrap();
+
+        let timeouts = Timeouts {
+            connect: Some(Duration::from_millis(300)),
+            ..timeouts(3)
+        };
+        let connector = DirectConnector::new(dialer(timeouts));
+        let err = connector.connect_byte_stream(addr.into()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::DestinationUnreachable {
+                addr: addr.into(),
+                reason: "host unreachable".into()
+            }
+        );
+        let chain: &dyn Fail = &err;
+        let causes: Vec<_> = chain.iter_chain().map(ToString::to_string).collect();
+        assert!(
+            causes.iter().all(|cause| !cause.contains("proxy")),
+            "{:?}",
+            causes
+        );
+        assert_eq!(RetryOn::of(&err), Some(RetryOn::Timeout));
+    }
+
+    #[test]
+    fn route_by_rules() {
+        use crate::rules::{Pattern, Rule};
+
+        let echo_addr = spawn_echo_server();
+        // nothing listens on the proxy
+        let config = ServerConfig {
+            rules: Rules {
+                rules: vec![Rule::new(
+                    Pattern::parse("127.0.0.0/8").unwrap(),
+                    Route::Direct,
+                )],
+                default: Route::Reject,
+            },
+            ..ServerConfig::default()
+        };
+        let connector = ProxyConnector::new(&config);
+
+        let (mut strm, addr, _) = connector.connect_byte_stream(echo_addr.into()).unwrap();
+        assert_eq!(addr, echo_addr);
+        assert_eq!(&echo(&mut strm), b"hello");
+
+        let dst: Address = "192.0.2.1:554".parse().unwrap();
+        let err = connector.connect_byte_stream(dst.clone()).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::Rejected { addr: dst });
     }
 }
@@ -0,0 +1,162 @@
+//! Alternative destinations of a pipeline
+//!
+//! A session tries the destinations in its order, and moves on to the next one
+//! when the proxy cannot reach one.
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::model::{Address, Error, ErrorKind};
+
+/// Order of destinations for a new session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestinationOrder {
+    /// in the given order
+    Failover,
+    /// starting from the next destination of the previous session
+    RoundRobin,
+}
+
+impl FromStr for DestinationOrder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(DestinationOrder::Failover),
+            "round-robin" => Ok(DestinationOrder::RoundRobin),
+            _ => Err(format!(
+                "unknown destination order: {} (failover or round-robin)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DestinationOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DestinationOrder::Failover => write!(f, "failover"),
+            DestinationOrder::RoundRobin => write!(f, "round-robin"),
+        }
+    }
+}
+
+/// Orders destinations of sessions. Clones share the round-robin counter.
+#[derive(Debug, Clone)]
+pub struct Destinations {
+    addrs: Vec<Address>,
+    order: DestinationOrder,
+    next: Arc<AtomicUsize>,
+}
+
+impl Destinations {
+    /// `addrs` must not be empty
+    pub fn new(addrs: Vec<Address>, order: DestinationOrder) -> Self {
+        assert!(!addrs.is_empty(), "no destinations");
+        Self {
+            addrs,
+            order,
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Destinations for a new session in the order to try
+    pub fn next(&self) -> Vec<Address> {
+        let mut addrs = self.addrs.clone();
+        if self.order == DestinationOrder::RoundRobin {
+            let start = self.next.fetch_add(1, Ordering::Relaxed) % addrs.len();
+            addrs.rotate_left(start);
+        }
+        addrs
+    }
+}
+
+/// Whether the destination could not be reached, so that the next one is worth trying
+pub fn is_unreachable(err: &Error) -> bool {
+    fn unreachable(kind: &ErrorKind) -> bool {
+        match kind {
+            ErrorKind::HostUnreachable { .. }
+            | ErrorKind::NetworkUnreachable { .. }
+            | ErrorKind::ConnectionRefused { .. }
+            | ErrorKind::DestinationUnreachable { .. } => true,
+            // earlier hops failed to reach the next proxies, not the destination
+            ErrorKind::ProxyHop { last, cause, .. } => *last && unreachable(cause),
+            _ => false,
+        }
+    }
+    unreachable(err.kind())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn order() {
+        let addrs: Vec<Address> = vec![
+            "192.168.0.1:554".parse().unwrap(),
+            "192.168.0.2:554".parse().unwrap(),
+            "192.168.0.3:554".parse().unwrap(),
+        ];
+        let failover = Destinations::new(addrs.clone(), DestinationOrder::Failover);
+        assert_eq!(failover.next(), addrs);
+        assert_eq!(failover.next(), addrs);
+
+        let round_robin = Destinations::new(addrs.clone(), DestinationOrder::RoundRobin);
+        let firsts: Vec<_> = (0..4)
+            .map(|_| round_robin.clone().next()[0].clone())
+            .collect();
+        assert_eq!(
+            firsts,
+            vec![
+                addrs[0].clone(),
+                addrs[1].clone(),
+                addrs[2].clone(),
+                addrs[0].clone()
+            ]
+        );
+        assert_eq!(
+            round_robin.next(),
+            vec![addrs[1].clone(), addrs[2].clone(), addrs[0].clone()]
+        );
+    }
+
+    #[test]
+    fn unreachable() {
+        let proxy: Address = "127.0.0.1:1080".parse().unwrap();
+        let addr: Address = "192.168.0.1:554".parse().unwrap();
+        let refused = ErrorKind::ConnectionRefused {
+            proxy: proxy.clone(),
+            addr: addr.clone(),
+        };
+        assert!(is_unreachable(&refused.clone().into()));
+        assert!(is_unreachable(
+            &ErrorKind::ProxyHop {
+                hop: 2,
+                last: true,
+                proxy: proxy.clone(),
+                cause: Box::new(refused.clone()),
+            }
+            .into()
+        ));
+        assert!(!is_unreachable(
+            &ErrorKind::ProxyHop {
+                hop: 1,
+                last: false,
+                proxy: proxy.clone(),
+                cause: Box::new(refused),
+            }
+            .into()
+        ));
+        assert!(is_unreachable(
+            &ErrorKind::DestinationUnreachable {
+                addr: addr.clone(),
+                reason: "connection refused".into(),
+            }
+            .into()
+        ));
+        assert!(!is_unreachable(
+            &ErrorKind::ConnectionNotAllowed { proxy, addr }.into()
+        ));
+    }
+}
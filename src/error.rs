@@ -75,7 +75,30 @@ impl From<model::Error> for Error {
             | K::Disconnected { .. }
             | K::PacketSizeLimitExceeded { .. }
             | K::AddressAlreadInUse { .. }
-            | K::AddressNotAvailable { .. } => err.context(ErrorKind::Io),
+            | K::AddressNotAvailable { .. }
+            | K::AddressNotSupported { .. }
+            | K::AuthenticationFailed { .. }
+            | K::NotSupported { .. }
+            | K::DestinationNotSupported { .. }
+            | K::HttpConnectFailed { .. }
+            | K::ProxyConnectionFailed { .. }
+            | K::ConnectTimeout { .. }
+            | K::HandshakeTimeout { .. }
+            | K::ProxyGeneralFailure { .. }
+            | K::ConnectionNotAllowed { .. }
+            | K::NetworkUnreachable { .. }
+            | K::HostUnreachable { .. }
+            | K::ConnectionRefused { .. }
+            | K::DestinationTimeout { .. }
+            | K::DestinationUnreachable { .. }
+            | K::TtlExpired { .. }
+            | K::CommandNotSupported { .. }
+            | K::AddressTypeNotSupported { .. }
+            | K::UnassignedReply { .. }
+            | K::Rejected { .. }
+            | K::InvalidCertificate { .. }
+            | K::TlsHandshake { .. }
+            | K::ProxyHop { .. } => err.context(ErrorKind::Io),
         };
         Error { inner: ctx }
     }
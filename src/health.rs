@@ -0,0 +1,274 @@
+//! Health checks of proxies in background
+//!
+//! Proxies of the first hop are connected periodically, in TLS with TLS proxies,
+//! and asked for a tunnel to the probe destination if it is given.
+//! A proxy with a domain name is checked on one of its resolved addresses.
+//! A proxy changes its state after consecutive results against the current one.
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::config::{HealthCheckOptions, ProxyConfig, ServerConfig};
+use crate::connector::{handshake, local_target, Dialer, Timeouts, UsedProxy};
+use crate::model::{Address, Error};
+use crate::resolver::Resolver;
+use crate::thread::spawn_thread;
+
+/// Health of a proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyHealth {
+    pub up: bool,
+    /// time to connect and handshake in the last successful check
+    pub rtt: Option<Duration>,
+    pub last_checked: Option<Instant>,
+    /// error of the last check if it failed
+    pub last_error: Option<String>,
+    /// consecutive results against the current state
+    successes: u32,
+    failures: u32,
+}
+
+impl Default for ProxyHealth {
+    /// proxies are up until they fail
+    fn default() -> Self {
+        Self {
+            up: true,
+            rtt: None,
+            last_checked: None,
+            last_error: None,
+            successes: 0,
+            failures: 0,
+        }
+    }
+}
+
+/// Health of proxies shared by the checker and connectors
+#[derive(Debug, Clone, Default)]
+pub struct HealthState {
+    proxies: Arc<Mutex<HashMap<Address, ProxyHealth>>>,
+}
+
+impl HealthState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Health of `proxy`. `None` before the first check.
+    pub fn get(&self, proxy: &Address) -> Option<ProxyHealth> {
+        self.proxies.lock().ok()?.get(proxy).cloned()
+    }
+
+    /// Health of all the checked proxies
+    pub fn snapshot(&self) -> HashMap<Address, ProxyHealth> {
+        match self.proxies.lock() {
+            Ok(proxies) => proxies.clone(),
+            Err(_) => HashMap::new(),
+        }
+    }
+
+    /// Whether `proxy` is marked down. Unchecked proxies are up.
+    pub fn is_down(&self, proxy: &Address) -> bool {
+        matches!(self.get(proxy), Some(health) if !health.up)
+    }
+
+    fn record(&self, proxy: &Address, result: Result<Duration, Error>, rise: u32, fall: u32) {
+        let mut proxies = match self.proxies.lock() {
+            Ok(proxies) => proxies,
+            Err(_) => return,
+        };
+        let health = proxies.entry(proxy.clone()).or_default();
+        health.last_checked = Some(Instant::now());
+        match result {
+            Ok(rtt) => {
+                health.rtt = Some(rtt);
+                health.last_error = None;
+                health.failures = 0;
+                health.successes += 1;
+                if !health.up && health.successes >= rise {
+                    info!("proxy is up: {}: rtt = {:?}", proxy, rtt);
+                    health.up = true;
+                }
+            }
+            Err(err) => {
+                health.last_error = Some(err.to_string());
+                health.successes = 0;
+                health.failures += 1;
+                if health.up && health.failures >= fall {
+                    warn!("proxy is down: {}: {}", proxy, err);
+                    health.up = false;
+                }
+            }
+        }
+    }
+}
+
+/// Live sessions by the first proxies they are assigned to or connected through
+///
+/// Servers count sessions by `proxies` of them to assign proxies to new sessions.
+pub fn load<'a>(proxies: impl IntoIterator<Item = &'a UsedProxy>) -> HashMap<Address, usize> {
+    let mut load = HashMap::new();
+    for proxy in proxies.into_iter().filter_map(UsedProxy::get) {
+        *load.entry(proxy).or_insert(0) += 1;
+    }
+    load
+}
+
+/// Checks proxies of the first hop
+#[derive(Debug, Clone)]
+pub struct HealthChecker {
+    proxies: Vec<ProxyConfig>,
+    options: HealthCheckOptions,
+    /// every timeout is the timeout of checks
+    dialer: Dialer,
+    state: HealthState,
+}
+
+impl HealthChecker {
+    /// `None` if health checks are disabled
+    pub fn new(config: &ServerConfig, state: HealthState) -> Option<Self> {
+        let options = config.health_check.clone()?;
+        let timeout = Some(options.timeout);
+        let timeouts = Timeouts {
+            connect: timeout,
+            handshake: timeout,
+            rw: timeout,
+        };
+        let resolver = Resolver::new(config.dns_cache_ttl, config.ip_preference);
+        Some(Self {
+            proxies: config.proxies.first().cloned().unwrap_or_default(),
+            options,
+            dialer: Dialer::new(timeouts, resolver)
+                .with_outbound(config.outbound.clone())
+                .with_tls(config.proxy_tls.clone()),
+            state,
+        })
+    }
+
+    /// Spawn a checker updating `state` if health checks are enabled by `config`
+    pub fn start(
+        config: &ServerConfig,
+        state: HealthState,
+    ) -> Result<Option<HealthCheckHandle>, Error> {
+        match Self::new(config, state) {
+            Some(checker) => Ok(Some(checker.spawn()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Connect to `proxy` and request the probe. Returns the round trip time.
+    pub fn check(&self, proxy: &ProxyConfig) -> Result<Duration, Error> {
+        let start = Instant::now();
+        let (mut strm, _) = self
+            .dialer
+            .connect_proxy(&proxy.addr, self.dialer.proxy_tls(proxy))?;
+        if let Some(probe) = &self.options.probe {
+            let probe = local_target(self.dialer.resolver(), proxy, probe.clone())?;
+            self.dialer.handshake(&proxy.addr, &mut strm, |strm| {
+                handshake(strm, proxy, &probe)
+            })?;
+        }
+        Ok(start.elapsed())
+    }
+
+    pub fn check_all(&self) {
+        for proxy in &self.proxies {
+            let result = self.check(proxy);
+            match &result {
+                Ok(rtt) => trace!("health check: {}: rtt = {:?}", proxy.addr, rtt),
+                Err(err) => debug!("health check failed: {}: {}", proxy.addr, err),
+            }
+            let (rise, fall) = (self.options.rise, self.options.fall);
+            self.state.record(&proxy.addr, result, rise, fall);
+        }
+    }
+
+    /// Check proxies every interval in a thread until the handle is stopped
+    pub fn spawn(self) -> Result<HealthCheckHandle, Error> {
+        let (tx, rx) = mpsc::channel::<()>();
+        let handle = spawn_thread("health check", move || loop {
+            self.check_all();
+            match rx.recv_timeout(self.options.interval) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                _ => break,
+            }
+        })?;
+        Ok(HealthCheckHandle { tx, handle })
+    }
+}
+
+/// Running health checker
+#[derive(Debug)]
+pub struct HealthCheckHandle {
+    /// dropped to stop the checker
+    tx: mpsc::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl HealthCheckHandle {
+    pub fn stop(self) {
+        drop(self.tx);
+        self.handle.join().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::ErrorKind;
+
+    #[test]
+    fn hysteresis() {
+        let state = HealthState::new();
+        let proxy = &"127.0.0.1:1080".parse().unwrap();
+        let fail = || Err(ErrorKind::Io.into());
+        let ok = || Ok(Duration::from_millis(10));
+
+        assert!(!state.is_down(proxy));
+        state.record(proxy, fail(), 2, 2);
+        assert!(!state.is_down(proxy));
+        state.record(proxy, fail(), 2, 2);
+        assert!(state.is_down(proxy));
+
+        state.record(proxy, ok(), 2, 2);
+        assert!(state.is_down(proxy));
+        state.record(proxy, fail(), 2, 2);
+        state.record(proxy, ok(), 2, 2);
+        assert!(state.is_down(proxy));
+        state.record(proxy, ok(), 2, 2);
+        assert!(!state.is_down(proxy));
+        assert_eq!(
+            state.get(proxy).unwrap().rtt,
+            Some(Duration::from_millis(10))
+        );
+    }
+
+    #[test]
+    fn check_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let up = listener.local_addr().unwrap();
+        let down = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = ServerConfig::new(
+            "127.0.0.1:1081".parse().unwrap(),
+            up,
+            "127.0.0.1:80".parse().unwrap(),
+        );
+        config.proxies[0].push(ProxyConfig::new(crate::model::ProxyProtocol::Socks5h, down));
+        config.health_check = Some(HealthCheckOptions {
+            fall: 1,
+            ..HealthCheckOptions::default()
+        });
+        let state = HealthState::new();
+        let checker = HealthChecker::new(&config, state.clone()).unwrap();
+        checker.check_all();
+        assert!(!state.is_down(&up.into()));
+        assert!(state.get(&up.into()).unwrap().rtt.is_some());
+        assert!(state.is_down(&down.into()));
+    }
+}
@@ -0,0 +1,127 @@
+//! HTTP CONNECT tunnel (RFC 7231 section 4.3.6)
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+
+use crate::model::{Address, Credentials};
+
+/// limit of the response header size
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Status line of the response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl Status {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// `host:port` with IPv6 addresses in brackets
+fn authority(addr: &Address) -> String {
+    match addr {
+        Address::IpAddr(IpAddr::V6(ip), port) => format!("[{}]:{}", ip, port),
+        addr => addr.to_string(),
+    }
+}
+
+/// Request a tunnel to `addr` and returns the status of the response
+///
+/// Bytes after the response header are left in `strm`.
+pub fn connect<S: Read + Write>(
+    strm: &mut S,
+    addr: &Address,
+    auth: Option<&Credentials>,
+) -> io::Result<Status> {
+    let authority = authority(addr);
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some(auth) = auth {
+        let token = base64::encode(format!("{}:{}", auth.username, auth.password));
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    req.push_str("\r\n");
+    strm.write_all(req.as_bytes())?;
+
+    let header = read_header(strm)?;
+    parse_status(&header)
+}
+
+/// Read until the empty line without consuming bytes after it
+fn read_header<R: Read>(rd: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    let mut byte = [0; 1];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HEADER_SIZE {
+            return Err(invalid_data("too large response header"));
+        }
+        rd.read_exact(&mut byte)?;
+        header.push(byte[0]);
+    }
+    Ok(header)
+}
+
+/// Parse `HTTP/1.1 200 Connection established`
+fn parse_status(header: &[u8]) -> io::Result<Status> {
+    let header = String::from_utf8_lossy(header);
+    let line = header.lines().next().unwrap_or("");
+    let mut fields = line.splitn(3, ' ');
+    match (fields.next(), fields.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/1.") => {
+            let code = code
+                .parse()
+                .map_err(|_| invalid_data("invalid status code"))?;
+            let reason = fields.next().unwrap_or("").to_owned();
+            Ok(Status { code, reason })
+        }
+        _ => Err(invalid_data("invalid status line")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::byte_stream::test::BufferStream;
+    use std::borrow::Cow;
+
+    #[test]
+    fn connect_with_auth() {
+        let rd = b"HTTP/1.1 200 Connection established\r\nVia: proxy\r\n\r\ntunnel".to_vec();
+        let mut strm = BufferStream::with_buffer(Cow::from(rd), Cow::from(vec![]));
+        let status = connect(
+            &mut strm,
+            &"[::1]:554".parse().unwrap(),
+            Some(&Credentials::new("user", "pass")),
+        )
+        .unwrap();
+        assert_eq!(
+            status,
+            Status {
+                code: 200,
+                reason: "Connection established".into()
+            }
+        );
+        assert_eq!(
+            strm.wr_buff().get_ref().as_slice(),
+            &b"CONNECT [::1]:554 HTTP/1.1\r\nHost: [::1]:554\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"[..]
+        );
+        // the tunnel starts right after the header
+        let mut rest = vec![];
+        strm.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"tunnel");
+    }
+
+    #[test]
+    fn invalid_response() {
+        let rd = b"SSH-2.0-OpenSSH\r\n\r\n".to_vec();
+        let mut strm = BufferStream::with_buffer(Cow::from(rd), Cow::from(vec![]));
+        let err = connect(&mut strm, &"127.0.0.1:80".parse().unwrap(), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
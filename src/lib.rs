@@ -1,19 +1,34 @@
 pub mod acceptor;
+pub mod balancer;
 mod byte_stream;
 pub mod config;
 pub mod connector;
+pub mod destination;
 pub mod error;
+pub mod health;
+mod http;
 pub mod model;
+mod outbound;
 mod pkt_stream;
+mod proxy_protocol;
 mod relay;
+pub mod resolver;
+pub mod retry;
+pub mod rules;
 pub mod server;
 pub mod server_command;
 mod session;
+mod socks4;
+mod socks5;
 mod tcp_listener_ext;
 mod test;
 mod thread;
+pub mod tls;
+pub mod udp_server;
+mod unix_listener_ext;
 
 pub use config::*;
 pub use model::model::*;
 pub use server::*;
 pub use server_command::*;
+pub use udp_server::*;
@@ -3,162 +3,326 @@
 #[macro_use]
 extern crate eyre;
 
-use ::clap::App;
-use color_eyre::Section;
-use eyre::{Result, WrapErr};
+mod config_file;
+mod pipeline;
+
+use ::clap::{App, ArgMatches};
+use eyre::Result;
 use log::*;
 use std::io;
-use std::net::SocketAddr;
-use tcp2socks::model::model::Address;
-use url::Url;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tcp2socks::server::Server;
+use tcp2socks::udp_server::{UdpServer, UdpServerCommand};
+use tcp2socks::{
+    HealthCheckOptions, L4Protocol, ProxyProtocolOptions, ServerCommand, ServerConfig,
+    TlsCertificate, TlsClientOptions,
+};
+
+use config_file::ConfigFile;
+use pipeline::*;
 
-fn parse_url(s: &str) -> Result<Url> {
-    Url::parse(s).wrap_err_with(|| eyre!("invalid URL: {}", s))
+fn set_handler(signals: &[i32], handler: impl Fn(i32) + Send + 'static) -> io::Result<()> {
+    use signal_hook::*;
+    let signals = iterator::Signals::new(signals)?;
+    std::thread::spawn(move || signals.forever().for_each(handler));
+    Ok(())
 }
 
-fn validate_socket_addr_contained_unique(url: &Url) -> Result<()> {
-    url.socket_addrs(|| None)
-        .wrap_err_with(|| {
-            format!(
-                "endpoint url must be the form of `protocol://host:port`: {}",
-                url
-            )
-        })
-        .and_then(|xs| {
-            if xs.len() == 1 {
-                Ok(())
-            } else {
-                Err(eyre!("socket address must be unique."))
-            }
-        })
+/// Options of TLS to destinations
+const DESTINATION_TLS_ARGS: &[&str] = &[
+    "dst-tls-ca",
+    "dst-tls-server-name",
+    "dst-tls-alpn",
+    "dst-tls-cert",
+];
+
+fn destination_tls(matches: &ArgMatches) -> TlsClientOptions {
+    TlsClientOptions {
+        ca: matches.value_of("dst-tls-ca").map(Into::into),
+        server_name: matches.value_of("dst-tls-server-name").map(String::from),
+        alpn: matches
+            .value_of("dst-tls-alpn")
+            .map(|alpn| alpn.split(',').map(String::from).collect())
+            .unwrap_or_default(),
+        cert: matches.value_of("dst-tls-cert").map(|cert| TlsCertificate {
+            server_name: None,
+            cert: cert.into(),
+            key: matches.value_of("dst-tls-key").expect("required").into(),
+        }),
+        fingerprints: vec![],
+    }
 }
 
-#[derive(Debug)]
-pub struct Pipeline {
-    src: ServerUrl,
-    proxy: ProxyUrl,
-    dst: DestinationUrl,
+const PROXY_TLS_ARGS: &[&str] = &[
+    "proxy-tls-ca",
+    "proxy-tls-server-name",
+    "proxy-tls-fingerprint",
+];
+
+fn proxy_tls(matches: &ArgMatches) -> Result<TlsClientOptions> {
+    Ok(TlsClientOptions {
+        ca: matches.value_of("proxy-tls-ca").map(Into::into),
+        server_name: matches.value_of("proxy-tls-server-name").map(String::from),
+        fingerprints: matches
+            .values_of("proxy-tls-fingerprint")
+            .map(|fingerprints| fingerprints.map(parse_fingerprint).collect())
+            .transpose()?
+            .unwrap_or_default(),
+        ..TlsClientOptions::default()
+    })
 }
 
-impl Pipeline {
-    pub fn parse(args: Vec<&str>) -> Result<Self> {
-        if args.len() != 3 {
-            return Err(eyre!("pipeline must be length 3. (src, proxy, dst)"));
-        }
-
-        match args.as_slice() {
-            [src, proxy, dst] => {
-                let src = ServerUrl::new(parse_url(src)?)?;
-                let proxy = ProxyUrl::new(parse_url(proxy)?)?;
-                let dst = DestinationUrl::new(parse_url(dst)?)?;
-                Ok(Self { src, proxy, dst })
-            }
-            _ => unreachable!(),
+/// Server configuration of the pipeline given by command line arguments
+fn cli_pipeline(matches: &ArgMatches) -> Result<ServerConfig> {
+    let pipeline = matches.values_of("url").expect("required").collect();
+    let mut config = Pipeline::parse(pipeline)?.server_config();
+    if matches.is_present("ipv6-only") {
+        config.v6_only = Some(true);
+    } else if matches.is_present("dual-stack") {
+        config.v6_only = Some(false);
+    }
+    if let Some(mode) = matches.value_of("unix-mode") {
+        config.unix_socket.mode = Some(parse_mode(mode)?);
+    }
+    if let Some(owner) = matches.value_of("unix-owner") {
+        config.unix_socket.owner = Some(parse_uid(owner)?);
+    }
+    if let Some(group) = matches.value_of("unix-group") {
+        config.unix_socket.group = Some(parse_gid(group)?);
+    }
+    config.unix_socket.remove_stale = !matches.is_present("keep-stale-socket");
+    if let Some(cert) = matches.value_of("tls-cert") {
+        config.tls.certs.push(TlsCertificate {
+            server_name: None,
+            cert: cert.into(),
+            key: matches.value_of("tls-key").expect("required").into(),
+        });
+    }
+    if let Some(certs) = matches.values_of("tls-sni") {
+        for cert in certs {
+            config.tls.certs.push(parse_sni_certificate(cert)?);
         }
     }
-
-    pub fn server_addr(&self) -> SocketAddr {
-        self.src.socket_addr()
+    config.tls.client_ca = matches.value_of("tls-client-ca").map(Into::into);
+    if let Some(crls) = matches.values_of("tls-crl") {
+        config.tls.client_crls = crls.map(Into::into).collect();
     }
-
-    pub fn proxy_addr(&self) -> SocketAddr {
-        self.proxy.socket_addr()
+    if let Some(sources) = matches.values_of("proxy-protocol") {
+        config.proxy_protocol = Some(ProxyProtocolOptions {
+            trusted: sources.map(parse_trusted_source).collect::<Result<_>>()?,
+            ..ProxyProtocolOptions::default()
+        });
     }
-
-    pub fn dst_addr(&self) -> Address {
-        self.dst.addr()
+    validate_proxy_protocol(&config)?;
+    if config.proxies.iter().flatten().any(|proxy| proxy.tls) {
+        config.proxy_tls = proxy_tls(matches)?;
+    } else if PROXY_TLS_ARGS.iter().any(|arg| matches.is_present(arg)) {
+        return Err(eyre!(
+            "TLS options of proxies without socks5h+tls:// proxies"
+        ));
+    }
+    validate_tls(&config)?;
+    if let Some(tls) = &mut config.dst_tls {
+        *tls = destination_tls(matches);
+    } else if DESTINATION_TLS_ARGS
+        .iter()
+        .any(|arg| matches.is_present(arg))
+    {
+        return Err(eyre!(
+            "TLS options of destinations without tls:// destinations"
+        ));
+    }
+    if let Some(secs) = matches.value_of("udp-idle-timeout") {
+        config.udp_idle_timeout = match secs.parse() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => return Err(eyre!("invalid udp idle timeout: {}", secs)),
+        };
+    }
+    if let Some(count) = matches.value_of("udp-max-sessions") {
+        config.udp_max_sessions = match count.parse() {
+            Ok(count) if count > 0 => count,
+            _ => return Err(eyre!("invalid udp max sessions: {}", count)),
+        };
+    }
+    if let Some(secs) = matches.value_of("dns-cache-ttl") {
+        config.dns_cache_ttl = Duration::from_secs(
+            secs.parse()
+                .map_err(|_| eyre!("invalid dns cache ttl: {}", secs))?,
+        );
+    }
+    if let Some(secs) = matches.value_of("proxy-cooldown") {
+        config.proxy_cooldown = Duration::from_secs(
+            secs.parse()
+                .map_err(|_| eyre!("invalid proxy cooldown: {}", secs))?,
+        );
+    }
+    if let Some(secs) = matches.value_of("health-check") {
+        let interval = match secs.parse() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => return Err(eyre!("invalid health check interval: {}", secs)),
+        };
+        config.health_check = Some(HealthCheckOptions {
+            interval,
+            probe: matches
+                .value_of("health-check-probe")
+                .map(parse_probe)
+                .transpose()?,
+            ..HealthCheckOptions::default()
+        });
+    }
+    if let Some(n) = matches.value_of("connect-retries") {
+        let retries: u32 = n
+            .parse()
+            .map_err(|_| eyre!("invalid connect retries: {}", n))?;
+        config.retry.max_attempts = retries.saturating_add(1);
     }
+    if let Some(strategy) = matches.value_of("balance") {
+        config.balance = parse_balance_strategy(strategy)?;
+    }
+    if let Some(order) = matches.value_of("destination-order") {
+        config.dst_order = parse_destination_order(order)?;
+    }
+    if let Some(preference) = matches.value_of("ip-preference") {
+        config.ip_preference = parse_ip_preference(preference)?;
+    }
+    if let Some(addr) = matches.value_of("bind-address") {
+        config.outbound.bind_addr = Some(parse_bind_address(addr)?);
+    }
+    config.outbound.interface = matches.value_of("interface").map(String::from);
+    if let Some(mark) = matches.value_of("fwmark") {
+        config.outbound.fwmark = Some(parse_fwmark(mark)?);
+    }
+    if let Some(tos) = matches.value_of("tos") {
+        config.outbound.tos = Some(parse_tos(tos)?);
+    }
+    if let Some(rules) = matches.values_of("route") {
+        config.rules.rules = rules.map(parse_rule).collect::<Result<_>>()?;
+    }
+    if let Some(route) = matches.value_of("default-route") {
+        config.rules.default = parse_route(route)?;
+    }
+    // credentials are given to the proxies of the last hop
+    if let Some(user) = matches.value_of("proxy-user") {
+        let auth = proxy_credentials(
+            user,
+            None,
+            matches.value_of("proxy-password-file").map(Path::new),
+            matches.value_of("proxy-password-env"),
+        )?;
+        config.set_last_hop_auth(auth);
+    }
+    Ok(config)
 }
 
-#[derive(Debug, Clone)]
-struct ServerUrl(SocketAddr);
-
-#[derive(Debug, Clone)]
-struct ProxyUrl(SocketAddr);
+/// Sends a termination request to a server
+type Terminator = Box<dyn Fn() + Send>;
 
-#[derive(Debug, Clone)]
-struct DestinationUrl(Address);
-
-impl ServerUrl {
-    pub fn new(url: Url) -> Result<Self> {
-        if url.scheme() != "tcp" {
-            return Err(eyre!("not supportted server protocol: url = {}", url))
-                .note("supported protocols: tcp");
-        }
-
-        validate_socket_addr_contained_unique(&url)?;
-        let addr = url.socket_addrs(|| None).unwrap().pop().unwrap();
-
-        Ok(Self(addr))
-    }
-
-    pub fn socket_addr(&self) -> SocketAddr {
-        self.0
-    }
+fn terminator<T: Send + 'static>(tx: mpsc::Sender<T>, cmd: fn() -> T) -> Terminator {
+    Box::new(move || {
+        tx.send(cmd()).ok();
+    })
 }
 
-impl ProxyUrl {
-    pub fn new(url: Url) -> Result<Self> {
-        const PROTOCOLS: &[&str] = &["socks5h"];
+/// Thread serving a pipeline
+struct PipelineThread {
+    name: String,
+    terminate: Terminator,
+    handle: JoinHandle<tcp2socks::error::Result<()>>,
+}
 
-        if !PROTOCOLS.contains(&url.scheme()) {
-            return Err(eyre!("not supportted proxy protocol: url = {}", url))
-                .note("supported protocols: socks5h");
+/// Spawn a server for the pipeline. `tx_quit` is notified when the server quits.
+///
+/// Returns the thread and an extra terminator for the signal handler.
+fn spawn_pipeline(
+    name: String,
+    config: ServerConfig,
+    tx_quit: mpsc::Sender<()>,
+) -> Result<(PipelineThread, Terminator)> {
+    info!("start pipeline: {}: {:?}", name, config);
+    let builder = std::thread::Builder::new().name(format!("pipeline: {}", name));
+    let (handle, terminate, on_signal) = match config.protocol {
+        L4Protocol::Tcp => {
+            let (mut server, tx) = Server::new(config);
+            let handle = builder.spawn(move || {
+                let result = server.serve();
+                tx_quit.send(()).ok();
+                result
+            })?;
+            let terminate = || ServerCommand::Terminate;
+            (
+                handle,
+                terminator(tx.clone(), terminate),
+                terminator(tx, terminate),
+            )
         }
+        L4Protocol::Udp => {
+            let (mut server, tx) = UdpServer::new(config);
+            let handle = builder.spawn(move || {
+                let result = server.serve();
+                tx_quit.send(()).ok();
+                result
+            })?;
+            let terminate = || UdpServerCommand::Terminate;
+            (
+                handle,
+                terminator(tx.clone(), terminate),
+                terminator(tx, terminate),
+            )
+        }
+    };
+    let th = PipelineThread {
+        name,
+        terminate,
+        handle,
+    };
+    Ok((th, on_signal))
+}
 
-        validate_socket_addr_contained_unique(&url)?;
-        let addr = url.socket_addrs(|| None).unwrap().pop().unwrap();
-
-        Ok(Self(addr))
-    }
+/// Run a server for each pipeline until all of them stop
+///
+/// When a server quits, other servers are requested to terminate.
+fn run(pipelines: Vec<(String, ServerConfig)>) -> Result<()> {
+    use signal_hook::*;
 
-    pub fn socket_addr(&self) -> SocketAddr {
-        self.0
+    let (tx_quit, rx_quit) = mpsc::channel();
+    let mut servers = vec![];
+    let mut on_signal = vec![];
+    for (name, config) in pipelines {
+        let (th, terminate) = spawn_pipeline(name, config, tx_quit.clone())?;
+        servers.push(th);
+        on_signal.push(terminate);
     }
-}
 
-impl DestinationUrl {
-    pub fn new(url: Url) -> Result<Self> {
-        if url.scheme() != "tcp" {
-            return Err(eyre!("not supportted destination protocol: url = {}", url))
-                .note("supported protocols: tcp");
-        }
+    set_handler(&[SIGTERM, SIGINT, SIGQUIT, SIGCHLD], move |_| {
+        on_signal.iter().for_each(|terminate| terminate());
+    })
+    .expect("setting ctrl-c handler");
 
-        let addr = match (url.host(), url.port()) {
-            (Some(host), Some(port)) => {
-                use url::Host as H;
-                match host {
-                    H::Domain(domain) => Address::Domain(domain.into(), port),
-                    H::Ipv4(ip) => Address::IpAddr(ip.into(), port),
-                    H::Ipv6(ip) => Address::IpAddr(ip.into(), port),
-                }
+    // wait for the first server quits
+    rx_quit.recv().ok();
+    servers.iter().for_each(|th| (th.terminate)());
+
+    let mut failed = vec![];
+    for th in servers {
+        match th.handle.join().expect("server thread panicked") {
+            Ok(()) => info!("pipeline stopped: {}", th.name),
+            Err(err) => {
+                error!("server error: {}: {:?}", th.name, err);
+                failed.push(th.name);
             }
-            _ => {
-                return Err(eyre!(
-                    "destination url should be `tcp://<host>:<port>`: url = {}",
-                    url
-                ));
-            }
-        };
-
-        Ok(Self(addr))
+        }
     }
-
-    pub fn addr(&self) -> Address {
-        self.0.clone()
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(eyre!("server quited with error: {}", failed.join(", ")))
     }
 }
 
-fn set_handler(signals: &[i32], handler: impl Fn(i32) + Send + 'static) -> io::Result<()> {
-    use signal_hook::*;
-    let signals = iterator::Signals::new(signals)?;
-    std::thread::spawn(move || signals.forever().for_each(handler));
-    Ok(())
-}
-
 fn main() -> eyre::Result<()> {
-    use signal_hook::*;
-
     pretty_env_logger::init_timed();
     color_eyre::install()?;
 
@@ -166,25 +330,9 @@ fn main() -> eyre::Result<()> {
     let app = App::from(yaml).version(::clap::crate_version!());
     let matches = app.get_matches();
 
-    let pipeline = matches.values_of("url").expect("required").collect();
-    let pipeline = Pipeline::parse(pipeline)?;
-    let config = tcp2socks::ServerConfig::new(
-        pipeline.server_addr(),
-        pipeline.proxy_addr(),
-        pipeline.dst_addr(),
-    );
-
-    let (mut server, tx) = tcp2socks::server::Server::new(config);
-    set_handler(&[SIGTERM, SIGINT, SIGQUIT, SIGCHLD], move |_| {
-        tx.send(tcp2socks::ServerCommand::Terminate).ok();
-    })
-    .expect("setting ctrl-c handler");
-
-    match server.serve() {
-        Ok(_) => Ok(()),
-        Err(err) => {
-            error!("server error: {:?}", err);
-            Err(eyre!("server quited with error: {:?}", err))
-        }
-    }
+    let pipelines = match matches.value_of("config") {
+        Some(path) => ConfigFile::load(Path::new(path))?.server_configs()?,
+        None => vec![("main".to_owned(), cli_pipeline(&matches)?)],
+    };
+    run(pipelines)
 }
@@ -19,9 +19,79 @@ pub enum ErrorKind {
     #[fail(display = "packet size limit exceeded: {} > {}", size, limit)]
     PacketSizeLimitExceeded { size: usize, limit: usize },
     #[fail(display = "address already in use: {}", addr)]
-    AddressAlreadInUse { addr: SocketAddr },
+    AddressAlreadInUse { addr: SockAddr },
     #[fail(display = "address not available: {}", addr)]
-    AddressNotAvailable { addr: SocketAddr },
+    AddressNotAvailable { addr: SockAddr },
+    #[fail(display = "address not supported: {}", addr)]
+    AddressNotSupported { addr: SockAddr },
+    #[fail(display = "proxy authentication failed: {}", proxy)]
+    AuthenticationFailed { proxy: Address },
+    #[fail(display = "proxy does not support {}: {}", protocol, proxy)]
+    NotSupported {
+        proxy: Address,
+        protocol: L4Protocol,
+    },
+    #[fail(
+        display = "proxy does not support the destination: {}: {}",
+        addr, proxy
+    )]
+    DestinationNotSupported { proxy: Address, addr: Address },
+    #[fail(
+        display = "http proxy refused CONNECT: {}: {} {}",
+        proxy, status, reason
+    )]
+    HttpConnectFailed {
+        proxy: Address,
+        status: u16,
+        reason: String,
+    },
+    #[fail(display = "connection to proxy failed: {}", proxy)]
+    ProxyConnectionFailed { proxy: Address },
+    #[fail(display = "timed out connecting to proxy: {}", proxy)]
+    ConnectTimeout { proxy: Address },
+    #[fail(display = "timed out handshaking with proxy: {}", proxy)]
+    HandshakeTimeout { proxy: Address },
+    #[fail(display = "general SOCKS server failure: {}", proxy)]
+    ProxyGeneralFailure { proxy: Address },
+    #[fail(display = "connection not allowed by ruleset: {}: {}", addr, proxy)]
+    ConnectionNotAllowed { proxy: Address, addr: Address },
+    #[fail(display = "network unreachable: {}: {}", addr, proxy)]
+    NetworkUnreachable { proxy: Address, addr: Address },
+    #[fail(display = "host unreachable: {}: {}", addr, proxy)]
+    HostUnreachable { proxy: Address, addr: Address },
+    #[fail(display = "connection refused: {}: {}", addr, proxy)]
+    ConnectionRefused { proxy: Address, addr: Address },
+    #[fail(display = "TTL expired: {}: {}", addr, proxy)]
+    TtlExpired { proxy: Address, addr: Address },
+    #[fail(display = "proxy does not support command {}: {}", command, proxy)]
+    CommandNotSupported { proxy: Address, command: u8 },
+    #[fail(
+        display = "proxy does not support the address type: {}: {}",
+        addr, proxy
+    )]
+    AddressTypeNotSupported { proxy: Address, addr: Address },
+    #[fail(display = "unassigned SOCKS reply {}: {}", code, proxy)]
+    UnassignedReply { proxy: Address, code: u8 },
+    /// connecting directly to the destination timed out
+    #[fail(display = "timed out connecting to destination: {}", addr)]
+    DestinationTimeout { addr: Address },
+    /// connecting directly to the destination failed
+    #[fail(display = "destination unreachable: {}: {}", addr, reason)]
+    DestinationUnreachable { addr: Address, reason: String },
+    #[fail(display = "destination rejected by rules: {}", addr)]
+    Rejected { addr: Address },
+    #[fail(display = "invalid TLS certificate or key: {}: {}", path, reason)]
+    InvalidCertificate { path: String, reason: String },
+    #[fail(display = "TLS handshake failed: {}: {}", addr, reason)]
+    TlsHandshake { addr: Address, reason: String },
+    /// `hop` counts proxies of a chain from 1. Replies of the `last` hop are about the destination.
+    #[fail(display = "proxy hop {} failed: {}: {}", hop, proxy, cause)]
+    ProxyHop {
+        hop: usize,
+        last: bool,
+        proxy: Address,
+        cause: Box<ErrorKind>,
+    },
 }
 
 impl ErrorKind {
@@ -59,6 +129,33 @@ impl Error {
     pub fn kind(&self) -> &ErrorKind {
         self.inner.get_context()
     }
+
+    /// Whether the error is a timeout of blocking IO
+    pub fn is_timeout(&self) -> bool {
+        use std::io::ErrorKind as K;
+        self.inner
+            .cause()
+            .and_then(|cause| cause.downcast_ref::<std::io::Error>())
+            .map_or(false, |err| {
+                err.kind() == K::WouldBlock || err.kind() == K::TimedOut
+            })
+    }
+
+    /// Kind of the IO error underlying the error and its contexts
+    pub fn io_error_kind(&self) -> Option<std::io::ErrorKind> {
+        let err: &dyn Fail = self;
+        err.iter_causes()
+            .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .map(|err| err.kind())
+    }
+
+    /// OS error code of the IO error underlying the error and its contexts
+    pub fn raw_os_error(&self) -> Option<i32> {
+        let err: &dyn Fail = self;
+        err.iter_causes()
+            .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .and_then(|err| err.raw_os_error())
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -1,12 +1,13 @@
 use std::fmt;
 use std::net::ToSocketAddrs;
 pub use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use serde::*;
 
 /// ip address and port
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Address {
     IpAddr(IpAddr, u16),
     Domain(String, u16),
@@ -70,20 +71,82 @@ impl ToSocketAddrs for Address {
     }
 }
 
-impl socks::ToTargetAddr for Address {
-    fn to_target_addr(&self) -> std::io::Result<socks::TargetAddr> {
+/// username/password for authentication to proxy servers
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new<S: Into<String>, T: Into<String>>(username: S, password: T) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+/// hide password from logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"********")
+            .finish()
+    }
+}
+
+/// address of an unix domain socket
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum UnixAddr {
+    /// socket which is not bound to any name (e.g. a client socket)
+    Unnamed,
+    /// socket bound to a filesystem path
+    Pathname(PathBuf),
+    /// socket bound to a name in the abstract namespace (linux only)
+    Abstract(Vec<u8>),
+}
+
+impl fmt::Display for UnixAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use UnixAddr::*;
         match self {
-            Address::IpAddr(std::net::IpAddr::V4(ipaddr), port) => {
-                (*ipaddr, *port).to_target_addr()
-            }
-            Address::IpAddr(std::net::IpAddr::V6(ipaddr), port) => {
-                (*ipaddr, *port).to_target_addr()
-            }
-            Address::Domain(domain, port) => (domain.as_str(), *port).to_target_addr(),
+            Unnamed => write!(f, "unix:<unnamed>"),
+            Pathname(path) => write!(f, "unix:{}", path.display()),
+            Abstract(name) => write!(f, "unix:@{}", String::from_utf8_lossy(name)),
+        }
+    }
+}
+
+/// address of a listening socket or a client connected to it
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SockAddr {
+    Inet(SocketAddr),
+    Unix(UnixAddr),
+}
+
+impl fmt::Display for SockAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SockAddr::Inet(addr) => write!(f, "{}", addr),
+            SockAddr::Unix(addr) => write!(f, "{}", addr),
         }
     }
 }
 
+impl From<SocketAddr> for SockAddr {
+    fn from(addr: SocketAddr) -> Self {
+        SockAddr::Inet(addr)
+    }
+}
+
+impl From<UnixAddr> for SockAddr {
+    fn from(addr: UnixAddr) -> Self {
+        SockAddr::Unix(addr)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum L4Protocol {
     Tcp,
@@ -99,6 +162,45 @@ impl fmt::Display for L4Protocol {
     }
 }
 
+/// Protocol to talk with the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ProxyProtocol {
+    /// SOCKS5 with local name resolution
+    Socks5,
+    /// SOCKS5 with remote name resolution
+    Socks5h,
+    /// HTTP CONNECT
+    Http,
+    /// SOCKS4. destinations should be IPv4 addresses.
+    Socks4,
+    /// SOCKS4 with remote name resolution
+    Socks4a,
+}
+
+impl ProxyProtocol {
+    /// Whether the proxy relays `protocol`
+    pub fn supports(&self, protocol: L4Protocol) -> bool {
+        match self {
+            ProxyProtocol::Socks5 | ProxyProtocol::Socks5h => true,
+            ProxyProtocol::Http | ProxyProtocol::Socks4 | ProxyProtocol::Socks4a => {
+                protocol == L4Protocol::Tcp
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProxyProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyProtocol::Socks5 => write!(f, "socks5"),
+            ProxyProtocol::Socks5h => write!(f, "socks5h"),
+            ProxyProtocol::Http => write!(f, "http"),
+            ProxyProtocol::Socks4 => write!(f, "socks4"),
+            ProxyProtocol::Socks4a => write!(f, "socks4a"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct UdpDatagram<'a> {
     pub frag: u8,
@@ -0,0 +1,119 @@
+//! Sockets to proxies and to destinations of direct routes
+//!
+//! Outbound options let policy routing steer the traffic of a pipeline:
+//! sockets are bound to the local address and the interface, and marked before connecting.
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::OutboundOptions;
+
+/// Whether `addr` can be connected from the bind address
+pub(crate) fn reachable(options: &OutboundOptions, addr: &SocketAddr) -> bool {
+    match options.bind_addr {
+        Some(bind) => bind.is_ipv4() == addr.is_ipv4(),
+        None => true,
+    }
+}
+
+/// TCP connection to `addr` with the options
+pub(crate) fn connect(
+    options: &OutboundOptions,
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let socket = socket(options, addr, Type::STREAM, Protocol::TCP)?;
+    match timeout {
+        Some(timeout) => socket.connect_timeout(&addr.into(), timeout)?,
+        None => socket.connect(&addr.into())?,
+    }
+    Ok(socket.into())
+}
+
+/// UDP socket to send datagrams to `peer` with the options
+pub(crate) fn bind_udp(options: &OutboundOptions, peer: SocketAddr) -> io::Result<UdpSocket> {
+    Ok(socket(options, peer, Type::DGRAM, Protocol::UDP)?.into())
+}
+
+/// Bound socket of the family of `peer`
+fn socket(
+    options: &OutboundOptions,
+    peer: SocketAddr,
+    ty: Type,
+    protocol: Protocol,
+) -> io::Result<Socket> {
+    if !reachable(options, &peer) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("bind address of another family: {}", peer),
+        ));
+    }
+    let socket = Socket::new(Domain::for_address(peer), ty, Some(protocol))?;
+    if let Some(interface) = &options.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    if let Some(mark) = options.fwmark {
+        socket.set_mark(mark)?;
+    }
+    if let Some(tos) = options.tos {
+        match peer {
+            SocketAddr::V4(_) => socket.set_tos(tos.into())?,
+            SocketAddr::V6(_) => set_tclass_v6(&socket, tos)?,
+        }
+    }
+    if let Some(ip) = options.bind_addr {
+        socket.bind(&SocketAddr::new(ip, 0).into())?;
+    }
+    Ok(socket)
+}
+
+/// `IPV6_TCLASS`, the IPv6 counterpart of `IP_TOS`
+fn set_tclass_v6(socket: &Socket, tclass: u8) -> io::Result<()> {
+    let tclass = libc::c_int::from(tclass);
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &tclass as *const _ as *const libc::c_void,
+            mem::size_of_val(&tclass) as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, TcpListener};
+
+    #[test]
+    fn bind() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = OutboundOptions {
+            bind_addr: Some(Ipv4Addr::LOCALHOST.into()),
+            tos: Some(0xb8),
+            ..OutboundOptions::default()
+        };
+        let strm = connect(&options, addr, Some(Duration::from_secs(1))).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(strm.local_addr().unwrap(), peer);
+        assert_eq!(peer.ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+
+        let v6: SocketAddr = "[::1]:554".parse().unwrap();
+        assert!(!reachable(&options, &v6));
+        assert_eq!(
+            connect(&options, v6, None).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(reachable(&OutboundOptions::default(), &v6));
+    }
+}
@@ -1,27 +1,44 @@
-use std::io;
+use std::io::{self, Read};
 use std::net;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use crate::model::{Error, ErrorKind};
+use log::*;
 
-pub trait PktStream {
+use crate::model::{Address, Error, ErrorKind, UdpDatagram};
+use crate::socks5;
+use crate::thread::spawn_thread;
+
+/// maximum payload size of an IPv4 UDP datagram
+pub const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// Datagram oriented connection to a destination
+///
+/// Methods take `&self`, so that a stream can be shared between sending and receiving threads.
+pub trait PktStream: Send + Sync {
+    /// maximum size of payloads
     fn pkt_size(&self) -> usize;
-    fn recv_pkt(&mut self) -> Result<&[u8], Error>;
+    /// Receive a payload into `buf`. Returns its size and the address it comes from.
+    ///
+    /// `buf` should be large enough for a UDP datagram, otherwise the payload is truncated.
+    fn recv_pkt(&self, buf: &mut [u8]) -> Result<(usize, Address), Error>;
     fn send_pkt(&self, pkt: &[u8]) -> Result<(), Error>;
+    /// Timeout of `recv_pkt`. `None` blocks indefinitely.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error>;
 }
 
+/// Datagrams sent to the destination without proxies
 pub struct UdpPktStream {
     pkt_size: usize,
+    /// socket connected to the destination
     socket: net::UdpSocket,
-    buf: Vec<u8>,
 }
 
 impl UdpPktStream {
     pub fn new(pkt_size: usize, socket: net::UdpSocket) -> Self {
-        Self {
-            pkt_size,
-            socket,
-            buf: Vec::with_capacity(pkt_size),
-        }
+        Self { pkt_size, socket }
     }
 }
 
@@ -30,9 +47,9 @@ impl PktStream for UdpPktStream {
         self.pkt_size
     }
 
-    fn recv_pkt(&mut self) -> Result<&[u8], Error> {
-        self.socket.recv(&mut self.buf)?;
-        Ok(&self.buf)
+    fn recv_pkt(&self, buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        let (size, addr) = self.socket.recv_from(buf)?;
+        Ok((size, addr.into()))
     }
 
     fn send_pkt(&self, pkt: &[u8]) -> Result<(), Error> {
@@ -57,4 +74,182 @@ impl PktStream for UdpPktStream {
             })
             .map_err(Into::into)
     }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.socket.set_read_timeout(timeout).map_err(Into::into)
+    }
+}
+
+/// Packets relayed through a proxy or sent directly
+pub enum ProxyPktStream {
+    Socks5(Socks5PktStream),
+    Direct(UdpPktStream),
+}
+
+impl From<Socks5PktStream> for ProxyPktStream {
+    fn from(strm: Socks5PktStream) -> Self {
+        ProxyPktStream::Socks5(strm)
+    }
+}
+
+impl From<UdpPktStream> for ProxyPktStream {
+    fn from(strm: UdpPktStream) -> Self {
+        ProxyPktStream::Direct(strm)
+    }
+}
+
+impl PktStream for ProxyPktStream {
+    fn pkt_size(&self) -> usize {
+        match self {
+            ProxyPktStream::Socks5(strm) => strm.pkt_size(),
+            ProxyPktStream::Direct(strm) => strm.pkt_size(),
+        }
+    }
+
+    fn recv_pkt(&self, buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        match self {
+            ProxyPktStream::Socks5(strm) => strm.recv_pkt(buf),
+            ProxyPktStream::Direct(strm) => strm.recv_pkt(buf),
+        }
+    }
+
+    fn send_pkt(&self, pkt: &[u8]) -> Result<(), Error> {
+        match self {
+            ProxyPktStream::Socks5(strm) => strm.send_pkt(pkt),
+            ProxyPktStream::Direct(strm) => strm.send_pkt(pkt),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            ProxyPktStream::Socks5(strm) => strm.set_read_timeout(timeout),
+            ProxyPktStream::Direct(strm) => strm.set_read_timeout(timeout),
+        }
+    }
+}
+
+/// Packets relayed through a SOCKS5 UDP association
+///
+/// The association lasts while the TCP control connection is open.
+/// Datagrams are sent to the relay address with UDP request headers for `dst_addr`.
+pub struct Socks5PktStream {
+    pkt_size: usize,
+    /// socket connected to the relay address
+    socket: net::UdpSocket,
+    dst_addr: Address,
+    /// TCP control connection of the association
+    control: net::TcpStream,
+    /// set when the proxy closes the control connection
+    closed: Arc<AtomicBool>,
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl Socks5PktStream {
+    pub fn new(
+        socket: net::UdpSocket,
+        dst_addr: Address,
+        control: net::TcpStream,
+    ) -> Result<Self, Error> {
+        let closed = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let mut control = control.try_clone()?;
+            control.set_read_timeout(None)?;
+            let closed = closed.clone();
+            let addr = dst_addr.clone();
+            let name = format!("udp association: {}", dst_addr);
+            spawn_thread(&name, move || {
+                // no data is expected on the control connection
+                let mut buf = [0; 64];
+                while let Ok(n) = control.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                }
+                debug!("control connection closed: {}", addr);
+                closed.store(true, Ordering::SeqCst);
+            })?
+        };
+        Ok(Self {
+            pkt_size: MAX_UDP_PAYLOAD - socks5::udp_header_len(&dst_addr),
+            socket,
+            dst_addr,
+            control,
+            closed,
+            watcher: Some(watcher),
+        })
+    }
+
+    fn check_association(&self) -> Result<(), Error> {
+        if self.closed.load(Ordering::SeqCst) {
+            Err(ErrorKind::disconnected("udp association").into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl PktStream for Socks5PktStream {
+    fn pkt_size(&self) -> usize {
+        self.pkt_size
+    }
+
+    fn recv_pkt(&self, buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        loop {
+            self.check_association()?;
+            let size = self.socket.recv(buf)?;
+            let (offset, src_addr) = match UdpDatagram::parse(&buf[..size]) {
+                Ok(dgram) if dgram.frag != 0 => {
+                    warn!("drop fragmented datagram: {}", dgram.dst_addr);
+                    continue;
+                }
+                Ok(dgram) => (size - dgram.data.len(), dgram.dst_addr),
+                Err(err) => {
+                    warn!("drop invalid datagram: {}", err);
+                    continue;
+                }
+            };
+            buf.copy_within(offset..size, 0);
+            return Ok((size - offset, src_addr));
+        }
+    }
+
+    fn send_pkt(&self, pkt: &[u8]) -> Result<(), Error> {
+        self.check_association()?;
+        if pkt.len() > self.pkt_size {
+            return Err(ErrorKind::PacketSizeLimitExceeded {
+                size: pkt.len(),
+                limit: self.pkt_size,
+            }
+            .into());
+        }
+        let dgram = UdpDatagram {
+            frag: 0,
+            dst_addr: self.dst_addr.clone(),
+            data: pkt,
+        }
+        .to_bytes()?;
+        let size = self.socket.send(&dgram)?;
+        if size != dgram.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Socks5PktStream::send: {} != {}", size, dgram.len()),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.socket.set_read_timeout(timeout).map_err(Into::into)
+    }
+}
+
+/// Closing the control connection terminates the association
+impl Drop for Socks5PktStream {
+    fn drop(&mut self) {
+        self.control.shutdown(net::Shutdown::Both).ok();
+        if let Some(watcher) = self.watcher.take() {
+            watcher.join().ok();
+        }
+    }
 }
@@ -0,0 +1,205 @@
+//! PROXY protocol v1 and v2 headers sent by load balancers
+//!
+//! Headers are read without consuming the data following them.
+//! Malformed headers are reported as `io::ErrorKind::InvalidData`.
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+/// `PROXY ` starting v1 headers
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// longest v1 header including CRLF
+const V1_MAX_LEN: usize = 107;
+/// signature starting v2 headers
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION: u8 = 0x20;
+const V2_CMD_LOCAL: u8 = 0x00;
+const V2_CMD_PROXY: u8 = 0x01;
+const V2_AF_INET: u8 = 0x10;
+const V2_AF_INET6: u8 = 0x20;
+
+/// Read a v1 or v2 header. Returns the source address the header tells.
+///
+/// `None` for connections of the load balancer itself,
+/// i.e. v1 `UNKNOWN`, v2 `LOCAL` and v2 addresses other than IPv4 and IPv6.
+pub fn read_header<R: Read>(rd: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0; 6];
+    rd.read_exact(&mut prefix)?;
+    if prefix == V1_PREFIX {
+        read_v1(rd)
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2(rd)
+    } else {
+        Err(invalid("no PROXY protocol header"))
+    }
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n` after `PROXY `
+fn read_v1<R: Read>(rd: &mut R) -> io::Result<Option<SocketAddr>> {
+    // byte by byte not to read beyond CRLF
+    let mut line = vec![];
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if V1_PREFIX.len() + line.len() >= V1_MAX_LEN {
+            return Err(invalid("too long PROXY protocol v1 header"));
+        }
+        rd.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("invalid PROXY protocol v1 header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [family @ "TCP4", src, _dst, src_port, _dst_port]
+        | [family @ "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src
+                .parse()
+                .map_err(|_| invalid("invalid source address of PROXY protocol v1 header"))?;
+            let port: u16 = src_port
+                .parse()
+                .map_err(|_| invalid("invalid source port of PROXY protocol v1 header"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("source address of another family"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY protocol v1 header")),
+    }
+}
+
+/// Binary header after the first 6 bytes of the signature
+fn read_v2<R: Read>(rd: &mut R) -> io::Result<Option<SocketAddr>> {
+    // the rest of the signature, version/command, family/protocol and length
+    let mut header = [0; 10];
+    rd.read_exact(&mut header)?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("invalid PROXY protocol v2 signature"));
+    }
+    let (version_command, family) = (header[6], header[7]);
+    if version_command & 0xf0 != V2_VERSION {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+    // addresses and TLVs, which are skipped
+    let mut body = vec![0; len];
+    rd.read_exact(&mut body)?;
+    match version_command & 0x0f {
+        V2_CMD_LOCAL => return Ok(None),
+        V2_CMD_PROXY => {}
+        _ => return Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+    let short = || invalid("too short PROXY protocol v2 addresses");
+    // source address, destination address, source port and destination port
+    match family & 0xf0 {
+        V2_AF_INET => {
+            let addrs = body.get(..12).ok_or_else(short)?;
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        V2_AF_INET6 => {
+            let addrs = body.get(..36).ok_or_else(short)?;
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // AF_UNSPEC and AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header read from `data` and the rest of `data`
+    fn read(data: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut rd = io::Cursor::new(data);
+        let header = read_header(&mut rd);
+        let mut rest = vec![];
+        rd.read_to_end(&mut rest).unwrap();
+        (header, rest)
+    }
+
+    #[test]
+    fn v1() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /");
+        assert_eq!(header.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n");
+        assert_eq!(
+            header.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        let (header, rest) = read(b"PROXY UNKNOWN\r\nhello");
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"hello");
+
+        for data in &[
+            &b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY TCP4 192.0.2.1\r\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            let err = read(data).0.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", data);
+        }
+        let long = [&b"PROXY "[..], &[b'x'; 200]].concat();
+        assert_eq!(
+            read(&long).0.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn v2() {
+        let encode = |command: u8, family: u8, addrs: &[u8]| {
+            let mut data = V2_SIGNATURE.to_vec();
+            data.extend_from_slice(&[V2_VERSION | command, family]);
+            data.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+            data.extend_from_slice(addrs);
+            data.extend_from_slice(b"hello");
+            data
+        };
+        // TCP over IPv4 with a TLV
+        let addrs = [
+            192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb, 0x04, 0, 1, 0,
+        ];
+        let (header, rest) = read(&encode(V2_CMD_PROXY, 0x11, &addrs));
+        assert_eq!(header.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+
+        let mut addrs = vec![0; 36];
+        addrs[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addrs[32..34].copy_from_slice(&56324u16.to_be_bytes());
+        let (header, _) = read(&encode(V2_CMD_PROXY, 0x21, &addrs));
+        assert_eq!(
+            header.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        // health checks of the load balancer
+        let (header, rest) = read(&encode(V2_CMD_LOCAL, 0, &[]));
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"hello");
+
+        let err = read(&encode(V2_CMD_PROXY, 0x11, &[192, 0, 2, 1]))
+            .0
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut data = encode(V2_CMD_PROXY, 0x11, &addrs);
+        data[12] = 0x11;
+        assert_eq!(
+            read(&data).0.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -1,19 +1,18 @@
 use std::io;
-use std::net::SocketAddr;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::{self, JoinHandle};
 
 use log::*;
 
 use crate::byte_stream::{BoxedStream, ByteStream};
-use crate::model::{Error, ErrorKind};
+use crate::model::{Error, ErrorKind, SockAddr, SocketAddr};
 use crate::session::DisconnectGuard;
 use crate::thread::spawn_thread;
 
 #[derive(Debug)]
 pub struct RelayHandle {
     /// client address
-    client_addr: SocketAddr,
+    client_addr: SockAddr,
     /// server address
     server_addr: SocketAddr,
     /// handle to relay: client -> external network
@@ -24,7 +23,7 @@ pub struct RelayHandle {
 
 impl RelayHandle {
     fn new(
-        client_addr: SocketAddr,
+        client_addr: SockAddr,
         server_addr: SocketAddr,
         outbound_th: JoinHandle<Result<(), Error>>,
         incoming_th: JoinHandle<Result<(), Error>>,
@@ -58,7 +57,7 @@ impl RelayHandle {
 /// * `guard`
 ///    Send `Disconnect` to the main thread when the relay thread is completed.
 pub fn spawn_relay<S>(
-    client_addr: SocketAddr,
+    client_addr: SockAddr,
     server_addr: SocketAddr,
     client_conn: BoxedStream,
     server_conn: impl ByteStream,
@@ -74,15 +73,29 @@ where
     let outbound_th = {
         let guard = guard.clone();
         let rx = rx.clone();
+        let client_addr = client_addr.clone();
         spawn_thread("outbound", move || {
             let _guard = guard;
-            spawn_relay_half(rx, client_addr, server_addr, read_client, write_server)
+            spawn_relay_half(
+                rx,
+                client_addr,
+                server_addr.into(),
+                read_client,
+                write_server,
+            )
         })?
     };
     let incoming_th = {
+        let client_addr = client_addr.clone();
         spawn_thread("incoming", move || {
             let _guard = guard;
-            spawn_relay_half(rx, server_addr, client_addr, read_server, write_client)
+            spawn_relay_half(
+                rx,
+                server_addr.into(),
+                client_addr,
+                read_server,
+                write_client,
+            )
         })?
     };
     Ok(RelayHandle::new(
@@ -95,8 +108,8 @@ where
 
 fn spawn_relay_half(
     rx: Arc<Mutex<mpsc::Receiver<()>>>,
-    src_addr: SocketAddr,
-    dst_addr: SocketAddr,
+    src_addr: SockAddr,
+    dst_addr: SockAddr,
     mut src: impl io::Read + Send + 'static,
     mut dst: impl io::Write + Send + 'static,
 ) -> Result<(), Error> {
@@ -154,7 +167,7 @@ mod tests {
         use crate::session::SessionId;
 
         let client_writer = Arc::new(Mutex::new(io::Cursor::new(vec![])));
-        let client_addr = "192.168.1.1:45678".parse().unwrap();
+        let client_addr = "192.168.1.1:45678".parse::<SocketAddr>().unwrap();
         let dummy_client_conn = Box::new(IterBuffer {
             iter: vec![b"hello".to_vec(), b" ".to_vec(), b"client".to_vec()].into_iter(),
             wr_buff: client_writer.clone(),
@@ -174,7 +187,7 @@ mod tests {
         let handle = {
             let rx_relay = Arc::new(Mutex::new(rx_relay));
             spawn_relay(
-                client_addr,
+                client_addr.into(),
                 server_addr,
                 dummy_client_conn,
                 dummy_server_conn,
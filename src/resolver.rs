@@ -0,0 +1,347 @@
+//! Local name resolution with cache
+//!
+//! The system resolver does not tell TTLs of records,
+//! so resolved addresses are cached for a fixed duration.
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::model::Address;
+use crate::thread::spawn_thread;
+
+/// Address family tried first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpPreference {
+    /// order of the system resolver
+    System,
+    Ipv4,
+    Ipv6,
+}
+
+impl FromStr for IpPreference {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(IpPreference::System),
+            "ipv4" => Ok(IpPreference::Ipv4),
+            "ipv6" => Ok(IpPreference::Ipv6),
+            _ => Err(format!(
+                "unknown ip preference: {} (system, ipv4 or ipv6)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IpPreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpPreference::System => write!(f, "system"),
+            IpPreference::Ipv4 => write!(f, "ipv4"),
+            IpPreference::Ipv6 => write!(f, "ipv6"),
+        }
+    }
+}
+
+impl IpPreference {
+    /// Stable sort of `ips` in the preferred order
+    fn sort(self, ips: &mut [IpAddr]) {
+        match self {
+            IpPreference::System => {}
+            IpPreference::Ipv4 => ips.sort_by_key(|ip| !ip.is_ipv4()),
+            IpPreference::Ipv6 => ips.sort_by_key(|ip| !ip.is_ipv6()),
+        }
+    }
+}
+
+/// Names cached at most
+const MAX_CACHE_ENTRIES: usize = 1024;
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Function looking up addresses of a name
+type Lookup = Arc<dyn Fn(&str) -> io::Result<Vec<IpAddr>> + Send + Sync>;
+
+/// Look up `domain` by the system resolver
+fn system_lookup(domain: &str) -> io::Result<Vec<IpAddr>> {
+    Ok((domain, 0).to_socket_addrs()?.map(|a| a.ip()).collect())
+}
+
+/// Resolver shared by sessions
+#[derive(Clone)]
+pub struct Resolver {
+    /// duration to keep resolved addresses. zero disables the cache.
+    ttl: Duration,
+    preference: IpPreference,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+    max_entries: usize,
+    lookup: Lookup,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("ttl", &self.ttl)
+            .field("preference", &self.preference)
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
+
+impl Resolver {
+    pub fn new(ttl: Duration, preference: IpPreference) -> Self {
+        Self {
+            ttl,
+            preference,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            max_entries: MAX_CACHE_ENTRIES,
+            lookup: Arc::new(system_lookup),
+        }
+    }
+
+    /// Look up names by `lookup` instead of the system resolver
+    pub fn with_lookup<F>(self, lookup: F) -> Self
+    where
+        F: Fn(&str) -> io::Result<Vec<IpAddr>> + Send + Sync + 'static,
+    {
+        Self {
+            lookup: Arc::new(lookup),
+            ..self
+        }
+    }
+
+    /// Resolve `addr` into socket addresses in the preferred order
+    pub fn resolve(&self, addr: &Address) -> io::Result<Vec<SocketAddr>> {
+        match addr {
+            Address::IpAddr(ip, port) => Ok(vec![SocketAddr::new(*ip, *port)]),
+            Address::Domain(domain, port) => Ok(self
+                .lookup(domain)?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, *port))
+                .collect()),
+        }
+    }
+
+    /// Resolve `addr` like `resolve`, failing with `TimedOut` if the lookup takes over `timeout`
+    ///
+    /// The lookup goes on in the background after the timeout, and its result is cached.
+    pub fn resolve_timeout(
+        &self,
+        addr: &Address,
+        timeout: Option<Duration>,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let timeout = match (addr, timeout) {
+            (Address::Domain(domain, _), Some(timeout)) if self.cached(domain).is_none() => timeout,
+            _ => return self.resolve(addr),
+        };
+        let (tx, rx) = mpsc::channel();
+        let (resolver, addr) = (self.clone(), addr.clone());
+        spawn_thread(&format!("resolve: {}", addr), move || {
+            // the receiver is gone after the timeout
+            tx.send(resolver.resolve(&addr)).ok();
+        })?;
+        match rx.recv_timeout(timeout) {
+            Ok(resolved) => resolved,
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "name resolution timed out",
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "name resolution aborted",
+            )),
+        }
+    }
+
+    fn lookup(&self, domain: &str) -> io::Result<Vec<IpAddr>> {
+        if let Some(ips) = self.cached(domain) {
+            trace!("resolved from cache: {}: {:?}", domain, ips);
+            return Ok(ips);
+        }
+        let mut ips = (self.lookup)(domain)?;
+        if ips.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no addresses: {}", domain),
+            ));
+        }
+        self.preference.sort(&mut ips);
+        debug!("resolved: {}: {:?}", domain, ips);
+        if self.ttl > Duration::from_secs(0) {
+            let entry = CacheEntry {
+                ips: ips.clone(),
+                expires: Instant::now() + self.ttl,
+            };
+            if let Ok(mut cache) = self.cache.lock() {
+                if cache.len() >= self.max_entries && !cache.contains_key(domain) {
+                    evict(&mut cache, self.max_entries);
+                }
+                cache.insert(domain.to_owned(), entry);
+            }
+        }
+        Ok(ips)
+    }
+
+    fn cached(&self, domain: &str) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.lock().ok()?;
+        match cache.get(domain) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.ips.clone()),
+            Some(_) => {
+                cache.remove(domain);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Make room for an entry in `cache` of `max_entries`
+///
+/// Expired entries are removed, or the entry expiring first if none are expired.
+fn evict(cache: &mut HashMap<String, CacheEntry>, max_entries: usize) {
+    let now = Instant::now();
+    cache.retain(|_, entry| entry.expires > now);
+    if cache.len() < max_entries {
+        return;
+    }
+    let first = cache
+        .iter()
+        .min_by_key(|(_, entry)| entry.expires)
+        .map(|(domain, _)| domain.clone());
+    if let Some(domain) = first {
+        cache.remove(&domain);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn preference() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        let mut ips = vec![v6, v4];
+        IpPreference::Ipv4.sort(&mut ips);
+        assert_eq!(ips, vec![v4, v6]);
+        IpPreference::Ipv6.sort(&mut ips);
+        assert_eq!(ips, vec![v6, v4]);
+        IpPreference::System.sort(&mut ips);
+        assert_eq!(ips, vec![v6, v4]);
+    }
+
+    /// Resolver looking up `192.0.2.1` for every name, and the count of lookups
+    fn stub_resolver(ttl: Duration) -> (Resolver, Arc<AtomicUsize>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let count = lookups.clone();
+        let resolver = Resolver::new(ttl, IpPreference::System).with_lookup(move |domain| {
+            count.fetch_add(1, Ordering::SeqCst);
+            match domain {
+                "empty.invalid" => Ok(vec![]),
+                _ => Ok(vec!["192.0.2.1".parse().unwrap()]),
+            }
+        });
+        (resolver, lookups)
+    }
+
+    fn resolve(resolver: &Resolver, domain: &str) -> io::Result<Vec<SocketAddr>> {
+        resolver.resolve(&Address::Domain(domain.into(), 554))
+    }
+
+    #[test]
+    fn cache() {
+        let (resolver, lookups) = stub_resolver(Duration::from_secs(60));
+        let addr = SocketAddr::new("192.0.2.1".parse().unwrap(), 554);
+        assert_eq!(resolve(&resolver, "camera.invalid").unwrap(), vec![addr]);
+        assert_eq!(resolve(&resolver, "camera.invalid").unwrap(), vec![addr]);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        // expired entries are looked up again
+        resolver
+            .cache
+            .lock()
+            .unwrap()
+            .get_mut("camera.invalid")
+            .unwrap()
+            .expires = Instant::now();
+        assert_eq!(resolve(&resolver, "camera.invalid").unwrap(), vec![addr]);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        assert!(resolve(&resolver, "empty.invalid").is_err());
+        assert_eq!(resolver.cache.lock().unwrap().len(), 1);
+
+        let (resolver, lookups) = stub_resolver(Duration::from_secs(0));
+        resolve(&resolver, "camera.invalid").unwrap();
+        resolve(&resolver, "camera.invalid").unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+        assert!(resolver.cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn resolve_timeout() {
+        let resolver =
+            Resolver::new(Duration::from_secs(60), IpPreference::System).with_lookup(|domain| {
+                if domain == "slow.invalid" {
+                    std::thread::sleep(Duration::from_millis(500));
+                }
+                Ok(vec!["192.0.2.1".parse().unwrap()])
+            });
+        let timeout = Some(Duration::from_millis(100));
+        let slow = Address::Domain("slow.invalid".into(), 554);
+        let err = resolver.resolve_timeout(&slow, timeout).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let fast = Address::Domain("fast.invalid".into(), 554);
+        let addr = SocketAddr::new("192.0.2.1".parse().unwrap(), 554);
+        assert_eq!(
+            resolver.resolve_timeout(&fast, timeout).unwrap(),
+            vec![addr]
+        );
+
+        // cached by the lookup timed out
+        std::thread::sleep(Duration::from_millis(600));
+        assert_eq!(
+            resolver.resolve_timeout(&slow, timeout).unwrap(),
+            vec![addr]
+        );
+    }
+
+    #[test]
+    fn cache_limit() {
+        let (mut resolver, _) = stub_resolver(Duration::from_secs(60));
+        resolver.max_entries = 2;
+        let cached = |resolver: &Resolver| {
+            let mut domains: Vec<_> = resolver.cache.lock().unwrap().keys().cloned().collect();
+            domains.sort();
+            domains
+        };
+        resolve(&resolver, "a.invalid").unwrap();
+        resolve(&resolver, "b.invalid").unwrap();
+
+        // expired entries are evicted first
+        resolver
+            .cache
+            .lock()
+            .unwrap()
+            .get_mut("b.invalid")
+            .unwrap()
+            .expires = Instant::now();
+        resolve(&resolver, "c.invalid").unwrap();
+        assert_eq!(cached(&resolver), vec!["a.invalid", "c.invalid"]);
+
+        // then the entry expiring first
+        resolve(&resolver, "d.invalid").unwrap();
+        assert_eq!(cached(&resolver), vec!["c.invalid", "d.invalid"]);
+    }
+}
@@ -0,0 +1,220 @@
+//! Retries of connections to the destination
+//!
+//! Backoff doubles at each retry and is jittered to spread retries of sessions
+//! failed at the same time.
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::model::{Error, ErrorKind};
+
+/// Class of errors to retry on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryOn {
+    /// the proxy or the destination refused the connection
+    Refused,
+    /// connecting or handshaking timed out
+    Timeout,
+    /// the connection was reset or closed during the handshake
+    Reset,
+}
+
+impl FromStr for RetryOn {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refused" => Ok(RetryOn::Refused),
+            "timeout" => Ok(RetryOn::Timeout),
+            "reset" => Ok(RetryOn::Reset),
+            _ => Err(format!(
+                "unknown retryable error: {} (refused, timeout or reset)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RetryOn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetryOn::Refused => write!(f, "refused"),
+            RetryOn::Timeout => write!(f, "timeout"),
+            RetryOn::Reset => write!(f, "reset"),
+        }
+    }
+}
+
+impl RetryOn {
+    /// Class of `err`. `None` for errors not worth retrying, e.g. rejections by rulesets.
+    pub fn of(err: &Error) -> Option<Self> {
+        if let ErrorKind::ConnectTimeout { .. }
+        | ErrorKind::HandshakeTimeout { .. }
+        | ErrorKind::DestinationTimeout { .. } = err.kind()
+        {
+            return Some(RetryOn::Timeout);
+        }
+        match err.io_error_kind()? {
+            io::ErrorKind::ConnectionRefused => Some(RetryOn::Refused),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Some(RetryOn::Timeout),
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof => Some(RetryOn::Reset),
+            _ => None,
+        }
+    }
+}
+
+/// Retries of connections to the destination for a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// attempts including the first one. 1 disables retries. (default: 1)
+    pub max_attempts: u32,
+    /// backoff before the first retry. (default: 200ms)
+    pub initial_backoff: Duration,
+    /// upper bound of backoff. (default: 5s)
+    pub max_backoff: Duration,
+    /// no retry starts later than this since the first attempt. (default: 30s)
+    pub deadline: Option<Duration>,
+    /// errors to retry on. (default: refused and timeout)
+    pub retry_on: Vec<RetryOn>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            deadline: Some(Duration::from_secs(30)),
+            retry_on: vec![RetryOn::Refused, RetryOn::Timeout],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after `attempts` failed with `err` in `elapsed`.
+    /// `None` to give up.
+    pub fn next_delay(&self, attempts: u32, elapsed: Duration, err: &Error) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        match RetryOn::of(err) {
+            Some(on) if self.retry_on.contains(&on) => {}
+            _ => return None,
+        }
+        let delay = self.jitter(self.backoff(attempts));
+        match self.deadline {
+            Some(deadline) if elapsed + delay > deadline => None,
+            _ => Some(delay),
+        }
+    }
+
+    /// Backoff after `attempts` failed attempts without jitter
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// Random duration between the half of `backoff` and `backoff`
+    fn jitter(&self, backoff: Duration) -> Duration {
+        let half = backoff / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Address;
+    use crate::socks4;
+    use failure::Fail;
+
+    fn io_error(kind: io::ErrorKind) -> Error {
+        io::Error::new(kind, "test").into()
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            ..RetryPolicy::default()
+        };
+        let backoffs: Vec<_> = (1..=6)
+            .map(|attempts| policy.backoff(attempts).as_millis())
+            .collect();
+        assert_eq!(backoffs, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.backoff(100), Duration::from_millis(1000));
+
+        let refused = io_error(io::ErrorKind::ConnectionRefused);
+        for attempts in 1..10 {
+            let delay = policy
+                .next_delay(attempts, Duration::from_secs(0), &refused)
+                .unwrap();
+            let backoff = policy.backoff(attempts);
+            assert!(backoff / 2 <= delay && delay <= backoff);
+        }
+        assert_eq!(
+            policy.next_delay(10, Duration::from_secs(0), &refused),
+            None
+        );
+        // the deadline passes before the retry
+        assert_eq!(
+            policy.next_delay(1, Duration::from_secs(30), &refused),
+            None
+        );
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            ..RetryPolicy::default()
+        };
+        let proxy: Address = "127.0.0.1:1080".parse().unwrap();
+        let retried = |err: Error| policy.next_delay(1, Duration::from_secs(0), &err).is_some();
+
+        assert!(retried(io_error(io::ErrorKind::ConnectionRefused)));
+        assert!(retried(io_error(io::ErrorKind::TimedOut)));
+        assert!(retried(
+            io_error(io::ErrorKind::WouldBlock)
+                .context(ErrorKind::HandshakeTimeout {
+                    proxy: proxy.clone()
+                })
+                .into()
+        ));
+        // not allowed by ruleset
+        assert!(!retried(io_error(io::ErrorKind::PermissionDenied)));
+        // rejected or failed by SOCKS4 proxies
+        assert!(!retried(
+            Error::from(io::Error::from(socks4::Rejected))
+                .context(ErrorKind::ConnectionRefused {
+                    proxy: proxy.clone(),
+                    addr: "192.0.2.1:554".parse().unwrap()
+                })
+                .into()
+        ));
+        assert!(!retried(ErrorKind::AuthenticationFailed { proxy }.into()));
+        assert!(!retried(io_error(io::ErrorKind::ConnectionReset)));
+        assert!(RetryPolicy {
+            max_attempts: 2,
+            retry_on: vec![RetryOn::Reset],
+            ..RetryPolicy::default()
+        }
+        .next_delay(
+            1,
+            Duration::from_secs(0),
+            &io_error(io::ErrorKind::ConnectionReset)
+        )
+        .is_some());
+    }
+}
@@ -0,0 +1,239 @@
+//! Rules routing destinations directly, through proxies or nowhere
+//!
+//! Rules are tried in order and the first matching one routes the destination.
+//! Destinations matching none of them take the default route.
+//! CIDR patterns match IP addresses only, and domain patterns match names only:
+//! names are not resolved to match rules.
+//! Rules of a client identity apply only to sessions of clients authenticated as it.
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use ipnet::IpNet;
+use regex::Regex;
+
+use crate::model::Address;
+
+/// Where to send destinations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// connect to the destination without proxies
+    Direct,
+    /// go through the proxies of the pipeline
+    Proxy,
+    /// close the client connection
+    Reject,
+}
+
+impl FromStr for Route {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(Route::Direct),
+            "proxy" => Ok(Route::Proxy),
+            "reject" => Ok(Route::Reject),
+            _ => Err(format!("unknown route: {} (direct, proxy or reject)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Route::Direct => write!(f, "direct"),
+            Route::Proxy => write!(f, "proxy"),
+            Route::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// Destinations a rule applies to
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Pattern {
+    /// IP addresses in the network
+    Cidr(IpNet),
+    /// the domain and its subdomains, e.g. `example.com` matches `www.example.com`
+    DomainSuffix(String),
+    /// names matching the regex
+    DomainRegex(Regex),
+    /// every destination
+    Any,
+}
+
+impl Pattern {
+    /// `10.0.0.0/8` or `192.168.0.1` for `Cidr`, a domain name for `DomainSuffix`
+    /// and `*` for `Any`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if s == "*" {
+            return Ok(Pattern::Any);
+        }
+        if let Ok(net) = s.parse() {
+            return Ok(Pattern::Cidr(net));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Pattern::Cidr(ip.into()));
+        }
+        if s.contains('/') {
+            return Err(format!("invalid CIDR: {}", s));
+        }
+        Ok(Pattern::domain_suffix(s))
+    }
+
+    /// Leading and trailing dots are ignored
+    pub fn domain_suffix(domain: &str) -> Self {
+        Pattern::DomainSuffix(domain.trim_matches('.').to_ascii_lowercase())
+    }
+
+    pub fn matches(&self, addr: &Address) -> bool {
+        match (self, addr) {
+            (Pattern::Cidr(net), Address::IpAddr(ip, _)) => net.contains(ip),
+            (Pattern::DomainSuffix(suffix), Address::Domain(domain, _)) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                domain == *suffix || domain.ends_with(&format!(".{}", suffix))
+            }
+            (Pattern::DomainRegex(regex), Address::Domain(domain, _)) => regex.is_match(domain),
+            (Pattern::Any, _) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub pattern: Pattern,
+    pub route: Route,
+    /// identity of clients the rule applies to. (default: every client)
+    pub client: Option<String>,
+}
+
+impl Rule {
+    pub fn new(pattern: Pattern, route: Route) -> Self {
+        Self {
+            pattern,
+            route,
+            client: None,
+        }
+    }
+
+    /// Apply the rule only to clients authenticated as `client`
+    pub fn with_client(self, client: String) -> Self {
+        Self {
+            client: Some(client),
+            ..self
+        }
+    }
+
+    fn matches(&self, client: Option<&str>, addr: &Address) -> bool {
+        let client = match &self.client {
+            Some(identity) => client == Some(identity.as_str()),
+            None => true,
+        };
+        client && self.pattern.matches(addr)
+    }
+}
+
+/// Rules in order and the default route
+#[derive(Debug, Clone)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+    /// route of destinations matching no rules. (default: Proxy)
+    pub default: Route,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            default: Route::Proxy,
+        }
+    }
+}
+
+impl Rules {
+    /// Whether every destination goes through the proxies
+    pub fn is_empty(&self) -> bool {
+        self.default == Route::Proxy && self.rules.iter().all(|rule| rule.route == Route::Proxy)
+    }
+
+    pub fn route(&self, addr: &Address) -> Route {
+        self.route_for(None, addr)
+    }
+
+    /// Route of `addr` for the client of the identity
+    pub fn route_for(&self, client: Option<&str>, addr: &Address) -> Route {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(client, addr))
+            .map_or(self.default, |rule| rule.route)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route() {
+        let rules = Rules {
+            rules: vec![
+                Rule::new(Pattern::parse("192.168.0.0/16").unwrap(), Route::Direct),
+                Rule::new(Pattern::parse(".lan").unwrap(), Route::Direct),
+                Rule::new(
+                    Pattern::DomainRegex(Regex::new(r"^cam[0-9]+\.example\.com$").unwrap()),
+                    Route::Proxy,
+                ),
+                Rule::new(Pattern::parse("2001:db8::1").unwrap(), Route::Proxy),
+            ],
+            default: Route::Reject,
+        };
+        let route = |addr: &str| rules.route(&addr.parse().unwrap());
+        let domain = |name: &str| rules.route(&Address::Domain(name.into(), 554));
+
+        assert_eq!(route("192.168.1.10:554"), Route::Direct);
+        assert_eq!(route("10.0.0.1:554"), Route::Reject);
+        assert_eq!(route("[2001:db8::1]:554"), Route::Proxy);
+        assert_eq!(route("[2001:db8::2]:554"), Route::Reject);
+        assert_eq!(domain("lan"), Route::Direct);
+        assert_eq!(domain("NVR.LAN."), Route::Direct);
+        assert_eq!(domain("nvr.wlan"), Route::Reject);
+        assert_eq!(domain("cam1.example.com"), Route::Proxy);
+        assert_eq!(domain("www.example.com"), Route::Reject);
+        assert!(!rules.is_empty());
+        assert!(Rules::default().is_empty());
+
+        assert!(Pattern::parse("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn route_for_client() {
+        let rules = Rules {
+            rules: vec![
+                Rule::new(Pattern::parse("10.0.0.0/8").unwrap(), Route::Direct)
+                    .with_client("nvr01.example.com".into()),
+                Rule::new(Pattern::parse("*").unwrap(), Route::Proxy)
+                    .with_client("camera01.example.com".into()),
+            ],
+            default: Route::Reject,
+        };
+        let addr = "10.0.0.1:554".parse().unwrap();
+        assert_eq!(rules.route(&addr), Route::Reject);
+        assert_eq!(
+            rules.route_for(Some("nvr01.example.com"), &addr),
+            Route::Direct
+        );
+        assert_eq!(
+            rules.route_for(Some("camera01.example.com"), &addr),
+            Route::Proxy
+        );
+        let domain = Address::Domain("example.net".into(), 443);
+        assert_eq!(
+            rules.route_for(Some("camera01.example.com"), &domain),
+            Route::Proxy
+        );
+        assert_eq!(
+            rules.route_for(Some("nvr01.example.com"), &domain),
+            Route::Reject
+        );
+    }
+}
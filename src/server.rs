@@ -1,7 +1,6 @@
 //! Proxy server main process
 //!
 use std::collections::HashMap;
-use std::net::TcpStream;
 use std::sync::{
     mpsc::{self, Receiver, Sender, SyncSender},
     Arc, Mutex,
@@ -11,15 +10,20 @@ use std::thread;
 use log::*;
 use rand::prelude::*;
 
-use crate::acceptor::{Binder, TcpBinder};
-use crate::byte_stream::ByteStream;
+use crate::acceptor::{
+    Binder, ListenerBinder, ProxyProtocolBinder, TcpBinder, TlsBinder, UnixBinder,
+};
+use crate::byte_stream::{BoxedStream, ByteStream};
 use crate::config::ServerConfig;
-use crate::connector::{Connector, SocksConnector};
+use crate::connector::{Connector, ProxyConnector, UsedProxy};
+use crate::destination::Destinations;
 use crate::error::Error;
-use crate::model::SocketAddr;
+use crate::health::{self, HealthChecker, HealthState};
+use crate::model::SockAddr;
 use crate::server_command::ServerCommand;
 use crate::session::{Session, SessionHandle, SessionId};
 use crate::thread::spawn_thread;
+use crate::tls::TlsClient;
 
 pub struct Server<S, T, C> {
     config: ServerConfig,
@@ -31,21 +35,27 @@ pub struct Server<S, T, C> {
     tx_acceptor_done: SyncSender<()>,
     /// make connection to service host
     connector: C,
+    /// orders destinations of sessions
+    destinations: Destinations,
     session: HashMap<SessionId, SessionHandle>,
     /// random context for generating SessionIds
     id_rng: StdRng,
+    /// health of proxies checked in background
+    health: HealthState,
 }
 
 /// spawn a thread send accepted stream to `tx`
 fn spawn_acceptor<S>(
-    acceptor: impl Iterator<Item = (S, SocketAddr)> + Send + 'static,
+    server_addr: &SockAddr,
+    acceptor: impl Iterator<Item = (S, SockAddr)> + Send + 'static,
     tx: Sender<ServerCommand<S>>,
 ) -> Result<thread::JoinHandle<()>, Error>
 where
     S: ByteStream + 'static,
 {
     use ServerCommand::*;
-    Ok(spawn_thread("acceptor", move || {
+    let name = format!("acceptor: {}", server_addr);
+    Ok(spawn_thread(&name, move || {
         for (strm, addr) in acceptor {
             if tx.send(Connect(strm, addr)).is_err() {
                 info!("disconnected ServerCommand chan");
@@ -64,37 +74,80 @@ where
 ///   Sender of session termination message.
 /// - *addr*
 ///   Address of the client connects to this server.
+/// - *proxy*
+///   First proxy the session goes through, the assigned one until connected.
 /// - *strm*
 ///   Established connection between a client and this server.
 fn spawn_session<S, D>(
     session: Session<D, S>,
     tx: SyncSender<()>,
-    addr: SocketAddr,
+    addr: SockAddr,
+    proxy: UsedProxy,
     strm: S,
 ) -> SessionHandle
 where
     S: ByteStream + 'static,
     D: Connector + 'static,
 {
-    let session_th = spawn_thread(&format!("{}: {}", session.id, addr), move || {
-        session.start(addr, strm)
-    })
-    .unwrap();
-    SessionHandle::new(addr, session_th, tx)
+    let session_th = {
+        let addr = addr.clone();
+        spawn_thread(&format!("{}: {}", session.id, addr), move || {
+            session.start(addr, strm)
+        })
+        .unwrap()
+    };
+    SessionHandle::new(addr, proxy, session_th, tx)
 }
 
-impl Server<TcpStream, TcpBinder, SocksConnector> {
-    pub fn new(config: ServerConfig) -> (Self, mpsc::Sender<ServerCommand<TcpStream>>) {
-        let (tx_done, rx_done) = mpsc::sync_channel(1);
-        Server::<TcpStream, TcpBinder, SocksConnector>::with_binder(
-            config.clone(),
+impl Server<BoxedStream<'static>, ListenerBinder, ProxyConnector> {
+    pub fn new(config: ServerConfig) -> (Self, mpsc::Sender<ServerCommand<BoxedStream<'static>>>) {
+        // each acceptor consumes one termination message
+        let (tx_done, rx_done) = mpsc::sync_channel(config.server_addrs.len());
+        let rx_done = Arc::new(Mutex::new(rx_done));
+        let tcp_binder = || {
             TcpBinder::new(
                 config.client_rw_timeout,
-                Arc::new(Mutex::new(rx_done)),
+                rx_done.clone(),
                 config.accept_timeout,
+                config.v6_only,
+            )
+        };
+        let mut binder = ListenerBinder::new(
+            tcp_binder(),
+            UnixBinder::new(
+                config.client_rw_timeout,
+                rx_done.clone(),
+                config.accept_timeout,
+                config.unix_socket.clone(),
             ),
-            tx_done,
-            SocksConnector::new(config.proxy_addr, config.server_rw_timeout),
+        );
+        if let Some(options) = &config.proxy_protocol {
+            binder =
+                binder.with_proxy_protocol(ProxyProtocolBinder::new(tcp_binder(), options.clone()));
+        }
+        if !config.tls.addrs.is_empty() {
+            let mut tls = TlsBinder::new(tcp_binder(), config.tls.clone());
+            if let Some(options) = &config.proxy_protocol {
+                tls = tls
+                    .with_proxy_protocol(ProxyProtocolBinder::new(tcp_binder(), options.clone()));
+            }
+            binder = binder.with_tls(tls);
+        }
+        let (server, tx) =
+            Server::<BoxedStream<'static>, ListenerBinder, ProxyConnector>::with_binder(
+                config.clone(),
+                binder,
+                tx_done,
+                ProxyConnector::new(&config),
+            );
+        // skip proxies marked down by the health checker
+        let connector = server.connector.clone().with_health(server.health());
+        (
+            Self {
+                connector,
+                ..server
+            },
+            tx,
         )
     }
 }
@@ -112,6 +165,7 @@ where
         connector: C,
     ) -> (Self, Sender<ServerCommand<S>>) {
         let (tx, rx) = mpsc::channel();
+        let destinations = Destinations::new(config.dst_addrs.clone(), config.dst_order);
         (
             Self {
                 config,
@@ -120,13 +174,20 @@ where
                 binder,
                 tx_acceptor_done,
                 connector,
+                destinations,
                 session: HashMap::new(),
                 id_rng: StdRng::from_entropy(),
+                health: HealthState::new(),
             },
             tx,
         )
     }
 
+    /// Health of proxies, which is updated while serving with health checks enabled
+    pub fn health(&self) -> HealthState {
+        self.health.clone()
+    }
+
     fn next_session_id(&mut self) -> SessionId {
         loop {
             let next_candidate = self.id_rng.next_u32().into();
@@ -140,34 +201,69 @@ where
 
     /// Server main loop
     pub fn serve(&mut self) -> Result<(), Error> {
-        let acceptor = self.binder.bind(self.config.server_addr)?;
-        let accept_th = spawn_acceptor(acceptor, self.tx_cmd.clone())?;
+        let dst_tls = match &self.config.dst_tls {
+            Some(options) => Some(Arc::new(TlsClient::new(options)?)),
+            None => None,
+        };
+        // bind all addresses before accepting anything, so that a bad address fails fast.
+        let acceptors = self
+            .config
+            .server_addrs
+            .iter()
+            .map(|addr| Ok((addr, self.binder.bind(addr.clone())?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let accept_ths = acceptors
+            .into_iter()
+            .map(|(addr, acceptor)| spawn_acceptor(addr, acceptor, self.tx_cmd.clone()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let health_check = HealthChecker::start(&self.config, self.health.clone())?;
 
         while let Ok(cmd) = self.rx_cmd.recv() {
             use ServerCommand::*;
             info!("cmd: {:?}", cmd);
             match cmd {
                 Terminate => {
-                    self.tx_acceptor_done.send(()).ok();
+                    accept_ths.iter().for_each(|_| {
+                        self.tx_acceptor_done.send(()).ok();
+                    });
                     self.session.iter().for_each(|(_, ss)| ss.stop());
 
                     self.session.drain().for_each(|(_, ss)| {
                         ss.join().ok();
                     });
-                    debug!("join accept thread");
-                    accept_th.join().ok();
+                    debug!("join accept threads");
+                    accept_ths.into_iter().for_each(|th| {
+                        th.join().ok();
+                    });
+                    if let Some(health_check) = health_check {
+                        health_check.stop();
+                    }
                     break;
                 }
                 Connect(stream, addr) => {
+                    let (mut connector, proxy) = self.connector.assign(
+                        &addr,
+                        &health::load(self.session.values().map(SessionHandle::proxy)),
+                    );
+                    let identity = stream.peer_identity();
+                    if let Some(identity) = &identity {
+                        connector = connector.identify(identity);
+                    }
                     let (session, tx) = Session::new(
                         self.next_session_id(),
-                        self.connector.clone(),
-                        self.config.server_addr,
-                        self.config.dst_addr.clone(),
+                        connector,
+                        self.destinations.next(),
                         self.tx_cmd.clone(),
                     );
+                    let mut session = session.with_retry(self.config.retry.clone());
+                    if let Some(tls) = &dst_tls {
+                        session = session.with_tls(tls.clone());
+                    }
+                    if let Some(identity) = &identity {
+                        info!("client identity: {}: {}: {}", session.id, addr, identity);
+                    }
                     self.session
-                        .insert(session.id, spawn_session(session, tx, addr, stream));
+                        .insert(session.id, spawn_session(session, tx, addr, proxy, stream));
                 }
                 Disconnect(id) => {
                     if let Some(session) = self.session.remove(&id) {
@@ -196,13 +292,21 @@ mod test {
     use crate::byte_stream::test::*;
     use crate::config::*;
     use crate::connector::*;
-    use crate::model;
+    use crate::model::{self, ProxyProtocol, SocketAddr};
 
     use std::borrow::Cow;
     use std::ops::Deref;
     use std::sync::{Arc, Mutex};
     use std::time::{Duration, SystemTime};
 
+    use crate::resolver::{IpPreference, Resolver};
+
+    fn dummy_connector() -> SocksConnector {
+        let resolver = Resolver::new(Duration::from_secs(0), IpPreference::System);
+        let dialer = Dialer::new(Timeouts::default(), resolver);
+        SocksConnector::new("0.0.0.0:1080".parse().unwrap(), None, dialer)
+    }
+
     #[test]
     fn server_shutdown() {
         let config = ServerConfig::default();
@@ -214,9 +318,10 @@ mod test {
                 None,
                 Arc::new(Mutex::new(rx_done)),
                 Some(Duration::from_secs(3)),
+                None,
             ),
             tx_done,
-            SocksConnector::new("0.0.0.0:1080".parse().unwrap(), None),
+            dummy_connector(),
         );
         let req_shutdown = Arc::new(Mutex::new(SystemTime::now()));
 
@@ -242,10 +347,10 @@ mod test {
 
     impl Binder for DummyBinder {
         type Stream = BufferStream;
-        type Iter = std::iter::Once<(Self::Stream, SocketAddr)>;
-        fn bind(&self, addr: SocketAddr) -> Result<Self::Iter, model::Error> {
+        type Iter = std::iter::Once<(Self::Stream, SockAddr)>;
+        fn bind(&self, addr: SockAddr) -> Result<Self::Iter, model::Error> {
             println!("bind: {}", addr);
-            Ok(std::iter::once((self.stream.clone(), self.src_addr)))
+            Ok(std::iter::once((self.stream.clone(), self.src_addr.into())))
         }
     }
 
@@ -267,7 +372,7 @@ mod test {
                     ServerConfig::default(),
                     binder,
                     tx_done,
-                    SocksConnector::new("0.0.0.0:1080".parse().unwrap(), None),
+                    dummy_connector(),
                 );
                 *tx.lock().unwrap() = Some(stx);
                 server.serve().ok();
@@ -283,4 +388,87 @@ mod test {
             .unwrap();
         th.join().unwrap();
     }
+
+    /// Yields `count` clients at once
+    struct BurstBinder {
+        count: u16,
+    }
+
+    impl Binder for BurstBinder {
+        type Stream = BufferStream;
+        type Iter = std::vec::IntoIter<(Self::Stream, SockAddr)>;
+        fn bind(&self, _addr: SockAddr) -> Result<Self::Iter, model::Error> {
+            let clients: Vec<_> = (0..self.count)
+                .map(|i| {
+                    let addr = SocketAddr::from(([127, 0, 0, 1], 50000 + i));
+                    (BufferStream::new(), addr.into())
+                })
+                .collect();
+            Ok(clients.into_iter())
+        }
+    }
+
+    #[test]
+    fn least_sessions_while_connecting() {
+        use crate::balancer::BalanceStrategy;
+        use std::net::TcpListener;
+
+        // proxies accept connections and never reply
+        let (tx_accepted, rx_accepted) = mpsc::channel();
+        let proxies: Vec<SocketAddr> = (0..2)
+            .map(|_| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+                let tx_accepted = tx_accepted.clone();
+                thread::spawn(move || {
+                    let mut strms = vec![];
+                    for strm in listener.incoming() {
+                        strms.push(strm.unwrap());
+                        if tx_accepted.send(addr).is_err() {
+                            break;
+                        }
+                    }
+                });
+                addr
+            })
+            .collect();
+        let hop = proxies
+            .iter()
+            .map(|addr| ProxyConfig::new(ProxyProtocol::Socks5h, *addr))
+            .collect();
+        let timeout = Some(Duration::from_secs(1));
+        let timeouts = Timeouts {
+            connect: timeout,
+            handshake: timeout,
+            rw: timeout,
+        };
+        let resolver = Resolver::new(Duration::from_secs(0), IpPreference::System);
+        let connector = FailoverConnector::new(
+            vec![hop],
+            Duration::from_secs(60),
+            Dialer::new(timeouts, resolver),
+        )
+        .with_strategy(BalanceStrategy::LeastSessions);
+
+        let (tx_done, _rx_done) = mpsc::sync_channel(1);
+        let (mut server, tx) = Server::with_binder(
+            ServerConfig::default(),
+            BurstBinder { count: 4 },
+            tx_done,
+            connector,
+        );
+        let th = thread::spawn(move || server.serve().ok());
+
+        // sessions are counted against the assigned proxies before they connect
+        let mut accepted = HashMap::new();
+        for _ in 0..4 {
+            let addr = rx_accepted.recv_timeout(Duration::from_secs(3)).unwrap();
+            *accepted.entry(addr).or_insert(0) += 1;
+        }
+        assert_eq!(accepted[&proxies[0]], 2);
+        assert_eq!(accepted[&proxies[1]], 2);
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        th.join().unwrap();
+    }
 }
@@ -1,7 +1,8 @@
 ///! Server control command
 ///!
 use std::fmt;
-use std::net::SocketAddr;
+
+use crate::model::SockAddr;
 
 use crate::session::SessionId;
 
@@ -9,7 +10,7 @@ pub enum ServerCommand<T> {
     /// terminate
     Terminate,
     /// connected stream and client address
-    Connect(T, SocketAddr),
+    Connect(T, SockAddr),
     Disconnect(SessionId),
 }
 
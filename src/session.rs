@@ -1,16 +1,23 @@
 use std::fmt;
-use std::sync::mpsc::{self, SyncSender};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 
 use log::*;
 
 use crate::byte_stream::ByteStream;
-use crate::connector::Connector;
+use crate::connector::{Connector, UsedProxy};
+use crate::destination::is_unreachable;
 use crate::model::model::*;
 use crate::model::Error;
 use crate::relay::{self, RelayHandle};
+use crate::retry::RetryPolicy;
 use crate::server_command::ServerCommand;
+use crate::tls::TlsClient;
+
+/// Connected stream, proxy address, address bound by the proxy and the destination
+type Connection<'a, B> = (B, SocketAddr, Option<Address>, &'a Address);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SessionId(pub u32);
@@ -30,7 +37,9 @@ impl fmt::Display for SessionId {
 #[derive(Debug)]
 pub struct SessionHandle {
     /// client address
-    addr: SocketAddr,
+    addr: SockAddr,
+    /// first proxy of the session
+    proxy: UsedProxy,
     /// thread performs relay bytes
     handle: thread::JoinHandle<Result<RelayHandle, Error>>,
     /// Sender to send termination messages to relay threads
@@ -39,15 +48,25 @@ pub struct SessionHandle {
 
 impl SessionHandle {
     pub fn new(
-        addr: SocketAddr,
+        addr: SockAddr,
+        proxy: UsedProxy,
         handle: thread::JoinHandle<Result<RelayHandle, Error>>,
         tx: SyncSender<()>,
     ) -> Self {
-        Self { addr, handle, tx }
+        Self {
+            addr,
+            proxy,
+            handle,
+            tx,
+        }
+    }
+
+    pub fn client_addr(&self) -> SockAddr {
+        self.addr.clone()
     }
 
-    pub fn client_addr(&self) -> SocketAddr {
-        self.addr
+    pub fn proxy(&self) -> &UsedProxy {
+        &self.proxy
     }
 
     pub fn stop(&self) {
@@ -73,8 +92,11 @@ impl SessionHandle {
 pub struct Session<D, S> {
     pub id: SessionId,
     pub dst_connector: D,
-    pub server_addr: SocketAddr,
-    pub dst_addr: Address,
+    /// destinations in the order to try
+    pub dst_addrs: Vec<Address>,
+    retry: RetryPolicy,
+    /// TLS to the destination over the proxied stream
+    tls: Option<Arc<TlsClient>>,
     /// termination message receiver
     rx: Arc<Mutex<mpsc::Receiver<()>>>,
     /// Send `Disconnect` command to the main thread.
@@ -91,8 +113,7 @@ where
     pub fn new(
         id: SessionId,
         dst_connector: D,
-        server_addr: SocketAddr,
-        dst_addr: Address,
+        dst_addrs: Vec<Address>,
         tx_cmd: mpsc::Sender<ServerCommand<S>>,
     ) -> (Self, mpsc::SyncSender<()>) {
         let (tx, rx) = mpsc::sync_channel(2);
@@ -100,8 +121,9 @@ where
             Self {
                 id,
                 dst_connector,
-                server_addr,
-                dst_addr,
+                dst_addrs,
+                retry: RetryPolicy::default(),
+                tls: None,
                 rx: Arc::new(Mutex::new(rx)),
                 guard: Arc::new(Mutex::new(DisconnectGuard::new(id, tx_cmd))),
             },
@@ -109,23 +131,82 @@ where
         )
     }
 
+    /// Retry connections to the destination by `retry`
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// Start TLS with the destination after connecting through the proxy
+    pub fn with_tls(self, tls: Arc<TlsClient>) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
+        }
+    }
+
+    /// Connect to one of the destinations. Returns the connected destination too.
+    fn connect_any(&self) -> Result<Connection<'_, D::B>, Error> {
+        let mut last_err = None;
+        for dst_addr in &self.dst_addrs {
+            match self.dst_connector.connect_byte_stream(dst_addr.clone()) {
+                Ok((strm, proxy_addr, bound_addr)) => {
+                    return Ok((strm, proxy_addr, bound_addr, dst_addr))
+                }
+                Err(err) if is_unreachable(&err) => {
+                    warn!("destination unreachable: {}", err);
+                    last_err = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_err.expect("at least one destination"))
+    }
+
+    /// Connect to the destinations, retrying while the client waits
+    fn connect(&self) -> Result<Connection<'_, D::B>, Error> {
+        let start = Instant::now();
+        let mut attempts = 1;
+        loop {
+            let err = match self.connect_any() {
+                Ok(conn) => return Ok(conn),
+                Err(err) => err,
+            };
+            let delay = match self.retry.next_delay(attempts, start.elapsed(), &err) {
+                Some(delay) => delay,
+                None => return Err(err),
+            };
+            warn!(
+                "connect error: retry in {:?}: attempt {}: {}",
+                delay, attempts, err
+            );
+            // the session may be stopped while waiting
+            match self.rx.lock()?.recv_timeout(delay) {
+                Err(RecvTimeoutError::Timeout) => attempts += 1,
+                _ => return Err(err),
+            }
+        }
+    }
+
     fn make_session<'a>(
         &self,
-        src_addr: SocketAddr,
+        src_addr: SockAddr,
         src_conn: impl ByteStream + 'a,
     ) -> Result<RelayHandle, Error> {
-        info!("connect new client: dst_addr = {}", self.dst_addr);
-
-        let (strm, proxy_addr) = match self
-            .dst_connector
-            .connect_byte_stream(self.dst_addr.clone())
-        {
-            Ok((strm, proxy_addr)) => {
-                info!(
-                    "connected: proxy_addr = {}, dst_addr = {}",
-                    proxy_addr, self.dst_addr
-                );
-                (strm, proxy_addr)
+        info!("connect new client: dst_addr = {}", self.dst_addrs[0]);
+
+        let (strm, proxy_addr, dst_addr) = match self.connect() {
+            Ok((strm, proxy_addr, bound_addr, dst_addr)) => {
+                match bound_addr {
+                    Some(bound_addr) => info!(
+                        "connected: proxy_addr = {}, bound_addr = {}, dst_addr = {}",
+                        proxy_addr, bound_addr, dst_addr
+                    ),
+                    None => info!(
+                        "connected: proxy_addr = {}, dst_addr = {}",
+                        proxy_addr, dst_addr
+                    ),
+                }
+                (strm, proxy_addr, dst_addr)
             }
             Err(err) => {
                 error!("connect error: {}", err);
@@ -134,6 +215,27 @@ where
             }
         };
 
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => {
+                return relay::spawn_relay(
+                    src_addr,
+                    proxy_addr,
+                    Box::new(src_conn),
+                    strm,
+                    self.rx.clone(),
+                    self.guard.clone(),
+                )
+            }
+        };
+        let strm = match tls.connect(dst_addr, strm) {
+            Ok(strm) => strm,
+            Err(err) => {
+                error!("tls error: {}", err);
+                return Err(err);
+            }
+        };
+        info!("tls started: dst_addr = {}", dst_addr);
         relay::spawn_relay(
             src_addr,
             proxy_addr,
@@ -146,7 +248,7 @@ where
 
     pub fn start<'a>(
         self,
-        src_addr: SocketAddr,
+        src_addr: SockAddr,
         src_conn: impl ByteStream + 'a,
     ) -> Result<RelayHandle, Error> {
         self.make_session(src_addr, src_conn)
@@ -171,3 +273,64 @@ impl<S> Drop for DisconnectGuard<S> {
         self.tx.send(ServerCommand::Disconnect(self.id)).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyConfig;
+    use crate::connector::{Dialer, DirectConnector, FailoverConnector, Timeouts};
+    use crate::resolver::{IpPreference, Resolver};
+    use crate::socks5;
+    use crate::test::servers::{spawn_echo_server, MockProxy};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn destination_failover_before_proxy_failover() {
+        let first = MockProxy::new().spawn();
+        let second = MockProxy::new().spawn();
+        let hop = vec![
+            ProxyConfig::new(ProxyProtocol::Socks5h, first.addr),
+            ProxyConfig::new(ProxyProtocol::Socks5h, second.addr),
+        ];
+        let resolver = Resolver::new(Duration::from_secs(0), IpPreference::System);
+        let dialer = Dialer::new(Timeouts::default(), resolver);
+        let connector = FailoverConnector::new(vec![hop], Duration::from_secs(60), dialer);
+        // nothing listens on the port of the dropped listener, so the proxy replies refused
+        let refusing = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let working = spawn_echo_server();
+
+        let (tx_cmd, _rx_cmd) = mpsc::channel::<ServerCommand<()>>();
+        let dst_addrs = vec![refusing.into(), working.into()];
+        let (session, _tx) = Session::new(SessionId(0), connector.clone(), dst_addrs, tx_cmd);
+        let (_strm, proxy_addr, _, dst_addr) = session.connect_any().unwrap();
+        assert_eq!(proxy_addr, first.addr);
+        assert_eq!(dst_addr, &Address::from(working));
+        assert_eq!(first.request(), (socks5::CMD_CONNECT, refusing.into()));
+        assert_eq!(first.request(), (socks5::CMD_CONNECT, working.into()));
+        assert!(!connector.cooling_down(&first.addr.into()));
+        assert!(!connector.cooling_down(&second.addr.into()));
+    }
+
+    #[test]
+    fn direct_destination_failover() {
+        let resolver = Resolver::new(Duration::from_secs(0), IpPreference::System);
+        let connector = DirectConnector::new(Dialer::new(Timeouts::default(), resolver));
+        // nothing listens on the port of the dropped listener
+        let refusing = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let working = spawn_echo_server();
+
+        let (tx_cmd, _rx_cmd) = mpsc::channel::<ServerCommand<()>>();
+        let dst_addrs = vec![refusing.into(), working.into()];
+        let (session, _tx) = Session::new(SessionId(0), connector, dst_addrs, tx_cmd);
+        let (_strm, dst_sock, _, dst_addr) = session.connect_any().unwrap();
+        assert_eq!(dst_sock, working);
+        assert_eq!(dst_addr, &Address::from(working));
+    }
+}
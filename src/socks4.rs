@@ -0,0 +1,155 @@
+//! SOCKS4 protocol and SOCKS4a extension
+//!
+//! Rejections for identd are reported as `io::ErrorKind::PermissionDenied`.
+//! Rejections of requests are also reported as `io::ErrorKind::PermissionDenied`,
+//! with `Rejected` as the inner error.
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+
+use crate::model::Address;
+
+const VERSION: u8 = 4;
+const CMD_CONNECT: u8 = 1;
+
+/// version of replies
+const REPLY_VERSION: u8 = 0;
+const REPLY_GRANTED: u8 = 90;
+const REPLY_REJECTED: u8 = 91;
+const REPLY_NO_IDENTD: u8 = 92;
+const REPLY_USERID_MISMATCH: u8 = 93;
+
+/// Reply 91, which does not tell whether the request was rejected by rules or failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rejected;
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request rejected or failed")
+    }
+}
+
+impl error::Error for Rejected {}
+
+impl From<Rejected> for io::Error {
+    fn from(rejected: Rejected) -> Self {
+        // may be a rejection by rulesets, which is not worth retrying
+        io::Error::new(io::ErrorKind::PermissionDenied, rejected)
+    }
+}
+
+/// Whether `err` is a rejection of the request
+pub fn is_rejected(err: &io::Error) -> bool {
+    err.get_ref()
+        .map_or(false, |err| err.downcast_ref::<Rejected>().is_some())
+}
+
+/// Send CONNECT request to `addr` and wait for the reply
+///
+/// Domain names are sent by SOCKS4a. IPv6 addresses are not supported.
+pub fn connect<S: Read + Write>(strm: &mut S, addr: &Address, userid: &str) -> io::Result<()> {
+    if userid.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid userid",
+        ));
+    }
+    let mut req = vec![VERSION, CMD_CONNECT];
+    req.extend_from_slice(&addr.port().to_be_bytes());
+    match addr {
+        Address::IpAddr(IpAddr::V4(ip), _) => {
+            req.extend_from_slice(&ip.octets());
+            req.extend_from_slice(userid.as_bytes());
+            req.push(0);
+        }
+        Address::IpAddr(IpAddr::V6(_), _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SOCKS4 does not support IPv6",
+            ));
+        }
+        Address::Domain(domain, _) => {
+            if domain.contains('\0') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid domain name",
+                ));
+            }
+            // 0.0.0.x tells the proxy to resolve the domain name
+            req.extend_from_slice(&[0, 0, 0, 1]);
+            req.extend_from_slice(userid.as_bytes());
+            req.push(0);
+            req.extend_from_slice(domain.as_bytes());
+            req.push(0);
+        }
+    }
+    strm.write_all(&req)?;
+
+    // VN CD DSTPORT DSTIP
+    let mut reply = [0; 8];
+    strm.read_exact(&mut reply)?;
+    if reply[0] != REPLY_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response version",
+        ));
+    }
+    match reply[1] {
+        REPLY_GRANTED => Ok(()),
+        REPLY_REJECTED => Err(Rejected.into()),
+        REPLY_NO_IDENTD => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "identd is not reachable",
+        )),
+        REPLY_USERID_MISMATCH => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "userid is not confirmed by identd",
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown reply code",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::byte_stream::test::BufferStream;
+    use std::borrow::Cow;
+
+    fn request(addr: Address, reply_code: u8) -> (io::Result<()>, Vec<u8>) {
+        let rd = vec![REPLY_VERSION, reply_code, 0, 0, 0, 0, 0, 0];
+        let mut strm = BufferStream::with_buffer(Cow::from(rd), Cow::from(vec![]));
+        let result = connect(&mut strm, &addr, "user");
+        let req = strm.wr_buff().get_ref().clone();
+        (result, req)
+    }
+
+    #[test]
+    fn connect_ipv4() {
+        let (result, req) = request("192.0.2.1:554".parse().unwrap(), REPLY_GRANTED);
+        result.unwrap();
+        assert_eq!(req, b"\x04\x01\x02\x2a\xc0\x00\x02\x01user\x00");
+    }
+
+    #[test]
+    fn connect_domain() {
+        let (result, req) = request(Address::Domain("camera".into(), 554), REPLY_GRANTED);
+        result.unwrap();
+        assert_eq!(req, b"\x04\x01\x02\x2a\x00\x00\x00\x01user\x00camera\x00");
+    }
+
+    #[test]
+    fn rejected() {
+        let (result, _) = request("192.0.2.1:554".parse().unwrap(), REPLY_REJECTED);
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(is_rejected(&err));
+        let (result, _) = request("192.0.2.1:554".parse().unwrap(), REPLY_USERID_MISMATCH);
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!is_rejected(&err));
+    }
+}
@@ -0,0 +1,349 @@
+//! SOCKS5 protocol (RFC 1928, RFC 1929)
+//!
+//! Handshake functions work on any byte stream.
+//! Authentication failures are reported as `io::ErrorKind::PermissionDenied`.
+//! Failure replies are reported with `Reply` as the inner error.
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::model::{Address, Credentials, UdpDatagram};
+
+const VERSION: u8 = 5;
+/// version of username/password authentication subnegotiation
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const METHOD_NO_ACCEPTABLE: u8 = 0xff;
+
+pub const CMD_CONNECT: u8 = 1;
+pub const CMD_UDP_ASSOCIATE: u8 = 3;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Failure reply codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    GeneralFailure,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+    Unassigned(u8),
+}
+
+impl Reply {
+    /// `None` for succeeded
+    pub fn from_code(code: u8) -> Option<Self> {
+        let reply = match code {
+            0 => return None,
+            1 => Reply::GeneralFailure,
+            2 => Reply::NotAllowed,
+            3 => Reply::NetworkUnreachable,
+            4 => Reply::HostUnreachable,
+            5 => Reply::ConnectionRefused,
+            6 => Reply::TtlExpired,
+            7 => Reply::CommandNotSupported,
+            8 => Reply::AddressTypeNotSupported,
+            code => Reply::Unassigned(code),
+        };
+        Some(reply)
+    }
+
+    pub fn io_error_kind(self) -> io::ErrorKind {
+        match self {
+            Reply::NotAllowed => io::ErrorKind::PermissionDenied,
+            Reply::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reply::GeneralFailure => write!(f, "general SOCKS server failure"),
+            Reply::NotAllowed => write!(f, "connection not allowed by ruleset"),
+            Reply::NetworkUnreachable => write!(f, "network unreachable"),
+            Reply::HostUnreachable => write!(f, "host unreachable"),
+            Reply::ConnectionRefused => write!(f, "connection refused"),
+            Reply::TtlExpired => write!(f, "TTL expired"),
+            Reply::CommandNotSupported => write!(f, "command not supported"),
+            Reply::AddressTypeNotSupported => write!(f, "address type not supported"),
+            Reply::Unassigned(code) => write!(f, "unassigned reply: {}", code),
+        }
+    }
+}
+
+impl error::Error for Reply {}
+
+impl From<Reply> for io::Error {
+    fn from(reply: Reply) -> Self {
+        io::Error::new(reply.io_error_kind(), reply)
+    }
+}
+
+/// Failure reply carried by `err`
+pub fn reply_of(err: &io::Error) -> Option<Reply> {
+    err.get_ref()?.downcast_ref::<Reply>().copied()
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Negotiate authentication method and authenticate
+pub fn negotiate<S: Read + Write>(strm: &mut S, auth: Option<&Credentials>) -> io::Result<()> {
+    let methods: &[u8] = if auth.is_some() {
+        &[METHOD_NO_AUTH, METHOD_PASSWORD]
+    } else {
+        &[METHOD_NO_AUTH]
+    };
+    let mut greeting = vec![VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    strm.write_all(&greeting)?;
+
+    let mut buf = [0; 2];
+    strm.read_exact(&mut buf)?;
+    if buf[0] != VERSION {
+        return Err(invalid_data("invalid response version"));
+    }
+    match (buf[1], auth) {
+        (METHOD_NO_AUTH, _) => Ok(()),
+        (METHOD_PASSWORD, Some(auth)) => authenticate(strm, auth),
+        (METHOD_NO_ACCEPTABLE, _) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "no acceptable auth methods",
+        )),
+        _ => Err(invalid_data("unknown auth method")),
+    }
+}
+
+/// Username/password authentication (RFC 1929)
+fn authenticate<S: Read + Write>(strm: &mut S, auth: &Credentials) -> io::Result<()> {
+    let username = auth.username.as_bytes();
+    let password = auth.password.as_bytes();
+    if username.is_empty() || username.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid username",
+        ));
+    }
+    if password.is_empty() || password.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid password",
+        ));
+    }
+
+    let mut req = vec![AUTH_VERSION, username.len() as u8];
+    req.extend_from_slice(username);
+    req.push(password.len() as u8);
+    req.extend_from_slice(password);
+    strm.write_all(&req)?;
+
+    let mut buf = [0; 2];
+    strm.read_exact(&mut buf)?;
+    if buf[0] != AUTH_VERSION {
+        return Err(invalid_data("invalid response version"));
+    }
+    if buf[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "password authentication failed",
+        ));
+    }
+    Ok(())
+}
+
+/// Send a request and returns the bound address in the reply
+pub fn request<S: Read + Write>(strm: &mut S, cmd: u8, addr: &Address) -> io::Result<Address> {
+    let mut req = vec![VERSION, cmd, 0];
+    write_addr(&mut req, addr)?;
+    strm.write_all(&req)?;
+
+    let mut buf = [0; 3];
+    strm.read_exact(&mut buf)?;
+    if buf[0] != VERSION {
+        return Err(invalid_data("invalid response version"));
+    }
+    match Reply::from_code(buf[1]) {
+        None => read_addr(strm),
+        Some(reply) => Err(reply.into()),
+    }
+}
+
+/// Append ATYP, ADDR and PORT fields
+fn write_addr(buf: &mut Vec<u8>, addr: &Address) -> io::Result<()> {
+    match addr {
+        Address::IpAddr(IpAddr::V4(ip), _) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Address::IpAddr(IpAddr::V6(ip), _) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Address::Domain(domain, _) => {
+            if domain.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain name too long",
+                ));
+            }
+            buf.push(ATYP_DOMAIN);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    Ok(())
+}
+
+/// Read ATYP, ADDR and PORT fields
+fn read_addr<R: Read>(rd: &mut R) -> io::Result<Address> {
+    let mut atyp = [0; 1];
+    rd.read_exact(&mut atyp)?;
+    let ip = match atyp[0] {
+        ATYP_IPV4 => {
+            let mut octets = [0; 4];
+            rd.read_exact(&mut octets)?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0; 16];
+            rd.read_exact(&mut octets)?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            rd.read_exact(&mut len)?;
+            let mut domain = vec![0; len[0] as usize];
+            rd.read_exact(&mut domain)?;
+            let domain =
+                String::from_utf8(domain).map_err(|_| invalid_data("invalid domain name"))?;
+            return Ok(Address::Domain(domain, read_port(rd)?));
+        }
+        _ => return Err(invalid_data("unsupported address type")),
+    };
+    Ok(Address::IpAddr(ip, read_port(rd)?))
+}
+
+fn read_port<R: Read>(rd: &mut R) -> io::Result<u16> {
+    let mut port = [0; 2];
+    rd.read_exact(&mut port)?;
+    Ok(u16::from_be_bytes(port))
+}
+
+/// Size of the UDP request header for `addr`
+pub fn udp_header_len(addr: &Address) -> usize {
+    let addr_len = match addr {
+        Address::IpAddr(IpAddr::V4(_), _) => 4,
+        Address::IpAddr(IpAddr::V6(_), _) => 16,
+        Address::Domain(domain, _) => 1 + domain.len(),
+    };
+    // RSV FRAG ATYP DST.ADDR DST.PORT
+    2 + 1 + 1 + addr_len + 2
+}
+
+impl<'a> UdpDatagram<'a> {
+    /// Decapsulate UDP request header
+    pub fn parse(pkt: &'a [u8]) -> io::Result<Self> {
+        if pkt.len() < 3 {
+            return Err(invalid_data("too short udp datagram"));
+        }
+        if pkt[0..2] != [0, 0] {
+            return Err(invalid_data("invalid reserved bytes"));
+        }
+        let frag = pkt[2];
+        let mut rd = &pkt[3..];
+        let dst_addr = read_addr(&mut rd)?;
+        Ok(Self {
+            frag,
+            dst_addr,
+            data: rd,
+        })
+    }
+
+    /// Encapsulate data with UDP request header
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut pkt = Vec::with_capacity(udp_header_len(&self.dst_addr) + self.data.len());
+        pkt.extend_from_slice(&[0, 0, self.frag]);
+        write_addr(&mut pkt, &self.dst_addr)?;
+        pkt.extend_from_slice(self.data);
+        Ok(pkt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_datagram() {
+        let addrs: Vec<Address> = vec![
+            "192.168.0.1:53".parse().unwrap(),
+            "[2001:db8::1]:53".parse().unwrap(),
+            Address::Domain("example.com".into(), 53),
+        ];
+        for addr in addrs {
+            let dgram = UdpDatagram {
+                frag: 0,
+                dst_addr: addr.clone(),
+                data: b"hello",
+            };
+            let pkt = dgram.to_bytes().unwrap();
+            assert_eq!(pkt.len(), udp_header_len(&addr) + 5);
+            assert_eq!(UdpDatagram::parse(&pkt).unwrap(), dgram);
+        }
+
+        assert_eq!(
+            UdpDatagram::parse(&[0, 0, 0, ATYP_IPV4, 127, 0])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            UdpDatagram::parse(&[0, 1, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 53])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn negotiate_password() {
+        use crate::byte_stream::test::BufferStream;
+        use std::borrow::Cow;
+
+        // method selection, authentication status, reply
+        let rd = [
+            &[VERSION, METHOD_PASSWORD][..],
+            &[AUTH_VERSION, 0],
+            &[VERSION, 0, 0, ATYP_IPV4, 10, 0, 0, 1, 0x1f, 0x90],
+        ]
+        .concat();
+        let mut strm = BufferStream::with_buffer(Cow::from(rd), Cow::from(vec![]));
+        negotiate(&mut strm, Some(&Credentials::new("user", "pass"))).unwrap();
+        let bound = request(&mut strm, CMD_CONNECT, &"127.0.0.1:80".parse().unwrap()).unwrap();
+        assert_eq!(bound, "10.0.0.1:8080".parse().unwrap());
+
+        let expected = [
+            &[VERSION, 2, METHOD_NO_AUTH, METHOD_PASSWORD][..],
+            &[AUTH_VERSION, 4],
+            b"user",
+            &[4],
+            b"pass",
+            &[VERSION, CMD_CONNECT, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80],
+        ]
+        .concat();
+        assert_eq!(strm.wr_buff().get_ref(), &expected);
+    }
+}
@@ -2,7 +2,7 @@ use std::convert::TryInto;
 use std::io;
 use std::mem;
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::time::Duration;
 
 use nix::sys::time::{TimeVal, TimeValLike};
@@ -17,21 +17,8 @@ impl TcpListenerExt for TcpListener {
     /// * `timeout`
     ///   Timeout for _accept_. If the value is `None`, wait connection indefinitely.
     fn accept_timeout(&self, timeout: Option<Duration>) -> io::Result<(TcpStream, SocketAddr)> {
-        use nix::sys::select::*;
-
         let fd = self.as_raw_fd();
-
-        let mut tm = timeout.map(dur_to_timeval::<TimeVal>).transpose()?;
-
-        let mut fds = FdSet::new();
-        fds.insert(fd);
-        let r = select(None, &mut fds, None, None, &mut tm)
-            .map_err(|err| io::Error::from_raw_os_error(err.as_errno().unwrap() as i32))?;
-        if r == 0 {
-            return Err(io::Error::new(io::ErrorKind::TimedOut, "select accept"));
-        }
-        assert!(r == 1);
-        assert!(fds.contains(fd));
+        wait_readable(fd, timeout)?;
 
         let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
         let mut len = mem::size_of_val(&storage) as libc::socklen_t;
@@ -47,6 +34,28 @@ impl TcpListenerExt for TcpListener {
     }
 }
 
+/// select(2) until `fd` gets readable
+///
+/// * `timeout`
+///   Timeout for _select_. If the value is `None`, wait indefinitely.
+///   Returns `TimedOut` error if `fd` does not get readable in time.
+pub(crate) fn wait_readable(fd: RawFd, timeout: Option<Duration>) -> io::Result<()> {
+    use nix::sys::select::*;
+
+    let mut tm = timeout.map(dur_to_timeval::<TimeVal>).transpose()?;
+
+    let mut fds = FdSet::new();
+    fds.insert(fd);
+    let r = select(None, &mut fds, None, None, &mut tm)
+        .map_err(|err| io::Error::from_raw_os_error(err.as_errno().unwrap() as i32))?;
+    if r == 0 {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "select accept"));
+    }
+    assert!(r == 1);
+    assert!(fds.contains(fd));
+    Ok(())
+}
+
 /// Convert Duration to timeval in microseconds
 fn dur_to_timeval<T: TimeValLike>(dur: Duration) -> io::Result<T> {
     dur.as_micros()
@@ -73,15 +82,19 @@ fn sockaddr_to_addr(storage: &libc::sockaddr_storage, len: usize) -> io::Result<
         libc::AF_INET => {
             assert!(len as usize >= mem::size_of::<libc::sockaddr_in>());
             let addr = unsafe { *(storage as *const _ as *const libc::sockaddr_in) };
-            Ok(SocketAddrV4::new(addr.sin_addr.s_addr.into(), addr.sin_port).into())
+            Ok(SocketAddrV4::new(
+                u32::from_be(addr.sin_addr.s_addr).into(),
+                u16::from_be(addr.sin_port),
+            )
+            .into())
         }
         libc::AF_INET6 => {
             assert!(len as usize >= mem::size_of::<libc::sockaddr_in6>());
             let addr = unsafe { *(storage as *const _ as *const libc::sockaddr_in6) };
             Ok(SocketAddrV6::new(
                 addr.sin6_addr.s6_addr.into(),
-                addr.sin6_port,
-                addr.sin6_flowinfo,
+                u16::from_be(addr.sin6_port),
+                u32::from_be(addr.sin6_flowinfo),
                 addr.sin6_scope_id,
             )
             .into())
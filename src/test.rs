@@ -1,3 +1,313 @@
+/// Servers shared by tests of modules
+#[cfg(test)]
+pub mod servers {
+    use std::io::{self, Read};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    use rustls::{Certificate, NoClientAuth, PrivateKey, ServerSession};
+
+    use crate::byte_stream::ByteStream;
+    use crate::model::{Address, UdpDatagram};
+    use crate::tls::TlsStream;
+
+    /// how long tests wait for the servers
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Echo server serving each connection until the client closes it
+    pub fn spawn_echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for strm in listener.incoming() {
+                let mut strm = match strm {
+                    Ok(strm) => strm,
+                    Err(_) => return,
+                };
+                thread::spawn(move || {
+                    let mut rd = strm.try_clone().unwrap();
+                    io::copy(&mut rd, &mut strm).ok();
+                });
+            }
+        });
+        addr
+    }
+
+    /// SOCKS5 proxy serving each connection in a thread
+    ///
+    /// CONNECT requests are forwarded to the destinations unless a reply is given,
+    /// and datagrams of UDP associations are echoed back to the client.
+    #[derive(Clone, Default)]
+    pub struct MockProxy {
+        /// username/password authentication fails
+        reject_auth: bool,
+        /// reply code to CONNECT requests with bound address 10.0.0.1:8080
+        reply: Option<u8>,
+        /// UDP associations are closed after echoing the number of datagrams
+        datagrams: Option<usize>,
+        tls: Option<Arc<rustls::ServerConfig>>,
+    }
+
+    /// Proxy spawned by `MockProxy::spawn`
+    pub struct SpawnedProxy {
+        pub addr: SocketAddr,
+        credentials: mpsc::Receiver<(String, String)>,
+        requests: mpsc::Receiver<(u8, Address)>,
+        closed: mpsc::Receiver<()>,
+    }
+
+    /// Reports to the test of what clients sent
+    #[derive(Clone)]
+    struct Events {
+        credentials: mpsc::Sender<(String, String)>,
+        requests: mpsc::Sender<(u8, Address)>,
+        /// UDP associations closed by clients
+        closed: mpsc::Sender<()>,
+    }
+
+    impl MockProxy {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Reject any username/password
+        pub fn reject_auth(self) -> Self {
+            Self {
+                reject_auth: true,
+                ..self
+            }
+        }
+
+        /// Reply `code` to CONNECT requests instead of forwarding them
+        pub fn with_reply(self, code: u8) -> Self {
+            Self {
+                reply: Some(code),
+                ..self
+            }
+        }
+
+        /// Close UDP associations after echoing `count` datagrams
+        pub fn with_datagrams(self, count: usize) -> Self {
+            Self {
+                datagrams: Some(count),
+                ..self
+            }
+        }
+
+        /// Talk SOCKS5 inside TLS with the certificate and the private key in DER
+        pub fn with_tls(self, (cert, key): (Vec<u8>, Vec<u8>)) -> Self {
+            let mut config = rustls::ServerConfig::new(NoClientAuth::new());
+            config
+                .set_single_cert(vec![Certificate(cert)], PrivateKey(key))
+                .unwrap();
+            Self {
+                tls: Some(Arc::new(config)),
+                ..self
+            }
+        }
+
+        pub fn spawn(self) -> SpawnedProxy {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (tx_credentials, credentials) = mpsc::channel();
+            let (tx_requests, requests) = mpsc::channel();
+            let (tx_closed, closed) = mpsc::channel();
+            let events = Events {
+                credentials: tx_credentials,
+                requests: tx_requests,
+                closed: tx_closed,
+            };
+            thread::spawn(move || {
+                for sock in listener.incoming() {
+                    let sock = match sock {
+                        Ok(sock) => sock,
+                        Err(_) => return,
+                    };
+                    let (proxy, events) = (self.clone(), events.clone());
+                    thread::spawn(move || match &proxy.tls {
+                        Some(config) => {
+                            let session = ServerSession::new(config);
+                            let split = sock.split().unwrap();
+                            if let Ok(strm) = TlsStream::handshake(session, split) {
+                                proxy.serve(strm, &sock, events).ok();
+                            }
+                        }
+                        None => {
+                            let strm = sock.try_clone().unwrap();
+                            proxy.serve(strm, &sock, events).ok();
+                        }
+                    });
+                }
+            });
+            SpawnedProxy {
+                addr,
+                credentials,
+                requests,
+                closed,
+            }
+        }
+
+        /// Serve a client over `strm` on `sock`
+        fn serve<S: ByteStream>(
+            &self,
+            mut strm: S,
+            sock: &TcpStream,
+            events: Events,
+        ) -> io::Result<()> {
+            // VER NMETHODS METHODS
+            let mut buf = [0; 2];
+            strm.read_exact(&mut buf)?;
+            let mut methods = vec![0; buf[1] as usize];
+            strm.read_exact(&mut methods)?;
+            if methods.contains(&2) {
+                strm.write_all(&[5, 2])?;
+                let login = read_login(&mut strm)?;
+                events.credentials.send(login).ok();
+                if self.reject_auth {
+                    return strm.write_all(&[1, 1]);
+                }
+                strm.write_all(&[1, 0])?;
+            } else {
+                strm.write_all(&[5, 0])?;
+            }
+
+            // VER CMD RSV ATYP DST.ADDR DST.PORT
+            let mut req = [0; 4];
+            strm.read_exact(&mut req)?;
+            let addr = read_addr(&mut strm, req[3])?;
+            events.requests.send((req[1], addr.clone())).ok();
+            match req[1] {
+                1 => self.connect(strm, addr),
+                3 => {
+                    self.associate(strm, sock)?;
+                    events.closed.send(()).ok();
+                    Ok(())
+                }
+                _ => strm.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]),
+            }
+        }
+
+        /// Reply or forward a CONNECT request to `addr`
+        fn connect<S: ByteStream>(&self, mut strm: S, addr: Address) -> io::Result<()> {
+            if let Some(code) = self.reply {
+                return strm.write_all(&[5, code, 0, 1, 10, 0, 0, 1, 0x1f, 0x90]);
+            }
+            let upstream = match addr {
+                Address::IpAddr(ip, port) => TcpStream::connect((ip, port)),
+                Address::Domain(name, port) => TcpStream::connect((name.as_str(), port)),
+            };
+            let mut upstream = match upstream {
+                Ok(upstream) => upstream,
+                // connection refused
+                Err(_) => return strm.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]),
+            };
+            strm.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+            let (mut rd, mut wr) = strm.split().map_err(|_| io::ErrorKind::Other)?;
+            let mut to_upstream = upstream.try_clone()?;
+            thread::spawn(move || {
+                io::copy(&mut rd, &mut to_upstream).ok();
+                to_upstream.shutdown(std::net::Shutdown::Write).ok();
+            });
+            io::copy(&mut upstream, &mut wr)?;
+            Ok(())
+        }
+
+        /// Echo datagrams until the client closes the association on `control`
+        fn associate<S: ByteStream>(&self, mut strm: S, control: &TcpStream) -> io::Result<()> {
+            let relay = UdpSocket::bind("127.0.0.1:0")?;
+            let port = relay.local_addr()?.port().to_be_bytes();
+            // unspecified relay address means the proxy address
+            strm.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, port[0], port[1]])?;
+
+            relay.set_read_timeout(Some(Duration::from_millis(100)))?;
+            control.set_nonblocking(true)?;
+            let mut buf = [0; 1024];
+            let mut echoed = 0;
+            loop {
+                if let Ok((size, client)) = relay.recv_from(&mut buf) {
+                    let dgram = UdpDatagram::parse(&buf[..size])?;
+                    relay.send_to(&dgram.to_bytes()?, client)?;
+                    echoed += 1;
+                    if Some(echoed) == self.datagrams {
+                        // closed by the proxy
+                        return Err(io::ErrorKind::ConnectionAborted.into());
+                    }
+                }
+                if let Ok(0) = (&*control).read(&mut [0; 1]) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    impl SpawnedProxy {
+        /// Username and password of the next authentication
+        pub fn credentials(&self) -> (String, String) {
+            self.credentials
+                .recv_timeout(WAIT)
+                .expect("no authentication")
+        }
+
+        /// Command and destination of the next request
+        pub fn request(&self) -> (u8, Address) {
+            self.requests.recv_timeout(WAIT).expect("no request")
+        }
+
+        /// Wait for a client to close a UDP association
+        pub fn association_closed(&self) {
+            self.closed
+                .recv_timeout(WAIT)
+                .expect("no association closed")
+        }
+    }
+
+    /// VER ULEN UNAME PLEN PASSWD
+    fn read_login<R: Read>(rd: &mut R) -> io::Result<(String, String)> {
+        let mut ver = [0; 1];
+        rd.read_exact(&mut ver)?;
+        let mut field = || -> io::Result<String> {
+            let mut len = [0; 1];
+            rd.read_exact(&mut len)?;
+            let mut field = vec![0; len[0] as usize];
+            rd.read_exact(&mut field)?;
+            Ok(String::from_utf8_lossy(&field).into_owned())
+        };
+        Ok((field()?, field()?))
+    }
+
+    /// DST.ADDR DST.PORT of `atyp`
+    fn read_addr<R: Read>(rd: &mut R, atyp: u8) -> io::Result<Address> {
+        let port = |rd: &mut R| -> io::Result<u16> {
+            let mut port = [0; 2];
+            rd.read_exact(&mut port)?;
+            Ok(u16::from_be_bytes(port))
+        };
+        match atyp {
+            1 => {
+                let mut ip = [0; 4];
+                rd.read_exact(&mut ip)?;
+                Ok(Address::IpAddr(Ipv4Addr::from(ip).into(), port(rd)?))
+            }
+            3 => {
+                let mut len = [0; 1];
+                rd.read_exact(&mut len)?;
+                let mut name = vec![0; len[0] as usize];
+                rd.read_exact(&mut name)?;
+                let name = String::from_utf8_lossy(&name).into_owned();
+                Ok(Address::Domain(name, port(rd)?))
+            }
+            4 => {
+                let mut ip = [0; 16];
+                rd.read_exact(&mut ip)?;
+                Ok(Address::IpAddr(Ipv6Addr::from(ip).into(), port(rd)?))
+            }
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::config::ServerConfig;
@@ -0,0 +1,840 @@
+//! TLS sessions over TCP connections and other byte streams
+//!
+//! A session is shared by the read and the write half of a stream.
+//! The session is locked while records are processed, but not while the transport blocks on reading.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use log::*;
+use ring::digest;
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, ClientHello, ClientSession,
+    NoClientAuth, PrivateKey, ResolvesServerCert, RootCertStore, ServerSession, Session,
+};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+
+use crate::byte_stream::{ByteStream, DeadlineReader};
+use crate::config::{TlsCertificate, TlsClientOptions, TlsServerOptions};
+use crate::model::{Address, Error, ErrorKind};
+
+/// Size of TLS records read from the transport at once
+const RECORD_BUFFER_SIZE: usize = 16 * 1024 + 256;
+
+/// Byte stream of a TLS session over another byte stream
+pub struct TlsStream<S> {
+    tls: Arc<Mutex<Tls<S>>>,
+    /// read half of the transport
+    rd: Arc<Mutex<Box<dyn Read + Send>>>,
+    /// records read from the transport
+    buf: Vec<u8>,
+    /// verified identity of the peer
+    identity: Option<String>,
+}
+
+/// Session and the write half of the transport
+struct Tls<S> {
+    session: S,
+    wr: Box<dyn Write + Send>,
+}
+
+impl<S: Session> Tls<S> {
+    fn write_records(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.wr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Halves of a transport in one stream for handshakes
+struct Transport<'a> {
+    rd: &'a mut dyn Read,
+    wr: &'a mut dyn Write,
+}
+
+impl Read for Transport<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.rd.read(buf)
+    }
+}
+
+impl Write for Transport<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wr.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.wr.flush()
+    }
+}
+
+impl<S: Session> TlsStream<S> {
+    /// Complete the handshake of `session` over the read and the write half of a transport
+    ///
+    /// Timeouts of the transport bound each step of the handshake.
+    pub fn handshake(
+        mut session: S,
+        (mut rd, mut wr): (Box<dyn Read + Send>, Box<dyn Write + Send>),
+    ) -> io::Result<Self> {
+        session.complete_io(&mut Transport {
+            rd: &mut rd,
+            wr: &mut wr,
+        })?;
+        Self::handshaken(session, (rd, wr))
+    }
+
+    /// Complete the handshake of `session` reading the transport by `handshake_rd`
+    ///
+    /// `handshake_rd` reads the same transport as the read half, e.g. within a deadline.
+    pub fn handshake_by(
+        mut session: S,
+        handshake_rd: &mut dyn Read,
+        (rd, mut wr): (Box<dyn Read + Send>, Box<dyn Write + Send>),
+    ) -> io::Result<Self> {
+        session.complete_io(&mut Transport {
+            rd: handshake_rd,
+            wr: &mut wr,
+        })?;
+        Self::handshaken(session, (rd, wr))
+    }
+
+    fn handshaken(
+        session: S,
+        (rd, wr): (Box<dyn Read + Send>, Box<dyn Write + Send>),
+    ) -> io::Result<Self> {
+        let mut tls = Tls { session, wr };
+        tls.write_records()?;
+        Ok(Self {
+            tls: Arc::new(Mutex::new(tls)),
+            rd: Arc::new(Mutex::new(rd)),
+            buf: vec![0; RECORD_BUFFER_SIZE],
+            identity: None,
+        })
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Tls<S>>> {
+        self.tls
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "poisoned TLS session"))
+    }
+
+    fn clone_stream(&self) -> Self {
+        Self {
+            tls: self.tls.clone(),
+            rd: self.rd.clone(),
+            buf: vec![0; RECORD_BUFFER_SIZE],
+            identity: self.identity.clone(),
+        }
+    }
+}
+
+impl<S> fmt::Debug for TlsStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsStream")
+            .field("identity", &self.identity)
+            .finish()
+    }
+}
+
+impl<S: Session> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.lock()?.session.read(buf) {
+                Ok(0) => {}
+                Ok(n) => return Ok(n),
+                // close_notify
+                Err(err) if err.kind() == io::ErrorKind::ConnectionAborted => return Ok(0),
+                Err(err) => return Err(err),
+            }
+            let n = match self.rd.lock() {
+                Ok(mut rd) => rd.read(&mut self.buf)?,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "poisoned transport")),
+            };
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut tls = self.lock()?;
+            let mut records = &self.buf[..n];
+            while !records.is_empty() {
+                tls.session.read_tls(&mut records)?;
+                if let Err(err) = tls.session.process_new_packets() {
+                    // an alert describing the error
+                    tls.write_records().ok();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+            }
+            // e.g. responses to key updates
+            tls.write_records()?;
+        }
+    }
+}
+
+impl<S: Session> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut tls = self.lock()?;
+        let n = tls.session.write(buf)?;
+        tls.write_records()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut tls = self.lock()?;
+        tls.session.flush()?;
+        tls.write_records()?;
+        tls.wr.flush()
+    }
+}
+
+impl<S: Session + 'static> ByteStream for TlsStream<S> {
+    #[allow(clippy::type_complexity)]
+    fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+        Ok((Box::new(self.clone_stream()), Box::new(self.clone_stream())))
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.identity.clone()
+    }
+}
+
+/// Accepts TLS sessions of clients
+pub struct TlsServer {
+    config: Arc<rustls::ServerConfig>,
+    /// revoked client certificates
+    revoked: Revocations,
+}
+
+impl TlsServer {
+    /// Present the certificates of `options`, and verify client certificates if the CAs are given
+    pub fn new(options: &TlsServerOptions) -> Result<Self, Error> {
+        let mut resolver = SniResolver::default();
+        for cert in &options.certs {
+            let key = certified_key(cert)?;
+            match &cert.server_name {
+                Some(name) => {
+                    resolver.by_name.insert(name.to_ascii_lowercase(), key);
+                }
+                None => resolver.default = Some(key),
+            }
+        }
+        let verifier = match &options.client_ca {
+            Some(path) => AllowAnyAuthenticatedClient::new(root_store(path)?),
+            None => NoClientAuth::new(),
+        };
+        let mut config = rustls::ServerConfig::new(verifier);
+        config.cert_resolver = Arc::new(resolver);
+
+        let mut revoked = Revocations::default();
+        for path in &options.client_crls {
+            revoked.load(path)?;
+        }
+        Ok(Self {
+            config: Arc::new(config),
+            revoked,
+        })
+    }
+
+    /// Accept a TLS session of a client connected to `sock`
+    ///
+    /// The handshake is read within `timeout` however slowly the client sends it,
+    /// and each write of the handshake times out after `timeout`.
+    /// Timeouts of `sock` are restored afterwards.
+    /// Clients of revoked certificates are rejected after the handshake.
+    pub fn accept(&self, sock: TcpStream, timeout: Duration) -> io::Result<TlsServerStream> {
+        let (rd_timeout, wr_timeout) = (sock.read_timeout()?, sock.write_timeout()?);
+        sock.set_write_timeout(Some(timeout))?;
+        let mut handshake_rd = DeadlineReader {
+            sock: &sock,
+            deadline: Instant::now() + timeout,
+        };
+        let halves: (Box<dyn Read + Send>, Box<dyn Write + Send>) =
+            (Box::new(sock.try_clone()?), Box::new(sock.try_clone()?));
+        let mut strm =
+            TlsStream::handshake_by(ServerSession::new(&self.config), &mut handshake_rd, halves)?;
+        sock.set_read_timeout(rd_timeout)?;
+        sock.set_write_timeout(wr_timeout)?;
+
+        let peer = strm.lock()?.session.get_peer_certificates();
+        if let Some(cert) = peer.as_ref().and_then(|certs| certs.first()) {
+            let (_, cert) = x509_parser::parse_x509_certificate(&cert.0)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            if self.revoked.contains(&cert) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("client certificate revoked: {}", cert.subject()),
+                ));
+            }
+            strm.identity = identity(&cert);
+        }
+        Ok(strm)
+    }
+}
+
+/// Starts TLS sessions with servers over connections through proxies, or with proxies
+pub struct TlsClient {
+    config: Arc<ClientConfig>,
+    /// name to send by SNI and to verify instead of the host
+    server_name: Option<String>,
+    /// pinned SHA-256 digests of server certificates
+    fingerprints: Vec<Vec<u8>>,
+}
+
+impl TlsClient {
+    /// Verify servers by the CAs of `options` or else by the system roots
+    pub fn new(options: &TlsClientOptions) -> Result<Self, Error> {
+        let mut config = ClientConfig::new();
+        config.root_store = match &options.ca {
+            Some(path) => root_store(path)?,
+            None => match rustls_native_certs::load_native_certs() {
+                Ok(roots) => roots,
+                Err((Some(roots), err)) => {
+                    warn!("some system root certificates are not loaded: {}", err);
+                    roots
+                }
+                Err((None, err)) => return Err(err.into()),
+            },
+        };
+        let alpn: Vec<_> = options
+            .alpn
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        config.set_protocols(&alpn);
+        if let Some(cert) = &options.cert {
+            let (certs, key) = chain_and_key(cert)?;
+            config
+                .set_single_client_cert(certs, key)
+                .map_err(|err| invalid_certificate(&cert.key, &err.to_string()))?;
+        }
+        Ok(Self {
+            config: Arc::new(config),
+            server_name: options.server_name.clone(),
+            fingerprints: options.fingerprints.clone(),
+        })
+    }
+
+    /// Start a TLS session with `dst` over `strm`
+    ///
+    /// The server name defaults to the host of `dst`. IP addresses need a server name.
+    /// The server certificate must be one of the pinned ones if any.
+    pub fn connect(&self, dst: &Address, strm: impl ByteStream) -> Result<TlsClientStream, Error> {
+        let name = match (&self.server_name, dst) {
+            (Some(name), _) => name.as_str(),
+            (None, Address::Domain(host, _)) => host.as_str(),
+            (None, Address::IpAddr(..)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no server name to verify TLS of {}", dst),
+                )
+                .into())
+            }
+        };
+        let name = webpki::DNSNameRef::try_from_ascii_str(name).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid server name: {}", name),
+            )
+        })?;
+        let session = ClientSession::new(&self.config, name);
+        let strm = TlsStream::handshake(session, strm.split()?).map_err(|err| {
+            let reason = err.to_string();
+            err.context(ErrorKind::TlsHandshake {
+                addr: dst.clone(),
+                reason,
+            })
+        })?;
+        if !self.fingerprints.is_empty() && !self.pinned(&strm)? {
+            return Err(ErrorKind::TlsHandshake {
+                addr: dst.clone(),
+                reason: "server certificate not pinned".to_owned(),
+            }
+            .into());
+        }
+        Ok(strm)
+    }
+
+    /// Whether the certificate of the server is pinned
+    fn pinned(&self, strm: &TlsClientStream) -> io::Result<bool> {
+        let peer = strm.lock()?.session.get_peer_certificates();
+        Ok(match peer.as_ref().and_then(|certs| certs.first()) {
+            Some(cert) => {
+                let fingerprint = digest::digest(&digest::SHA256, &cert.0);
+                self.fingerprints
+                    .iter()
+                    .any(|pin| pin.as_slice() == fingerprint.as_ref())
+            }
+            None => false,
+        })
+    }
+}
+
+impl fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsClient")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+/// Trust anchors in a PEM bundle
+fn root_store(path: &Path) -> Result<RootCertStore, Error> {
+    let file = fs::File::open(path).map_err(|err| invalid_certificate(path, &err.to_string()))?;
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut BufReader::new(file)) {
+        Ok((0, _)) => Err(invalid_certificate(path, "no CA certificates")),
+        Ok(_) => Ok(roots),
+        Err(()) => Err(invalid_certificate(path, "invalid PEM")),
+    }
+}
+
+/// Certificates revoked by CRLs, by their issuers and serial numbers
+///
+/// CRLs are trusted as they are configured like CAs. Their signatures are not verified.
+#[derive(Debug, Default)]
+struct Revocations {
+    revoked: HashSet<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Revocations {
+    /// Load a CRL in PEM or DER
+    fn load(&mut self, path: &Path) -> Result<(), Error> {
+        let data = fs::read(path).map_err(|err| invalid_certificate(path, &err.to_string()))?;
+        let der = match x509_parser::pem::parse_x509_pem(&data) {
+            Ok((_, pem)) => pem.contents,
+            Err(_) => data,
+        };
+        let (_, crl) = x509_parser::parse_x509_crl(&der)
+            .map_err(|err| invalid_certificate(path, &err.to_string()))?;
+        let issuer = crl.issuer().as_raw();
+        for cert in crl.iter_revoked_certificates() {
+            self.revoked
+                .insert((issuer.to_vec(), cert.raw_serial().to_vec()));
+        }
+        Ok(())
+    }
+
+    fn contains(&self, cert: &X509Certificate) -> bool {
+        self.revoked.contains(&(
+            cert.issuer().as_raw().to_vec(),
+            cert.tbs_certificate.raw_serial().to_vec(),
+        ))
+    }
+}
+
+/// Identity of a verified client: the first DNS name, email or URI of SAN,
+/// the common name, or the whole subject
+fn identity(cert: &X509Certificate) -> Option<String> {
+    if let Some((_, san)) = cert.tbs_certificate.subject_alternative_name() {
+        let name = san.general_names.iter().find_map(|name| match name {
+            GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+                Some(name.to_string())
+            }
+            _ => None,
+        });
+        if name.is_some() {
+            return name;
+        }
+    }
+    let subject = cert.subject();
+    match subject.iter_common_name().next().map(|cn| cn.as_str()) {
+        Some(Ok(cn)) => Some(cn.to_owned()),
+        _ => Some(subject.to_string()),
+    }
+}
+
+/// Certificate chain and the private key loaded from PEM files
+fn certified_key(cert: &TlsCertificate) -> Result<CertifiedKey, Error> {
+    let (certs, key) = chain_and_key(cert)?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|()| invalid_certificate(&cert.key, "unsupported private key"))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+fn chain_and_key(cert: &TlsCertificate) -> Result<(Vec<Certificate>, PrivateKey), Error> {
+    let certs = read_pem(&cert.cert, |rd| pemfile::certs(rd))?;
+    if certs.is_empty() {
+        return Err(invalid_certificate(&cert.cert, "no certificates"));
+    }
+    let mut keys = read_pem(&cert.key, |rd| pemfile::pkcs8_private_keys(rd))?;
+    if keys.is_empty() {
+        keys = read_pem(&cert.key, |rd| pemfile::rsa_private_keys(rd))?;
+    }
+    if keys.is_empty() {
+        return Err(invalid_certificate(
+            &cert.key,
+            "no PKCS#8 or RSA private keys",
+        ));
+    }
+    Ok((certs, keys.swap_remove(0)))
+}
+
+fn read_pem<T>(
+    path: &Path,
+    parse: impl FnOnce(&mut dyn io::BufRead) -> Result<Vec<T>, ()>,
+) -> Result<Vec<T>, Error> {
+    let file = fs::File::open(path).map_err(|err| invalid_certificate(path, &err.to_string()))?;
+    parse(&mut BufReader::new(file)).map_err(|()| invalid_certificate(path, "invalid PEM"))
+}
+
+fn invalid_certificate(path: &Path, reason: &str) -> Error {
+    ErrorKind::InvalidCertificate {
+        path: path.display().to_string(),
+        reason: reason.to_owned(),
+    }
+    .into()
+}
+
+/// Selects certificates by SNI
+///
+/// `*.example.com` matches one label. The default certificate is for the other names,
+/// and for clients sending no SNI.
+#[derive(Default)]
+struct SniResolver {
+    by_name: HashMap<String, CertifiedKey>,
+    default: Option<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let name = match client_hello.server_name() {
+            Some(name) => {
+                let name: &str = name.into();
+                name.to_ascii_lowercase()
+            }
+            None => return self.default.clone(),
+        };
+        let wildcard = name
+            .find('.')
+            .map(|dot| format!("*{}", &name[dot..]))
+            .unwrap_or_default();
+        self.by_name
+            .get(&name)
+            .or_else(|| self.by_name.get(&wildcard))
+            .or_else(|| self.default.as_ref())
+            .cloned()
+    }
+}
+
+/// Server side TLS stream of an accepted client
+pub type TlsServerStream = TlsStream<ServerSession>;
+
+/// Client side TLS stream to a destination or a proxy
+pub type TlsClientStream = TlsStream<ClientSession>;
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Self-signed certificate for `names` written to PEM files
+    pub fn self_signed(name: &str, names: &[&str]) -> (rcgen::Certificate, TlsCertificate) {
+        let cert = rcgen::generate_simple_self_signed(
+            names
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        let path = |ext: &str| -> PathBuf {
+            std::env::temp_dir().join(format!("tcp2socks-{}-{}.{}", name, std::process::id(), ext))
+        };
+        fs::write(path("crt"), cert.serialize_pem().unwrap()).unwrap();
+        fs::write(path("key"), cert.serialize_private_key_pem()).unwrap();
+        let files = TlsCertificate {
+            server_name: None,
+            cert: path("crt"),
+            key: path("key"),
+        };
+        (cert, files)
+    }
+
+    /// CA certificate written to a PEM file
+    pub fn certificate_authority(name: &str) -> (rcgen::Certificate, PathBuf) {
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new());
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("tcp2socks-{}-{}.crt", name, std::process::id()));
+        fs::write(&path, cert.serialize_pem().unwrap()).unwrap();
+        (cert, path)
+    }
+
+    /// Certificate for `names` signed by `ca`. Returns the certificate and the private key in DER.
+    pub fn signed(ca: &rcgen::Certificate, names: &[&str]) -> (Vec<u8>, Vec<u8>) {
+        let params = rcgen::CertificateParams::new(
+            names
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect::<Vec<_>>(),
+        );
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        (
+            cert.serialize_der_with_signer(ca).unwrap(),
+            cert.serialize_private_key_der(),
+        )
+    }
+
+    /// Client trusting `roots` connecting to `name`
+    pub fn connect_client(
+        roots: &[&rcgen::Certificate],
+        name: &str,
+        sock: TcpStream,
+    ) -> io::Result<TlsStream<ClientSession>> {
+        connect_client_with_cert(roots, None, name, sock)
+    }
+
+    /// Client presenting the certificate and the private key in DER
+    pub fn connect_client_with_cert(
+        roots: &[&rcgen::Certificate],
+        cert: Option<&(Vec<u8>, Vec<u8>)>,
+        name: &str,
+        sock: TcpStream,
+    ) -> io::Result<TlsStream<ClientSession>> {
+        let mut config = ClientConfig::new();
+        for root in roots {
+            config
+                .root_store
+                .add(&rustls::Certificate(root.serialize_der().unwrap()))
+                .unwrap();
+        }
+        if let Some((cert, key)) = cert {
+            config
+                .set_single_client_cert(
+                    vec![rustls::Certificate(cert.clone())],
+                    rustls::PrivateKey(key.clone()),
+                )
+                .unwrap();
+        }
+        let name = webpki::DNSNameRef::try_from_ascii_str(name).unwrap();
+        let session = ClientSession::new(&Arc::new(config), name);
+        TlsStream::handshake(session, (Box::new(sock.try_clone()?), Box::new(sock)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test::*;
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn sni() {
+        let (default, default_files) = self_signed("sni-default", &["localhost"]);
+        let (camera, camera_files) = self_signed("sni-camera", &["camera.example.com"]);
+        let (wildcard, wildcard_files) = self_signed("sni-wildcard", &["*.example.org"]);
+        let server = TlsServer::new(&TlsServerOptions {
+            certs: vec![
+                default_files,
+                TlsCertificate {
+                    server_name: Some("camera.example.com".into()),
+                    ..camera_files
+                },
+                TlsCertificate {
+                    server_name: Some("*.example.org".into()),
+                    ..wildcard_files
+                },
+            ],
+            ..TlsServerOptions::default()
+        })
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            for _ in 0..4 {
+                let (sock, _) = listener.accept().unwrap();
+                if let Ok(mut strm) = server.accept(sock, Duration::from_secs(3)) {
+                    let mut buf = [0; 5];
+                    strm.read_exact(&mut buf).unwrap();
+                    strm.write_all(&buf).unwrap();
+                }
+            }
+        });
+
+        let echo = |root: &rcgen::Certificate, name: &str| {
+            let sock = TcpStream::connect(addr).unwrap();
+            let mut strm = connect_client(&[root], name, sock)?;
+            strm.write_all(b"hello")?;
+            let mut buf = [0; 5];
+            strm.read_exact(&mut buf)?;
+            Ok::<_, io::Error>(buf)
+        };
+        assert_eq!(&echo(&default, "localhost").unwrap(), b"hello");
+        assert_eq!(&echo(&camera, "camera.example.com").unwrap(), b"hello");
+        assert_eq!(&echo(&wildcard, "nvr.example.org").unwrap(), b"hello");
+        // the default certificate is not for the name
+        assert!(echo(&default, "nvr.example.com").is_err());
+        server.join().unwrap();
+
+        let missing = TlsCertificate {
+            server_name: None,
+            cert: "/nonexistent.crt".into(),
+            key: "/nonexistent.key".into(),
+        };
+        match TlsServer::new(&TlsServerOptions {
+            certs: vec![missing],
+            ..TlsServerOptions::default()
+        }) {
+            Err(err) => assert!(matches!(err.kind(), ErrorKind::InvalidCertificate { .. })),
+            Ok(_) => panic!("certificate must be loaded"),
+        }
+    }
+
+    #[test]
+    fn handshake_deadline() {
+        let (_, files) = self_signed("deadline", &["localhost"]);
+        let server = TlsServer::new(&TlsServerOptions {
+            certs: vec![files],
+            ..TlsServerOptions::default()
+        })
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut sock = TcpStream::connect(addr).unwrap();
+            // every byte arrives within the timeout, but not the whole record
+            for byte in &[
+                0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03, 0x03,
+            ] {
+                if sock.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let (sock, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        assert!(server.accept(sock, Duration::from_millis(300)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn client_auth() {
+        let (root, files) = self_signed("auth-server", &["localhost"]);
+        let (ca, ca_file) = certificate_authority("auth-ca");
+        let client_cert = signed(&ca, &["camera01.example.com"]);
+        let server = TlsServer::new(&TlsServerOptions {
+            certs: vec![files],
+            client_ca: Some(ca_file),
+            ..TlsServerOptions::default()
+        })
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the server handshakes in another thread and gives back itself
+        let accept = |server: TlsServer, cert: Option<&(Vec<u8>, Vec<u8>)>| {
+            let listener = listener.try_clone().unwrap();
+            let handle = thread::spawn(move || {
+                let (sock, _) = listener.accept().unwrap();
+                let strm = server.accept(sock, Duration::from_secs(3));
+                (server, strm)
+            });
+            let sock = TcpStream::connect(addr).unwrap();
+            let _ = connect_client_with_cert(&[&root], cert, "localhost", sock);
+            handle.join().unwrap()
+        };
+
+        let (server, strm) = accept(server, None);
+        assert!(strm.is_err());
+        let (mut server, strm) = accept(server, Some(&client_cert));
+        assert_eq!(
+            strm.unwrap().peer_identity().unwrap(),
+            "camera01.example.com"
+        );
+
+        let (_, cert) = x509_parser::parse_x509_certificate(&client_cert.0).unwrap();
+        server.revoked.revoked.insert((
+            cert.issuer().as_raw().to_vec(),
+            cert.tbs_certificate.raw_serial().to_vec(),
+        ));
+        let (_, strm) = accept(server, Some(&client_cert));
+        assert_eq!(strm.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn client() {
+        let (root, files) = self_signed("client-dst", &["api.example.com"]);
+        let root_file =
+            std::env::temp_dir().join(format!("tcp2socks-client-root-{}.crt", std::process::id()));
+        fs::write(&root_file, root.serialize_pem().unwrap()).unwrap();
+        let mut config = rustls::ServerConfig::new(NoClientAuth::new());
+        let (certs, key) = chain_and_key(&files).unwrap();
+        let fingerprint = digest::digest(&digest::SHA256, &certs[0].0);
+        config.set_single_cert(certs, key).unwrap();
+        config.set_protocols(&[b"h2".to_vec()]);
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            for _ in 0..5 {
+                let (sock, _) = listener.accept().unwrap();
+                let session = ServerSession::new(&config);
+                if let Ok(mut strm) = TlsStream::handshake(session, sock.split().unwrap()) {
+                    let alpn = strm
+                        .lock()
+                        .unwrap()
+                        .session
+                        .get_alpn_protocol()
+                        .unwrap()
+                        .to_vec();
+                    // pinning clients may have gone
+                    strm.write_all(&alpn).ok();
+                }
+            }
+        });
+
+        let options = TlsClientOptions {
+            ca: Some(root_file),
+            alpn: vec!["h2".into(), "http/1.1".into()],
+            ..TlsClientOptions::default()
+        };
+        let ip: Address = addr.into();
+        let connect = |options: &TlsClientOptions, dst: &Address| {
+            let client = TlsClient::new(options).unwrap();
+            client.connect(dst, TcpStream::connect(addr).unwrap())
+        };
+        // IP addresses need a server name
+        assert!(connect(&options, &ip).is_err());
+        // the certificate is not for the host
+        let domain = Address::Domain("localhost".into(), addr.port());
+        assert!(connect(&options, &domain).is_err());
+
+        let options = TlsClientOptions {
+            server_name: Some("api.example.com".into()),
+            ..options
+        };
+        let mut strm = connect(&options, &ip).unwrap();
+        let mut alpn = [0; 2];
+        strm.read_exact(&mut alpn).unwrap();
+        assert_eq!(&alpn, b"h2");
+
+        // the certificate must be pinned in addition to the CA verification
+        let pinned = |fingerprint: &[u8]| TlsClientOptions {
+            fingerprints: vec![vec![0; 32], fingerprint.to_vec()],
+            ..options.clone()
+        };
+        assert!(connect(&pinned(fingerprint.as_ref()), &ip).is_ok());
+        let err = connect(&pinned(&[1; 32]), &ip).unwrap_err();
+        assert!(err.to_string().contains("not pinned"), "{}", err);
+        server.join().unwrap();
+    }
+}
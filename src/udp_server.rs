@@ -0,0 +1,582 @@
+//! UDP relay server
+//!
+//! Datagrams from each client address are relayed through their own association with the proxy.
+//! Associations are kept in a NAT table keyed by the listener and client addresses,
+//! and are closed after `udp_idle_timeout` without traffic in either direction.
+//! Datagrams are dropped rather than queued without bound when the table is full
+//! or an association falls behind.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+use rand::prelude::*;
+
+use crate::acceptor::addr_error;
+use crate::config::ServerConfig;
+use crate::connector::{Connector, ProxyConnector, UsedProxy};
+use crate::destination::Destinations;
+use crate::error::Error;
+use crate::health::{self, HealthChecker, HealthState};
+use crate::model::{self, Address, ErrorKind, SockAddr};
+use crate::pkt_stream::{PktStream, MAX_UDP_PAYLOAD};
+use crate::session::SessionId;
+use crate::thread::spawn_thread;
+
+/// interval of checking termination and idle timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// datagrams of a client waiting to be sent to the proxy
+const SESSION_QUEUE_LEN: usize = 64;
+
+/// listener address and client address
+type NatKey = (SocketAddr, SocketAddr);
+
+/// sends stopped sessions to the thread joining them
+type Reaper = Sender<(NatKey, UdpSessionHandle)>;
+
+pub enum UdpServerCommand {
+    /// terminate
+    Terminate,
+    /// datagram from a client received by the listener
+    Recv {
+        listener: Arc<UdpSocket>,
+        client_addr: SocketAddr,
+        data: Vec<u8>,
+    },
+    /// association of the session is closed
+    Disconnect(NatKey, SessionId),
+}
+
+impl fmt::Debug for UdpServerCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use UdpServerCommand::*;
+        match self {
+            Terminate => write!(f, "Terminate"),
+            Recv {
+                client_addr, data, ..
+            } => write!(f, "Recv({}, {} bytes)", client_addr, data.len()),
+            Disconnect((_, addr), id) => write!(f, "Disconnect({}, {})", addr, id),
+        }
+    }
+}
+
+/// Association of a client
+struct UdpSessionHandle {
+    id: SessionId,
+    /// first proxy of the session
+    proxy: UsedProxy,
+    /// datagrams from the client
+    tx: SyncSender<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<Result<(), model::Error>>,
+}
+
+impl UdpSessionHandle {
+    fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Relay datagrams between a client and an association to the destination
+struct UdpSession {
+    id: SessionId,
+    dst_addr: Address,
+    idle_timeout: Duration,
+    /// listener received datagrams from the client
+    listener: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl UdpSession {
+    fn is_idle(&self) -> bool {
+        match self.last_active.lock() {
+            Ok(last_active) => last_active.elapsed() >= self.idle_timeout,
+            Err(_) => true,
+        }
+    }
+
+    fn touch(&self) {
+        if let Ok(mut last_active) = self.last_active.lock() {
+            *last_active = Instant::now();
+        }
+    }
+
+    fn start<C: Connector>(self, connector: C, rx: Receiver<Vec<u8>>) -> Result<(), model::Error>
+    where
+        C::P: 'static,
+    {
+        let (strm, proxy_addr) = connector.connect_pkt_stream(self.dst_addr.clone())?;
+        info!(
+            "associated: {}: {} -> {} -> {}",
+            self.id, self.client_addr, proxy_addr, self.dst_addr
+        );
+        strm.set_read_timeout(Some(POLL_INTERVAL))?;
+        let strm = Arc::new(strm);
+        let session = Arc::new(self);
+
+        let downstream = {
+            let strm = strm.clone();
+            let session = session.clone();
+            let name = format!("{}: {}: downstream", session.id, session.client_addr);
+            spawn_thread(&name, move || {
+                let result = session.relay_downstream(&*strm);
+                session.stop.store(true, Ordering::SeqCst);
+                result
+            })?
+        };
+        let result = session.relay_upstream(&*strm, rx);
+        session.stop.store(true, Ordering::SeqCst);
+        let downstream = downstream
+            .join()
+            .map_err(|err| model::Error::from(ErrorKind::Poisoned(format!("{:?}", err))))?;
+        result.and(downstream)
+    }
+
+    /// client -> destination
+    fn relay_upstream(
+        &self,
+        strm: &impl PktStream,
+        rx: Receiver<Vec<u8>>,
+    ) -> Result<(), model::Error> {
+        while !self.stop.load(Ordering::SeqCst) {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(pkt) => {
+                    strm.send_pkt(&pkt)?;
+                    self.touch();
+                }
+                Err(RecvTimeoutError::Timeout) if self.is_idle() => {
+                    debug!("idle timeout: {}: {}", self.id, self.client_addr);
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// destination -> client
+    fn relay_downstream(&self, strm: &impl PktStream) -> Result<(), model::Error> {
+        let mut buf = vec![0; MAX_UDP_PAYLOAD];
+        while !self.stop.load(Ordering::SeqCst) {
+            match strm.recv_pkt(&mut buf) {
+                Ok((size, _)) => {
+                    self.listener.send_to(&buf[..size], self.client_addr)?;
+                    self.touch();
+                }
+                Err(err) if err.is_timeout() => {
+                    if self.is_idle() {
+                        break;
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bind a UDP socket to `addr`
+fn bind_udp(addr: &SockAddr, v6_only: Option<bool>) -> Result<UdpSocket, model::Error> {
+    let inet_addr = match addr {
+        SockAddr::Inet(addr) => *addr,
+        addr => return Err(ErrorKind::AddressNotSupported { addr: addr.clone() }.into()),
+    };
+    let udp = match inet_addr {
+        SocketAddr::V4(_) => net2::UdpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let udp = net2::UdpBuilder::new_v6()?;
+            if let Some(v6_only) = v6_only {
+                udp.only_v6(v6_only)?;
+            }
+            udp
+        }
+    };
+    udp.bind(inet_addr)
+        .map_err(|err| addr_error(err, addr.clone()))
+}
+
+/// spawn a thread send received datagrams to `tx`
+fn spawn_listener(
+    listener: Arc<UdpSocket>,
+    accept_timeout: Option<Duration>,
+    stop: Arc<AtomicBool>,
+    tx: Sender<UdpServerCommand>,
+) -> Result<thread::JoinHandle<()>, Error> {
+    listener.set_read_timeout(accept_timeout.or(Some(POLL_INTERVAL)))?;
+    let name = format!("udp listener: {}", listener.local_addr()?);
+    Ok(spawn_thread(&name, move || {
+        let mut buf = vec![0; MAX_UDP_PAYLOAD];
+        while !stop.load(Ordering::SeqCst) {
+            match listener.recv_from(&mut buf) {
+                Ok((size, client_addr)) => {
+                    let cmd = UdpServerCommand::Recv {
+                        listener: listener.clone(),
+                        client_addr,
+                        data: buf[..size].to_vec(),
+                    };
+                    if tx.send(cmd).is_err() {
+                        info!("disconnected UdpServerCommand chan");
+                        break;
+                    }
+                }
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::WouldBlock
+                        || err.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(err) => {
+                    error!("udp listener error: {}", err);
+                    break;
+                }
+            }
+        }
+    })?)
+}
+
+pub struct UdpServer<C> {
+    config: ServerConfig,
+    /// bound sockets served instead of the server addresses
+    listeners: Vec<UdpSocket>,
+    tx_cmd: Sender<UdpServerCommand>,
+    rx_cmd: Receiver<UdpServerCommand>,
+    /// make associations to service host
+    connector: C,
+    /// orders destinations of associations
+    destinations: Destinations,
+    /// NAT table
+    session: HashMap<NatKey, UdpSessionHandle>,
+    /// random context for generating SessionIds
+    id_rng: StdRng,
+    /// health of proxies checked in background
+    health: HealthState,
+}
+
+impl UdpServer<ProxyConnector> {
+    pub fn new(config: ServerConfig) -> (Self, Sender<UdpServerCommand>) {
+        let connector = ProxyConnector::new(&config);
+        let (server, tx) = UdpServer::with_connector(config, connector);
+        // skip proxies marked down by the health checker
+        let connector = server.connector.clone().with_health(server.health());
+        (
+            Self {
+                connector,
+                ..server
+            },
+            tx,
+        )
+    }
+}
+
+impl<C> UdpServer<C>
+where
+    C: Connector + Clone + 'static,
+    C::P: 'static,
+{
+    pub fn with_connector(config: ServerConfig, connector: C) -> (Self, Sender<UdpServerCommand>) {
+        let (tx, rx) = mpsc::channel();
+        let destinations = Destinations::new(config.dst_addrs.clone(), config.dst_order);
+        (
+            Self {
+                config,
+                listeners: vec![],
+                tx_cmd: tx.clone(),
+                rx_cmd: rx,
+                connector,
+                destinations,
+                session: HashMap::new(),
+                id_rng: StdRng::from_entropy(),
+                health: HealthState::new(),
+            },
+            tx,
+        )
+    }
+
+    /// Serve on bound `listeners` instead of binding the server addresses
+    pub fn with_listeners(self, listeners: Vec<UdpSocket>) -> Self {
+        Self { listeners, ..self }
+    }
+
+    /// Health of proxies, which is updated while serving with health checks enabled
+    pub fn health(&self) -> HealthState {
+        self.health.clone()
+    }
+
+    fn next_session_id(&mut self) -> SessionId {
+        loop {
+            let next_candidate = self.id_rng.next_u32().into();
+            if self.session.values().any(|ss| ss.id == next_candidate) {
+                continue;
+            }
+            debug!("next session id is issued: {}", next_candidate);
+            return next_candidate;
+        }
+    }
+
+    /// spawn a session for the client
+    fn spawn_session(
+        &mut self,
+        listener: Arc<UdpSocket>,
+        client_addr: SocketAddr,
+    ) -> Result<UdpSessionHandle, Error> {
+        let id = self.next_session_id();
+        let key = (listener.local_addr()?, client_addr);
+        let stop = Arc::new(AtomicBool::new(false));
+        let (connector, proxy) = self.connector.assign(
+            &client_addr.into(),
+            &health::load(self.session.values().map(|ss| &ss.proxy)),
+        );
+        let session = UdpSession {
+            id,
+            // UDP ASSOCIATE does not tell whether the destination is reachable
+            dst_addr: self.destinations.next().remove(0),
+            idle_timeout: self.config.udp_idle_timeout,
+            listener,
+            client_addr,
+            stop: stop.clone(),
+            last_active: Arc::new(Mutex::new(Instant::now())),
+        };
+        let (tx, rx) = mpsc::sync_channel(SESSION_QUEUE_LEN);
+        let tx_cmd = self.tx_cmd.clone();
+        let handle = spawn_thread(&format!("{}: {}", id, client_addr), move || {
+            let result = session.start(connector, rx);
+            tx_cmd.send(UdpServerCommand::Disconnect(key, id)).ok();
+            result
+        })?;
+        Ok(UdpSessionHandle {
+            id,
+            proxy,
+            tx,
+            stop,
+            handle,
+        })
+    }
+
+    /// Spawn a thread joining stopped sessions, so that the main loop does not wait for them
+    fn spawn_reaper() -> Result<(Reaper, thread::JoinHandle<()>), Error> {
+        let (tx, rx) = mpsc::channel::<(NatKey, UdpSessionHandle)>();
+        let th = spawn_thread("udp session reaper", move || {
+            for ((_, addr), session) in rx {
+                let id = session.id;
+                session.stop();
+                match session.handle.join() {
+                    Ok(Ok(())) => info!("session is stopped: {}: {}", addr, id),
+                    Ok(Err(err)) => error!("session error: {}: {}: {}", addr, id, err),
+                    Err(err) => error!("session panic: {}: {}: {:?}", addr, id, err),
+                }
+            }
+        })?;
+        Ok((tx, th))
+    }
+
+    /// Relay `data` of a client through its session, starting one if there is room
+    fn relay(
+        &mut self,
+        listener: Arc<UdpSocket>,
+        client_addr: SocketAddr,
+        data: Vec<u8>,
+        reaper: &Reaper,
+    ) -> Result<(), Error> {
+        let key = (listener.local_addr()?, client_addr);
+        let data = match self.session.get(&key) {
+            Some(session) => match session.tx.try_send(data) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    trace!("session queue full, datagram dropped: {}", client_addr);
+                    return Ok(());
+                }
+                // the session is closing. start a new one.
+                Err(TrySendError::Disconnected(data)) => data,
+            },
+            None => data,
+        };
+        if let Some(session) = self.session.remove(&key) {
+            reaper.send((key, session)).ok();
+        }
+        if self.session.len() >= self.config.udp_max_sessions {
+            debug!("too many sessions, datagram dropped: {}", client_addr);
+            return Ok(());
+        }
+        let session = self.spawn_session(listener, client_addr)?;
+        session.tx.try_send(data).ok();
+        self.session.insert(key, session);
+        Ok(())
+    }
+
+    /// Server main loop
+    pub fn serve(&mut self) -> Result<(), Error> {
+        // bind all addresses before receiving anything, so that a bad address fails fast.
+        let listeners = if self.listeners.is_empty() {
+            self.config
+                .server_addrs
+                .iter()
+                .map(|addr| bind_udp(addr, self.config.v6_only))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            std::mem::take(&mut self.listeners)
+        };
+        let listeners = listeners.into_iter().map(Arc::new);
+        let stop = Arc::new(AtomicBool::new(false));
+        let listen_ths = listeners
+            .map(|listener| {
+                spawn_listener(
+                    listener,
+                    self.config.accept_timeout,
+                    stop.clone(),
+                    self.tx_cmd.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let (reaper, reaper_th) = Self::spawn_reaper()?;
+        let health_check = HealthChecker::start(&self.config, self.health.clone())?;
+
+        while let Ok(cmd) = self.rx_cmd.recv() {
+            use UdpServerCommand::*;
+            trace!("cmd: {:?}", cmd);
+            match cmd {
+                Terminate => {
+                    info!("cmd: {:?}", cmd);
+                    stop.store(true, Ordering::SeqCst);
+                    self.session.values().for_each(UdpSessionHandle::stop);
+                    self.session.drain().for_each(|session| {
+                        reaper.send(session).ok();
+                    });
+                    drop(reaper);
+                    reaper_th.join().ok();
+                    debug!("join listener threads");
+                    listen_ths.into_iter().for_each(|th| {
+                        th.join().ok();
+                    });
+                    if let Some(health_check) = health_check {
+                        health_check.stop();
+                    }
+                    break;
+                }
+                Recv {
+                    listener,
+                    client_addr,
+                    data,
+                } => {
+                    if let Err(err) = self.relay(listener, client_addr, data, &reaper) {
+                        error!("session error: {}: {}", client_addr, err);
+                    }
+                }
+                Disconnect(key, id) => match self.session.get(&key) {
+                    Some(session) if session.id == id => {
+                        let session = self.session.remove(&key).unwrap();
+                        reaper.send((key, session)).ok();
+                    }
+                    _ => debug!("session has already been removed: {}", id),
+                },
+            }
+        }
+        info!("server shutdown");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::servers::MockProxy;
+
+    fn udp_server(
+        proxy_addr: SocketAddr,
+        config: impl FnOnce(&mut ServerConfig),
+    ) -> (SocketAddr, Sender<UdpServerCommand>, thread::JoinHandle<()>) {
+        // datagrams are queued on the bound socket until the server receives them
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let mut server_config =
+            ServerConfig::new(server_addr, proxy_addr, "127.0.0.1:53".parse().unwrap());
+        config(&mut server_config);
+        let (server, tx) = UdpServer::new(server_config);
+        let mut server = server.with_listeners(vec![listener]);
+        let th = thread::spawn(move || server.serve().unwrap());
+        (server_addr, tx, th)
+    }
+
+    fn client(server_addr: SocketAddr) -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server_addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .unwrap();
+        client
+    }
+
+    #[test]
+    fn relay_per_client() {
+        let proxy = MockProxy::new().spawn();
+        let (server_addr, tx, th) = udp_server(proxy.addr, |_| {});
+
+        let clients: Vec<_> = (0..2).map(|_| client(server_addr)).collect();
+        for (i, client) in clients.iter().enumerate() {
+            for _ in 0..2 {
+                let msg = format!("hello {}", i);
+                client.send(msg.as_bytes()).unwrap();
+                let mut buf = [0; 64];
+                let size = client.recv(&mut buf).unwrap();
+                assert_eq!(&buf[..size], msg.as_bytes());
+            }
+        }
+
+        tx.send(UdpServerCommand::Terminate).unwrap();
+        th.join().unwrap();
+        proxy.association_closed();
+        proxy.association_closed();
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let proxy = MockProxy::new().spawn();
+        let (server_addr, tx, th) = udp_server(proxy.addr, |config| {
+            config.udp_idle_timeout = Duration::from_secs(1);
+        });
+
+        let client = client(server_addr);
+        client.send(b"hello").unwrap();
+        let mut buf = [0; 64];
+        let size = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"hello");
+
+        // the association is closed without shutting down the server
+        proxy.association_closed();
+        tx.send(UdpServerCommand::Terminate).unwrap();
+        th.join().unwrap();
+    }
+
+    #[test]
+    fn max_sessions() {
+        let proxy = MockProxy::new().spawn();
+        let (server_addr, tx, th) = udp_server(proxy.addr, |config| {
+            config.udp_max_sessions = 1;
+        });
+
+        let first = client(server_addr);
+        first.send(b"first").unwrap();
+        let mut buf = [0; 64];
+        let size = first.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"first");
+
+        // datagrams of another client are dropped while the first one is served
+        let second = client(server_addr);
+        second
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        second.send(b"second").unwrap();
+        assert!(second.recv(&mut buf).is_err());
+        first.send(b"again").unwrap();
+        let size = first.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"again");
+
+        tx.send(UdpServerCommand::Terminate).unwrap();
+        th.join().unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{self, UnixListener, UnixStream};
+use std::time::Duration;
+
+use crate::model::UnixAddr;
+use crate::tcp_listener_ext::wait_readable;
+
+pub trait UnixListenerExt {
+    fn accept_timeout(&self, timeout: Option<Duration>) -> io::Result<(UnixStream, UnixAddr)>;
+}
+
+impl UnixListenerExt for UnixListener {
+    /// accept(2) with timeout
+    ///
+    /// * `timeout`
+    ///   Timeout for _accept_. If the value is `None`, wait connection indefinitely.
+    fn accept_timeout(&self, timeout: Option<Duration>) -> io::Result<(UnixStream, UnixAddr)> {
+        wait_readable(self.as_raw_fd(), timeout)?;
+        let (strm, addr) = self.accept()?;
+        Ok((strm, to_unix_addr(&addr)))
+    }
+}
+
+/// Convert std's unix socket address to UnixAddr
+///
+/// The name of an abstract address is not available from `std::os::unix::net::SocketAddr`,
+/// so that it is reported as an empty name.
+fn to_unix_addr(addr: &net::SocketAddr) -> UnixAddr {
+    match addr.as_pathname() {
+        Some(path) => UnixAddr::Pathname(path.to_owned()),
+        None if addr.is_unnamed() => UnixAddr::Unnamed,
+        None => UnixAddr::Abstract(vec![]),
+    }
+}
+
+/// Create a listener bound to `name` in the abstract namespace
+///
+/// * `name`
+///   The name without the leading null byte.
+/// * `backlog`
+///   Passed to `listen(2)` directly.
+pub fn bind_abstract(name: &[u8], backlog: i32) -> io::Result<UnixListener> {
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // close `fd` on error
+        let listener = UnixListener::from_raw_fd(fd);
+
+        let mut addr: libc::sockaddr_un = mem::zeroed();
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        // sun_path[0] is the null byte indicates the abstract namespace
+        if name.len() >= addr.sun_path.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("abstract socket name is too long: {} bytes", name.len()),
+            ));
+        }
+        for (dst, src) in addr.sun_path[1..].iter_mut().zip(name) {
+            *dst = *src as libc::c_char;
+        }
+        let len = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+
+        if libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::listen(fd, backlog) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(listener)
+    }
+}